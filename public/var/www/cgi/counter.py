@@ -0,0 +1,16 @@
+#!/usr/bin/env python3
+import json
+import os
+
+# Données de session fournies par le serveur
+session = json.loads(os.environ.get('SESSION_DATA', '{}'))
+count = int(session.get('counter', 0)) + 1
+
+print("Content-Type: text/html\r")
+print(f"X-Session-Set: counter={count}\r")
+print("\r")
+print("<html><body>")
+print("<h1>CGI Session Counter</h1>")
+print(f"<p>Session: {os.environ.get('SESSION_ID', 'none')}</p>")
+print(f"<p>You have loaded this page {count} time(s).</p>")
+print("</body></html>")
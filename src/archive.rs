@@ -0,0 +1,526 @@
+//! Archives of a listed directory, `GET /dir/?download=zip|tar|tar.gz`,
+//! generated while they are sent. The tree is walked up front for names and
+//! sizes only, so the route limits are checked before anything goes out and
+//! zip and tar bodies get an exact Content-Length; file contents are read
+//! as the client consumes the response.
+
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use flate2::Compression;
+use flate2::read::GzEncoder;
+
+use crate::config::Route;
+use crate::listing::civil;
+use crate::models::{ChunkedResponse, HttpResponseCommon, SimpleResponse, StreamResponse};
+use crate::request::HttpRequest;
+use crate::response::HttpResponseBuilder;
+use crate::utils::cookie::Cookie;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ArchiveFormat {
+    Zip,
+    Tar,
+    TarGz,
+}
+
+impl ArchiveFormat {
+    pub fn from_query(value: &str) -> Option<ArchiveFormat> {
+        match value {
+            "zip" => Some(ArchiveFormat::Zip),
+            "tar" => Some(ArchiveFormat::Tar),
+            "tar.gz" | "tgz" => Some(ArchiveFormat::TarGz),
+            _ => None,
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            ArchiveFormat::Zip => "zip",
+            ArchiveFormat::Tar => "tar",
+            ArchiveFormat::TarGz => "tar.gz",
+        }
+    }
+
+    fn content_type(&self) -> &'static str {
+        match self {
+            ArchiveFormat::Zip => "application/zip",
+            ArchiveFormat::Tar => "application/x-tar",
+            ArchiveFormat::TarGz => "application/gzip",
+        }
+    }
+}
+
+struct ArchiveEntry {
+    path: PathBuf,
+    /// Path inside the archive, `/`-separated; directories end with `/`
+    name: String,
+    is_dir: bool,
+    size: u64,
+    modified: u64, // Unix seconds
+}
+
+/// Largest size a ustar header holds (11 octal digits); bigger files get a
+/// pax `size` record
+const TAR_MAX_SIZE: u64 = 0o77_777_777_777;
+
+/// Archive of `dir`, reached at the decoded URL path `href`. Entries sit
+/// under a folder named after the directory; dot files follow the route's
+/// `show_hidden` and symbolic links are left out, so nothing outside the
+/// tree ends up in the archive. HTTP/1.0 clients cannot take the chunked
+/// body of a gzip stream and get the plain tar instead.
+pub fn directory_archive(
+    dir: &Path,
+    href: &str,
+    format: ArchiveFormat,
+    route: &Route,
+    request: &HttpRequest,
+    cookie: Option<&Cookie>,
+) -> Box<dyn HttpResponseCommon> {
+    let format = if format == ArchiveFormat::TarGz && request.version == "HTTP/1.0" {
+        ArchiveFormat::Tar
+    } else {
+        format
+    };
+
+    let folder = href
+        .split('/')
+        .rfind(|s| !s.is_empty())
+        .unwrap_or("root")
+        .to_string();
+
+    let mut entries = Vec::new();
+    let mut total = 0;
+    if let Err(reason) = walk(dir, &format!("{}/", folder), route, &mut entries, &mut total) {
+        return Box::new(SimpleResponse::new(
+            HttpResponseBuilder::new(403, "Forbidden")
+                .header("Content-Type", "text/plain; charset=utf-8")
+                .body(format!("{}\n", reason).into_bytes())
+                .cookie(cookie)
+                .build(),
+        ));
+    }
+
+    // Without zip64, offsets and the entry count must fit the classic fields
+    let length = archive_length(format, &entries);
+    if format == ArchiveFormat::Zip && (length > u32::MAX as u64 || entries.len() > 0xffff) {
+        return Box::new(SimpleResponse::new(
+            HttpResponseBuilder::new(403, "Forbidden")
+                .header("Content-Type", "text/plain; charset=utf-8")
+                .body(b"Directory too large for a zip archive, use ?download=tar\n".to_vec())
+                .cookie(cookie)
+                .build(),
+        ));
+    }
+
+    let filename = format!("{}.{}", folder, format.extension());
+    let ascii: String = filename
+        .chars()
+        .map(|c| if (c.is_ascii_graphic() || c == ' ') && c != '"' && c != '\\' { c } else { '_' })
+        .collect();
+    let builder = HttpResponseBuilder::ok()
+        .header("Content-Type", format.content_type())
+        .header(
+            "Content-Disposition",
+            &format!(
+                "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+                ascii,
+                urlencoding::encode(&filename)
+            ),
+        )
+        .cookie(cookie);
+
+    let reader = ArchiveReader::new(format, entries);
+    match format {
+        ArchiveFormat::TarGz => Box::new(ChunkedResponse::new(
+            builder.build_chunked(),
+            GzEncoder::new(reader, Compression::default()),
+        )),
+        _ => Box::new(StreamResponse::new(builder.build_sized(length), reader)),
+    }
+}
+
+/// Collect the entries under `dir` in name order, `prefix` being their
+/// path in the archive; fails once a route limit is passed
+fn walk(
+    dir: &Path,
+    prefix: &str,
+    route: &Route,
+    entries: &mut Vec<ArchiveEntry>,
+    total: &mut u64,
+) -> Result<(), String> {
+    let metadata = fs::metadata(dir).map_err(|e| format!("Cannot read directory: {}", e))?;
+    entries.push(ArchiveEntry {
+        path: dir.to_path_buf(),
+        name: prefix.to_string(),
+        is_dir: true,
+        size: 0,
+        modified: unix_seconds(&metadata),
+    });
+
+    let mut children: Vec<_> = fs::read_dir(dir)
+        .map_err(|e| format!("Cannot read directory: {}", e))?
+        .flatten()
+        .collect();
+    children.sort_by_key(|entry| entry.file_name());
+
+    for child in children {
+        let name = child.file_name().to_string_lossy().into_owned();
+        if name.starts_with('.') && !route.show_hidden {
+            continue;
+        }
+        let Ok(metadata) = fs::symlink_metadata(child.path()) else {
+            continue;
+        };
+
+        if metadata.is_dir() {
+            walk(&child.path(), &format!("{}{}/", prefix, name), route, entries, total)?;
+        } else if metadata.is_file() {
+            *total += metadata.len();
+            entries.push(ArchiveEntry {
+                path: child.path(),
+                name: format!("{}{}", prefix, name),
+                is_dir: false,
+                size: metadata.len(),
+                modified: unix_seconds(&metadata),
+            });
+        }
+
+        if entries.len() > route.archive_max_files {
+            return Err(format!(
+                "Directory has more than {} entries to archive",
+                route.archive_max_files
+            ));
+        }
+        if *total > route.archive_max_size {
+            return Err(format!(
+                "Directory holds more than {} bytes to archive",
+                route.archive_max_size
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn unix_seconds(metadata: &fs::Metadata) -> u64 {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_secs())
+}
+
+/// Exact size of the archive `ArchiveReader` produces for `entries`
+/// (uncompressed for tar.gz, where it is not announced)
+fn archive_length(format: ArchiveFormat, entries: &[ArchiveEntry]) -> u64 {
+    match format {
+        ArchiveFormat::Zip => {
+            let per_entry: u64 = entries
+                .iter()
+                .map(|e| 30 + e.size + 16 + 46 + 2 * e.name.len() as u64)
+                .sum();
+            per_entry + 22
+        }
+        ArchiveFormat::Tar | ArchiveFormat::TarGz => {
+            let per_entry: u64 = entries
+                .iter()
+                .map(|e| {
+                    let pax = pax_records(e).len() as u64;
+                    let pax = if pax > 0 { 512 + pad512(pax) } else { 0 };
+                    pax + 512 + pad512(e.size)
+                })
+                .sum();
+            per_entry + 1024
+        }
+    }
+}
+
+/// `n` rounded up to whole tar blocks
+fn pad512(n: u64) -> u64 {
+    n.div_ceil(512) * 512
+}
+
+/// Streams the archive of a walked tree. Files are read with the sizes seen
+/// by the walk: one that shrank since is padded with zeros and one that
+/// grew is cut, so the output always matches `archive_length`.
+struct ArchiveReader {
+    tar: bool,
+    entries: Vec<ArchiveEntry>,
+    next: usize,
+    /// Entry whose data is being sent, its trailer still to come
+    current: Option<usize>,
+    pending: Vec<u8>,
+    pending_index: usize,
+    file: Option<File>,
+    remaining: u64,
+    crc: crc32fast::Hasher,
+    /// Bytes produced so far (zip local header offsets)
+    offset: u64,
+    /// Zip central directory and the offset of each local header
+    central: Vec<u8>,
+    header_offset: u64,
+    finished: bool,
+}
+
+impl ArchiveReader {
+    fn new(format: ArchiveFormat, entries: Vec<ArchiveEntry>) -> Self {
+        Self {
+            tar: format != ArchiveFormat::Zip,
+            entries,
+            next: 0,
+            current: None,
+            pending: Vec::new(),
+            pending_index: 0,
+            file: None,
+            remaining: 0,
+            crc: crc32fast::Hasher::new(),
+            offset: 0,
+            central: Vec::new(),
+            header_offset: 0,
+            finished: false,
+        }
+    }
+
+    /// Queue what follows the data of the current entry: its trailer, then
+    /// the next header or the end of the archive. False once all is sent.
+    fn advance(&mut self) -> io::Result<bool> {
+        self.pending.clear();
+        self.pending_index = 0;
+
+        if let Some(i) = self.current.take() {
+            self.finish_entry(i);
+        }
+        if self.next < self.entries.len() {
+            let i = self.next;
+            self.next += 1;
+            self.start_entry(i)?;
+            self.current = Some(i);
+        } else if !self.finished {
+            self.finish_archive();
+            self.finished = true;
+        }
+        Ok(!self.pending.is_empty() || self.remaining > 0)
+    }
+
+    fn start_entry(&mut self, i: usize) -> io::Result<()> {
+        let entry = &self.entries[i];
+        // The previous trailer is queued but not counted yet
+        self.header_offset = self.offset + self.pending.len() as u64;
+        self.crc = crc32fast::Hasher::new();
+        self.remaining = entry.size;
+        self.file = if entry.is_dir {
+            None
+        } else {
+            Some(File::open(&entry.path)?)
+        };
+
+        if self.tar {
+            let records = pax_records(entry);
+            if !records.is_empty() {
+                let mut header = tar_header("././@PaxHeader", records.len() as u64, b'x', entry.modified);
+                set_checksum(&mut header);
+                self.pending.extend_from_slice(&header);
+                self.pending.extend_from_slice(&records);
+                pad_block(&mut self.pending);
+            }
+            let kind = if entry.is_dir { b'5' } else { b'0' };
+            let mut header = tar_header(&entry.name, entry.size, kind, entry.modified);
+            set_checksum(&mut header);
+            self.pending.extend_from_slice(&header);
+        } else {
+            let (time, date) = dos_time(entry.modified);
+            let h = &mut self.pending;
+            h.extend_from_slice(&0x04034b50u32.to_le_bytes());
+            h.extend_from_slice(&20u16.to_le_bytes()); // version needed
+            h.extend_from_slice(&ZIP_FLAGS.to_le_bytes());
+            h.extend_from_slice(&0u16.to_le_bytes()); // stored
+            h.extend_from_slice(&time.to_le_bytes());
+            h.extend_from_slice(&date.to_le_bytes());
+            h.extend_from_slice(&[0; 12]); // crc and sizes follow the data
+            h.extend_from_slice(&(entry.name.len() as u16).to_le_bytes());
+            h.extend_from_slice(&0u16.to_le_bytes()); // extra field
+            h.extend_from_slice(entry.name.as_bytes());
+        }
+        Ok(())
+    }
+
+    fn finish_entry(&mut self, i: usize) {
+        let entry = &self.entries[i];
+        self.file = None;
+
+        if self.tar {
+            let padding = (pad512(entry.size) - entry.size) as usize;
+            self.pending.resize(self.pending.len() + padding, 0);
+            return;
+        }
+
+        let crc = std::mem::take(&mut self.crc).finalize();
+        let size = entry.size as u32;
+        self.pending.extend_from_slice(&0x08074b50u32.to_le_bytes());
+        self.pending.extend_from_slice(&crc.to_le_bytes());
+        self.pending.extend_from_slice(&size.to_le_bytes());
+        self.pending.extend_from_slice(&size.to_le_bytes());
+
+        let (time, date) = dos_time(entry.modified);
+        let (mode, dos_attributes) = if entry.is_dir {
+            (0o040755u32, 0x10)
+        } else {
+            (0o100644u32, 0)
+        };
+        let c = &mut self.central;
+        c.extend_from_slice(&0x02014b50u32.to_le_bytes());
+        c.extend_from_slice(&0x0314u16.to_le_bytes()); // made by: Unix, 2.0
+        c.extend_from_slice(&20u16.to_le_bytes());
+        c.extend_from_slice(&ZIP_FLAGS.to_le_bytes());
+        c.extend_from_slice(&0u16.to_le_bytes());
+        c.extend_from_slice(&time.to_le_bytes());
+        c.extend_from_slice(&date.to_le_bytes());
+        c.extend_from_slice(&crc.to_le_bytes());
+        c.extend_from_slice(&size.to_le_bytes());
+        c.extend_from_slice(&size.to_le_bytes());
+        c.extend_from_slice(&(entry.name.len() as u16).to_le_bytes());
+        c.extend_from_slice(&[0; 8]); // extra, comment, disk, internal attributes
+        c.extend_from_slice(&((mode << 16) | dos_attributes).to_le_bytes());
+        c.extend_from_slice(&(self.header_offset as u32).to_le_bytes());
+        c.extend_from_slice(entry.name.as_bytes());
+    }
+
+    fn finish_archive(&mut self) {
+        if self.tar {
+            self.pending.resize(self.pending.len() + 1024, 0);
+            return;
+        }
+
+        let count = self.entries.len() as u16;
+        let central_offset = (self.offset + self.pending.len() as u64) as u32;
+        let central_size = self.central.len() as u32;
+        self.pending.append(&mut self.central);
+        let e = &mut self.pending;
+        e.extend_from_slice(&0x06054b50u32.to_le_bytes());
+        e.extend_from_slice(&[0; 4]); // disk numbers
+        e.extend_from_slice(&count.to_le_bytes());
+        e.extend_from_slice(&count.to_le_bytes());
+        e.extend_from_slice(&central_size.to_le_bytes());
+        e.extend_from_slice(&central_offset.to_le_bytes());
+        e.extend_from_slice(&0u16.to_le_bytes()); // comment
+    }
+}
+
+impl Read for ArchiveReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            if self.pending_index < self.pending.len() {
+                let n = buf.len().min(self.pending.len() - self.pending_index);
+                buf[..n].copy_from_slice(&self.pending[self.pending_index..self.pending_index + n]);
+                self.pending_index += n;
+                self.offset += n as u64;
+                return Ok(n);
+            }
+
+            if self.remaining > 0 {
+                let want = buf.len().min(self.remaining as usize);
+                let mut n = match &mut self.file {
+                    Some(file) => file.read(&mut buf[..want])?,
+                    None => 0,
+                };
+                if n == 0 {
+                    // The file shrank: keep the announced size
+                    self.file = None;
+                    buf[..want].fill(0);
+                    n = want;
+                }
+                self.crc.update(&buf[..n]);
+                self.remaining -= n as u64;
+                self.offset += n as u64;
+                return Ok(n);
+            }
+
+            if !self.advance()? {
+                return Ok(0);
+            }
+        }
+    }
+}
+
+/// General purpose flags: sizes in a data descriptor, UTF-8 names
+const ZIP_FLAGS: u16 = 0x0808;
+
+/// A ustar header without its checksum; `name` is cut to the 100 bytes
+/// the field holds, the full one going in a pax record before it
+fn tar_header(name: &str, size: u64, kind: u8, modified: u64) -> [u8; 512] {
+    let mut header = [0u8; 512];
+    let mut end = name.len().min(100);
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    header[..end].copy_from_slice(&name.as_bytes()[..end]);
+
+    let mode = if kind == b'5' { 0o755 } else { 0o644 };
+    write_octal(&mut header[100..108], mode);
+    write_octal(&mut header[108..116], 0); // uid
+    write_octal(&mut header[116..124], 0); // gid
+    write_octal(&mut header[124..136], size.min(TAR_MAX_SIZE));
+    write_octal(&mut header[136..148], modified.min(TAR_MAX_SIZE));
+    header[156] = kind;
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    header
+}
+
+/// Zero-padded octal number filling `field` but its final NUL
+fn write_octal(field: &mut [u8], value: u64) {
+    let width = field.len() - 1;
+    let digits = format!("{:0width$o}", value, width = width);
+    field[..width].copy_from_slice(digits.as_bytes());
+}
+
+fn set_checksum(header: &mut [u8; 512]) {
+    header[148..156].fill(b' ');
+    let sum: u32 = header.iter().map(|&b| b as u32).sum();
+    header[148..156].copy_from_slice(format!("{:06o}\0 ", sum).as_bytes());
+}
+
+fn pad_block(data: &mut Vec<u8>) {
+    let padded = pad512(data.len() as u64) as usize;
+    data.resize(padded, 0);
+}
+
+/// Pax extended header data for what a ustar header cannot hold: names
+/// over 100 bytes and sizes over 8 GiB. Empty when none is needed.
+fn pax_records(entry: &ArchiveEntry) -> Vec<u8> {
+    let mut records = Vec::new();
+    if entry.name.len() > 100 {
+        records.extend_from_slice(&pax_record("path", &entry.name));
+    }
+    if entry.size > TAR_MAX_SIZE {
+        records.extend_from_slice(&pax_record("size", &entry.size.to_string()));
+    }
+    records
+}
+
+/// `"<len> key=value\n"`, the length counting itself
+fn pax_record(key: &str, value: &str) -> Vec<u8> {
+    let body = key.len() + value.len() + 3; // space, '=' and newline
+    let mut len = body + 1;
+    while len != body + len.to_string().len() {
+        len = body + len.to_string().len();
+    }
+    format!("{} {}={}\n", len, key, value).into_bytes()
+}
+
+/// MS-DOS time and date fields (UTC), clamped to 1980 where they start
+fn dos_time(secs: u64) -> (u16, u16) {
+    let (year, month, day, hour, minute, second) = civil(secs);
+    if year < 1980 {
+        return (0, (1 << 5) | 1);
+    }
+    let year = year.min(2107);
+    let time = (hour << 11) | (minute << 5) | (second / 2);
+    let date = ((year - 1980) << 9) as u32 | (month << 5) | day;
+    (time as u16, date as u16)
+}
@@ -0,0 +1,576 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use md5::{Digest, Md5};
+use sha1::Sha1;
+use sha2::Sha256;
+use uuid::Uuid;
+
+use crate::config::{AuthConfig, AuthScheme};
+use crate::request::HttpRequest;
+
+/// How long a Digest nonce is accepted before the client must retry with a fresh one
+const NONCE_LIFETIME_SECS: u64 = 300;
+
+/// Successful bcrypt checks remembered at most; the cache starts over when full
+const BCRYPT_CACHE_MAX: usize = 1024;
+/// Wrong passwords a bcrypt user may send in a row before being slowed down
+const BCRYPT_FREE_FAILURES: u32 = 3;
+/// After that, at most one new password is hashed for the user per period
+const BCRYPT_RETRY_PERIOD: Duration = Duration::from_secs(5);
+
+thread_local! {
+    /// Highest nonce count accepted per Digest nonce, with the nonce's issue
+    /// time, so a captured Authorization header cannot be replayed
+    static NONCE_COUNTS: RefCell<HashMap<String, (u64, u64)>> = RefCell::new(HashMap::new());
+
+    /// Keyed digest of the password that last matched each bcrypt hash:
+    /// bcrypt is slow on purpose and runs on the event loop, so a known
+    /// good password is only hashed once
+    static BCRYPT_CACHE: RefCell<HashMap<String, Vec<u8>>> = RefCell::new(HashMap::new());
+
+    /// Failures in a row and time of the last one, per bcrypt hash, so a
+    /// client looping over wrong passwords cannot keep the event loop busy
+    static BCRYPT_FAILURES: RefCell<HashMap<String, (u32, Instant)>> = RefCell::new(HashMap::new());
+}
+
+const APR1_MAGIC: &str = "$apr1$";
+const ITOA64: &[u8] = b"./0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+/// A user that passed the route's authentication
+#[derive(Debug, Clone)]
+pub struct AuthUser {
+    pub name: String,
+    pub scheme: AuthScheme,
+}
+
+enum Failure {
+    Denied,
+    StaleNonce,
+}
+
+/// Check the `Authorization` header of a request against the route's auth config.
+/// On failure, returns the value to send in the `WWW-Authenticate` header.
+pub fn authenticate(auth: &AuthConfig, request: &HttpRequest) -> Result<AuthUser, String> {
+    let header = request.headers.get("authorization").unwrap_or_default();
+
+    let outcome = match auth.scheme {
+        AuthScheme::Basic => check_basic(auth, &header),
+        AuthScheme::Digest => check_digest(auth, request, &header),
+    };
+
+    match outcome {
+        Ok(name) => Ok(AuthUser {
+            name,
+            scheme: auth.scheme,
+        }),
+        Err(failure) => Err(challenge(auth, matches!(failure, Failure::StaleNonce))),
+    }
+}
+
+fn challenge(auth: &AuthConfig, stale: bool) -> String {
+    match auth.scheme {
+        AuthScheme::Basic => format!("Basic realm=\"{}\", charset=\"UTF-8\"", auth.realm),
+        AuthScheme::Digest => format!(
+            "Digest realm=\"{}\", qop=\"auth\", algorithm=MD5, nonce=\"{}\"{}",
+            auth.realm,
+            new_nonce(),
+            if stale { ", stale=true" } else { "" }
+        ),
+    }
+}
+
+/// Split `Scheme credentials` and check the scheme name case-insensitively
+fn credentials<'a>(header: &'a str, scheme: &str) -> Option<&'a str> {
+    let (name, rest) = header.trim().split_once(' ')?;
+    name.eq_ignore_ascii_case(scheme).then(|| rest.trim())
+}
+
+// === Basic ===
+
+fn check_basic(auth: &AuthConfig, header: &str) -> Result<String, Failure> {
+    let encoded = credentials(header, "Basic").ok_or(Failure::Denied)?;
+    let decoded = BASE64.decode(encoded).map_err(|_| Failure::Denied)?;
+    let decoded = String::from_utf8(decoded).map_err(|_| Failure::Denied)?;
+    let (user, password) = decoded.split_once(':').ok_or(Failure::Denied)?;
+
+    if verify_user(&auth.user_file, user, password) {
+        Ok(user.to_string())
+    } else {
+        Err(Failure::Denied)
+    }
+}
+
+/// Check a user's password against an htpasswd-style file
+pub(crate) fn verify_user(user_file: &str, user: &str, password: &str) -> bool {
+    let content = match fs::read_to_string(user_file) {
+        Ok(content) => content,
+        Err(e) => {
+            eprintln!("Cannot read htpasswd file '{}': {}", user_file, e);
+            return false;
+        }
+    };
+
+    content
+        .lines()
+        .filter_map(|line| line.trim().split_once(':'))
+        .find(|(name, _)| *name == user)
+        .is_some_and(|(_, hash)| verify_password(password, hash))
+}
+
+/// Verify a password against an htpasswd hash (bcrypt, {SHA} or $apr1$)
+fn verify_password(password: &str, hash: &str) -> bool {
+    if hash.starts_with("$2") {
+        verify_bcrypt(password, hash)
+    } else if let Some(expected) = hash.strip_prefix("{SHA}") {
+        let digest = Sha1::digest(password.as_bytes());
+        constant_time_eq(BASE64.encode(digest).as_bytes(), expected.as_bytes())
+    } else if let Some(rest) = hash.strip_prefix(APR1_MAGIC) {
+        let salt = rest.split('$').next().unwrap_or("");
+        constant_time_eq(apr1_crypt(password, salt).as_bytes(), hash.as_bytes())
+    } else {
+        false
+    }
+}
+
+fn verify_bcrypt(password: &str, hash: &str) -> bool {
+    let key = Sha256::new()
+        .chain_update(process_secret())
+        .chain_update(password.as_bytes())
+        .finalize()
+        .to_vec();
+    let known = BCRYPT_CACHE.with(|cache| {
+        cache
+            .borrow()
+            .get(hash)
+            .is_some_and(|cached| constant_time_eq(cached, &key))
+    });
+    if known {
+        return true;
+    }
+
+    // A user already past its free failures is refused without hashing
+    // until the retry period has passed; a cached password still works
+    let throttled = BCRYPT_FAILURES.with(|failures| {
+        failures.borrow().get(hash).is_some_and(|(count, last)| {
+            *count >= BCRYPT_FREE_FAILURES && last.elapsed() < BCRYPT_RETRY_PERIOD
+        })
+    });
+    if throttled {
+        return false;
+    }
+
+    if !bcrypt::verify(password, hash).unwrap_or(false) {
+        BCRYPT_FAILURES.with(|failures| {
+            let mut failures = failures.borrow_mut();
+            let entry = failures.entry(hash.to_string()).or_insert((0, Instant::now()));
+            *entry = (entry.0 + 1, Instant::now());
+        });
+        return false;
+    }
+    BCRYPT_FAILURES.with(|failures| failures.borrow_mut().remove(hash));
+    BCRYPT_CACHE.with(|cache| {
+        let mut cache = cache.borrow_mut();
+        if cache.len() >= BCRYPT_CACHE_MAX {
+            cache.clear();
+        }
+        cache.insert(hash.to_string(), key);
+    });
+    true
+}
+
+/// Apache's MD5-based crypt (`htpasswd -m`)
+fn apr1_crypt(password: &str, salt: &str) -> String {
+    let pw = password.as_bytes();
+    let salt = &salt.as_bytes()[..salt.len().min(8)];
+
+    let mut alt = Md5::new();
+    alt.update(pw);
+    alt.update(salt);
+    alt.update(pw);
+    let alt = alt.finalize();
+
+    let mut ctx = Md5::new();
+    ctx.update(pw);
+    ctx.update(APR1_MAGIC.as_bytes());
+    ctx.update(salt);
+    for chunk in (0..pw.len()).step_by(16) {
+        ctx.update(&alt[..(pw.len() - chunk).min(16)]);
+    }
+    let mut i = pw.len();
+    while i > 0 {
+        if i & 1 == 1 {
+            ctx.update([0u8]);
+        } else {
+            ctx.update(&pw[..1]);
+        }
+        i >>= 1;
+    }
+    let mut digest = ctx.finalize();
+
+    for round in 0..1000 {
+        let mut ctx = Md5::new();
+        if round & 1 == 1 {
+            ctx.update(pw);
+        } else {
+            ctx.update(digest);
+        }
+        if round % 3 != 0 {
+            ctx.update(salt);
+        }
+        if round % 7 != 0 {
+            ctx.update(pw);
+        }
+        if round & 1 == 1 {
+            ctx.update(digest);
+        } else {
+            ctx.update(pw);
+        }
+        digest = ctx.finalize();
+    }
+
+    let mut encoded = String::new();
+    let mut push = |value: u32, count: usize| {
+        let mut v = value;
+        for _ in 0..count {
+            encoded.push(ITOA64[(v & 0x3f) as usize] as char);
+            v >>= 6;
+        }
+    };
+    for (a, b, c) in [(0, 6, 12), (1, 7, 13), (2, 8, 14), (3, 9, 15), (4, 10, 5)] {
+        push(
+            ((digest[a] as u32) << 16) | ((digest[b] as u32) << 8) | digest[c] as u32,
+            4,
+        );
+    }
+    push(digest[11] as u32, 2);
+
+    format!("{}{}${}", APR1_MAGIC, String::from_utf8_lossy(salt), encoded)
+}
+
+// === Digest ===
+
+fn check_digest(auth: &AuthConfig, request: &HttpRequest, header: &str) -> Result<String, Failure> {
+    let params = parse_digest_params(credentials(header, "Digest").ok_or(Failure::Denied)?);
+    let param = |key: &str| params.get(key).map(|v| v.as_str()).ok_or(Failure::Denied);
+
+    let user = param("username")?;
+    let nonce = param("nonce")?;
+    let uri = param("uri")?;
+    let response = param("response")?;
+
+    if param("realm")? != auth.realm {
+        return Err(Failure::Denied);
+    }
+    if params
+        .get("algorithm")
+        .is_some_and(|alg| !alg.eq_ignore_ascii_case("MD5"))
+    {
+        return Err(Failure::Denied);
+    }
+
+    // The digest covers the request target as sent, before any rewrite
+    if uri != request.uri {
+        return Err(Failure::Denied);
+    }
+
+    let issued = nonce_timestamp(nonce).ok_or(Failure::Denied)?;
+
+    let content = fs::read_to_string(&auth.user_file).map_err(|e| {
+        eprintln!("Cannot read htdigest file '{}': {}", auth.user_file, e);
+        Failure::Denied
+    })?;
+    let ha1 = content
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.trim().splitn(3, ':');
+            Some((parts.next()?, parts.next()?, parts.next()?))
+        })
+        .find(|(name, realm, _)| *name == user && *realm == auth.realm)
+        .map(|(_, _, ha1)| ha1.to_string())
+        .ok_or(Failure::Denied)?;
+
+    let ha2 = md5_hex(&format!("{}:{}", request.method.to_str(), uri));
+    let (expected, count) = match params.get("qop").map(|q| q.as_str()) {
+        Some("auth") => {
+            let nc = param("nc")?;
+            let count = u64::from_str_radix(nc, 16).map_err(|_| Failure::Denied)?;
+            let expected = md5_hex(&format!(
+                "{}:{}:{}:{}:auth:{}",
+                ha1,
+                nonce,
+                nc,
+                param("cnonce")?,
+                ha2
+            ));
+            (expected, count)
+        }
+        Some(_) => return Err(Failure::Denied),
+        // Without qop there is no count: such a nonce is good for one request
+        None => (md5_hex(&format!("{}:{}:{}", ha1, nonce, ha2)), 1),
+    };
+
+    if !constant_time_eq(expected.as_bytes(), response.as_bytes()) {
+        return Err(Failure::Denied);
+    }
+
+    // Only report a stale nonce once the credentials are known to be right,
+    // so clients retry transparently instead of prompting the user again.
+    if unix_now().saturating_sub(issued) > NONCE_LIFETIME_SECS {
+        return Err(Failure::StaleNonce);
+    }
+    // A count not above the last one accepted is a replay; the client gets
+    // a fresh nonce, which a replaying party cannot answer
+    if !accept_nonce_count(nonce, issued, count) {
+        return Err(Failure::StaleNonce);
+    }
+
+    Ok(user.to_string())
+}
+
+/// Record `count` for a nonce if it is higher than any accepted before;
+/// expired nonces are forgotten whenever a new one shows up
+fn accept_nonce_count(nonce: &str, issued: u64, count: u64) -> bool {
+    NONCE_COUNTS.with(|counts| {
+        let mut counts = counts.borrow_mut();
+        match counts.get_mut(nonce) {
+            Some((_, last)) if count <= *last => false,
+            Some((_, last)) => {
+                *last = count;
+                true
+            }
+            None => {
+                let now = unix_now();
+                counts.retain(|_, (issued, _)| now.saturating_sub(*issued) <= NONCE_LIFETIME_SECS);
+                counts.insert(nonce.to_string(), (issued, count));
+                true
+            }
+        }
+    })
+}
+
+/// Parse `key=value, key="quoted, value"` pairs of a Digest Authorization header
+fn parse_digest_params(input: &str) -> HashMap<String, String> {
+    let mut params = HashMap::new();
+    let mut rest = input.trim();
+
+    while let Some((key, after)) = rest.split_once('=') {
+        let key = key.trim().trim_start_matches(',').trim().to_ascii_lowercase();
+        let after = after.trim_start();
+
+        let (value, remaining) = if let Some(quoted) = after.strip_prefix('"') {
+            match quoted.find('"') {
+                Some(end) => (&quoted[..end], &quoted[end + 1..]),
+                None => (quoted, ""),
+            }
+        } else {
+            match after.find(',') {
+                Some(end) => (after[..end].trim(), &after[end..]),
+                None => (after.trim(), ""),
+            }
+        };
+
+        params.insert(key, value.to_string());
+        rest = remaining.trim_start().trim_start_matches(',');
+    }
+
+    params
+}
+
+/// Per-process secret mixed into nonces so clients cannot forge their own,
+/// and into the bcrypt cache keys
+fn process_secret() -> &'static str {
+    static SECRET: OnceLock<String> = OnceLock::new();
+    SECRET.get_or_init(|| Uuid::new_v4().to_string())
+}
+
+/// A nonce is unique even within one second, as its counts are tracked
+/// per nonce: two clients must never share one
+fn new_nonce() -> String {
+    let timestamp = unix_now();
+    let salt = Uuid::new_v4().simple().to_string();
+    format!(
+        "{:x}.{}.{}",
+        timestamp,
+        salt,
+        md5_hex(&format!("{}:{}:{}", timestamp, salt, process_secret()))
+    )
+}
+
+/// Issue time of a nonce generated by this server, or `None` if it was forged
+fn nonce_timestamp(nonce: &str) -> Option<u64> {
+    let (timestamp, rest) = nonce.split_once('.')?;
+    let (salt, signature) = rest.split_once('.')?;
+    let timestamp = u64::from_str_radix(timestamp, 16).ok()?;
+    let expected = md5_hex(&format!("{}:{}:{}", timestamp, salt, process_secret()));
+    constant_time_eq(expected.as_bytes(), signature.as_bytes()).then_some(timestamp)
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn md5_hex(input: &str) -> String {
+    Md5::digest(input.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::HttpRequestBuilder;
+
+    /// User file with `lines`, removed when dropped
+    struct UserFile(std::path::PathBuf);
+
+    impl UserFile {
+        fn new(lines: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("auth-test-{}", Uuid::new_v4().simple()));
+            fs::write(&path, lines).expect("write user file");
+            UserFile(path)
+        }
+
+        fn config(&self, scheme: AuthScheme) -> AuthConfig {
+            AuthConfig {
+                scheme,
+                realm: "Private".to_string(),
+                user_file: self.0.to_string_lossy().into_owned(),
+            }
+        }
+    }
+
+    impl Drop for UserFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn request(target: &str, authorization: &str) -> HttpRequest {
+        let mut builder = HttpRequestBuilder::new();
+        builder
+            .append(
+                format!(
+                    "GET {} HTTP/1.1\r\nHost: x\r\nAuthorization: {}\r\n\r\n",
+                    target, authorization
+                )
+                .into_bytes(),
+            )
+            .expect("valid request");
+        builder.get().expect("complete request").clone()
+    }
+
+    fn digest_header(nonce: &str, uri: &str, nc: u32, password: &str) -> String {
+        let ha1 = md5_hex(&format!("alice:Private:{}", password));
+        let ha2 = md5_hex(&format!("GET:{}", uri));
+        let nc = format!("{:08x}", nc);
+        let response = md5_hex(&format!("{}:{}:{}:abc:auth:{}", ha1, nonce, nc, ha2));
+        format!(
+            "Digest username=\"alice\", realm=\"Private\", nonce=\"{}\", uri=\"{}\", qop=auth, nc={}, cnonce=\"abc\", response=\"{}\"",
+            nonce, uri, nc, response
+        )
+    }
+
+    fn basic_header(user: &str, password: &str) -> String {
+        format!("Basic {}", BASE64.encode(format!("{}:{}", user, password)))
+    }
+
+    #[test]
+    fn nonces_are_unique_and_signed() {
+        let (a, b) = (new_nonce(), new_nonce());
+        assert_ne!(a, b);
+        assert!(nonce_timestamp(&a).is_some());
+
+        let (timestamp, rest) = a.split_once('.').unwrap();
+        let forged = format!("{:x}.{}", u64::from_str_radix(timestamp, 16).unwrap() + 1, rest);
+        assert_eq!(nonce_timestamp(&forged), None);
+    }
+
+    #[test]
+    fn nonce_counts_must_increase() {
+        let issued = unix_now();
+        assert!(accept_nonce_count("n", issued, 1));
+        assert!(!accept_nonce_count("n", issued, 1));
+        assert!(accept_nonce_count("n", issued, 3));
+        assert!(!accept_nonce_count("n", issued, 2));
+        assert!(accept_nonce_count("other", issued, 1));
+    }
+
+    #[test]
+    fn digest_replay_is_answered_with_a_stale_nonce() {
+        let ha1 = md5_hex("alice:Private:wonder");
+        let file = UserFile::new(&format!("alice:Private:{}\n", ha1));
+        let auth = file.config(AuthScheme::Digest);
+        let nonce = new_nonce();
+
+        let first = request("/a?b", &digest_header(&nonce, "/a?b", 1, "wonder"));
+        assert_eq!(authenticate(&auth, &first).unwrap().name, "alice");
+
+        let challenge = authenticate(&auth, &first).unwrap_err();
+        assert!(challenge.contains("stale=true"));
+
+        let next = request("/a?b", &digest_header(&nonce, "/a?b", 2, "wonder"));
+        assert!(authenticate(&auth, &next).is_ok());
+    }
+
+    #[test]
+    fn digest_checks_password_and_target() {
+        let ha1 = md5_hex("alice:Private:wonder");
+        let file = UserFile::new(&format!("alice:Private:{}\n", ha1));
+        let auth = file.config(AuthScheme::Digest);
+        let nonce = new_nonce();
+
+        let wrong = request("/a", &digest_header(&nonce, "/a", 1, "nope"));
+        assert!(!authenticate(&auth, &wrong).unwrap_err().contains("stale"));
+
+        // Credentials computed for another target
+        let moved = request("/b", &digest_header(&nonce, "/a", 2, "wonder"));
+        assert!(authenticate(&auth, &moved).is_err());
+    }
+
+    #[test]
+    fn basic_accepts_sha_and_apr1_hashes() {
+        let sha = BASE64.encode(Sha1::digest(b"test123"));
+        // From `openssl passwd -apr1 -salt abcdefgh s3cret`
+        let apr1 = "$apr1$abcdefgh$M2T3erDstkD7SsE2QQnfH0";
+        let file = UserFile::new(&format!("test:{{SHA}}{}\nbob:{}\n", sha, apr1));
+        let auth = file.config(AuthScheme::Basic);
+
+        assert!(authenticate(&auth, &request("/", &basic_header("test", "test123"))).is_ok());
+        assert!(authenticate(&auth, &request("/", &basic_header("bob", "s3cret"))).is_ok());
+        let challenge = authenticate(&auth, &request("/", &basic_header("bob", "x"))).unwrap_err();
+        assert!(challenge.starts_with("Basic realm=\"Private\""));
+    }
+
+    #[test]
+    fn bcrypt_failures_throttle_new_passwords_but_not_cached_ones() {
+        let hash = bcrypt::hash("s3cret", 4).unwrap();
+        assert!(verify_bcrypt("s3cret", &hash));
+
+        for _ in 0..BCRYPT_FREE_FAILURES {
+            assert!(!verify_bcrypt("wrong", &hash));
+        }
+        // The right password was verified before and is still accepted
+        assert!(verify_bcrypt("s3cret", &hash));
+
+        let other = bcrypt::hash("fresh", 4).unwrap();
+        for _ in 0..BCRYPT_FREE_FAILURES {
+            assert!(!verify_bcrypt("wrong", &other));
+        }
+        // Never verified, so refused until the retry period has passed
+        assert!(!verify_bcrypt("fresh", &other));
+    }
+}
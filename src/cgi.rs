@@ -1,34 +1,55 @@
 use crate::{
-    config::Route, models::SimpleResponse, request::HttpRequest, response::HttpResponseBuilder, server::{SocketData, Status}, utils::HttpHeaders
+    auth::AuthUser, config::Route, models::{ChunkedResponse, HttpResponseCommon, SimpleResponse}, request::HttpRequest, response::HttpResponseBuilder, server::{SocketData, Status, Wakeup}, utils::HttpHeaders
 };
-use std::io::Write;
-use std::process::{Command, Stdio};
+use crate::utils::cookie::Cookie;
+use crate::utils::session::{SESSION_USER_KEY, Session, SessionStore};
+use std::io::{self, Read, Write};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
 
 /// Structure pour les données CGI (sans référence à socket_data)
 pub struct CgiContext {
     pub method: String,
+    pub version: String,
     pub path: String,
     pub query_string: String,
     pub headers: Vec<(String, String)>,
     pub body: Vec<u8>,
+    pub auth: Option<AuthUser>,
+    pub session: Option<Session>,
+    pub session_cookie: Option<Cookie>,
+}
+
+/// Modification de session demandée par le script via ses en-têtes
+enum SessionUpdate {
+    Set(String, String), // X-Session-Set: key=value
+    Delete(String),      // X-Session-Delete: key
 }
 
 impl CgiContext {
     /// Extrait les données nécessaires de la request
     pub fn from_request(request: &HttpRequest) -> Self {
         // Use the parsed path and query_string directly from the request
+        // Un champ répété devient une seule variable, valeurs combinées
         let headers: Vec<(String, String)> = request
             .headers
-            .iter()
-            .map(|(k, v)| (k.clone(), v.clone()))
+            .names()
+            .into_iter()
+            .map(|k| (k.to_string(), request.headers.get(k).unwrap_or_default()))
             .collect();
 
         Self {
             method: request.method.to_str().to_string(),
+            version: request.version.clone(),
             path: request.path.clone(),
             query_string: request.query_string.clone(),
             headers,
-            body: request.body.clone().unwrap_or(Vec::new()),
+            body: request.body.clone().unwrap_or_default(),
+            auth: None,
+            session: None,
+            session_cookie: None,
         }
     }
 }
@@ -69,14 +90,35 @@ pub fn run_cgi(
         .stdout(Stdio::piped())
         .stderr(Stdio::piped());
 
+    // Captures de la route regex, par numéro et par nom
+    for (name, value) in &route.captures {
+        cmd.env(format!("ROUTE_CAPTURE_{}", name.to_uppercase()), value);
+    }
+
+    // Utilisateur authentifié par le serveur
+    if let Some(user) = &context.auth {
+        cmd.env("REMOTE_USER", &user.name)
+            .env("AUTH_TYPE", user.scheme.as_str());
+    }
+
+    // Session courante, en lecture ; les modifications reviennent par les en-têtes X-Session-*
+    if let Some(session) = &context.session {
+        cmd.env("SESSION_ID", &session.id)
+            .env("SESSION_DATA", session.data_json());
+    }
+
     // Ajouter les headers HTTP comme variables d'environnement CGI
     for (key, value) in &context.headers {
+        // Les identifiants déjà vérifiés ne sont pas transmis au script
+        if context.auth.is_some() && key.eq_ignore_ascii_case("authorization") {
+            continue;
+        }
         let env_key = format!("HTTP_{}", key.to_uppercase().replace("-", "_"));
         cmd.env(env_key, value);
     }
 
-    // Si c'est un POST avec un body, configurer stdin
-    if context.method == "POST" && !context.body.is_empty() {
+    // Si la requête a un body (POST, PUT, PATCH), configurer stdin
+    if !context.body.is_empty() {
         cmd.stdin(Stdio::piped());
         cmd.env("CONTENT_LENGTH", context.body.len().to_string());
 
@@ -91,96 +133,448 @@ pub fn run_cgi(
     }
 
     // Spawner le processus
-    match cmd.spawn() {
-        Ok(mut child) => {
-            // Si POST, écrire le body dans stdin
-            if context.method == "POST" && !context.body.is_empty() {
-                if let Some(mut stdin) = child.stdin.take() {
-                    if let Err(e) = stdin.write_all(&context.body) {
-                        eprintln!("Failed to write body to CGI stdin: {:?}", e);
-                        send_error_response(socket_data, 500, "Failed to send data to CGI script");
-                        return false;
+    let mut child = match cmd.spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            eprintln!("Failed to spawn CGI process: {:?}", e);
+            send_error_response(socket_data, 500, "Failed to start CGI script");
+            return false;
+        }
+    };
+
+    // Le body est écrit dans un thread : un script qui ne lit pas son
+    // entrée ne doit pas bloquer la boucle d'événements. Fermer stdin à la
+    // fin signale la fin du body.
+    if !context.body.is_empty()
+        && let Some(mut stdin) = child.stdin.take()
+    {
+        let body = context.body;
+        thread::spawn(move || {
+            if let Err(e) = stdin.write_all(&body) {
+                eprintln!("Failed to write body to CGI stdin: {:?}", e);
+            }
+        });
+    }
+
+    // Le processus est surveillé (et tué après cgi_timeout) pendant que sa
+    // sortie est lue
+    let timeout = socket_data.status.timeouts.cgi;
+    let output = match CgiOutput::watch(child, timeout, socket_data.wakeup.clone()) {
+        Ok(output) => output,
+        Err(e) => {
+            eprintln!("Failed to wait for CGI process: {:?}", e);
+            send_error_response(socket_data, 500, "CGI process error");
+            return false;
+        }
+    };
+
+    // La réponse se construit au fil de la sortie du script, sans attendre
+    // ici : la connexion est reprise à chaque morceau reçu
+    let session = context
+        .session
+        .map(|session| (socket_data.session_store.clone(), session.id));
+    socket_data.status.response = Some(Box::new(CgiResponse {
+        state: Some(CgiState::Head(output)),
+        response: None,
+        version: context.version,
+        session,
+        session_cookie: context.session_cookie,
+        timeout,
+        added_headers: Vec::new(),
+        omit_body: false,
+    }));
+    socket_data.status.status = Status::Write;
+    true
+}
+
+/// Lecture de la sortie du script en attendant de pouvoir répondre
+enum CgiState {
+    /// Jusqu'à la ligne vide qui termine les en-têtes du script
+    Head(CgiOutput),
+    /// Corps lu en entier avant d'être envoyé, quand le script donne sa
+    /// longueur ou que le client ne comprend pas les chunks
+    Body(CgiOutput, HttpResponseBuilder, Vec<u8>),
+}
+
+/// Réponse d'un script CGI. Rien n'est envoyé avant l'arrivée de ses
+/// en-têtes ; les en-têtes de connexion et l'omission du corps (HEAD),
+/// demandés d'ici là, s'appliquent à la réponse une fois construite.
+struct CgiResponse {
+    /// `None` une fois la réponse construite
+    state: Option<CgiState>,
+    response: Option<Box<dyn HttpResponseCommon>>,
+    version: String,
+    session: Option<(SessionStore, String)>,
+    session_cookie: Option<Cookie>,
+    timeout: Duration,
+    added_headers: Vec<(String, String)>,
+    omit_body: bool,
+}
+
+impl CgiResponse {
+    /// Réponse complète dès que la sortie reçue le permet ; sinon l'état
+    /// de lecture est gardé pour le prochain morceau
+    fn advance(&mut self) -> Option<Box<dyn HttpResponseCommon>> {
+        let (mut output, builder, mut body) = match self.state.take()? {
+            CgiState::Head(mut output) => match output.read_head() {
+                Ok((head, body_start)) => {
+                    let headers = self.parse_head(&head);
+
+                    // Sans Content-Length du script, le corps part en chunks
+                    // au fil de sa sortie. Un client HTTP/1.0 ne comprend pas
+                    // les chunks : il reçoit la sortie complète, comme un
+                    // script qui donne sa longueur.
+                    let buffered =
+                        self.version == "HTTP/1.0" || headers.contains("content-length");
+                    let builder = HttpResponseBuilder::new(200, "OK")
+                        .headers(headers)
+                        .cookie(self.session_cookie.as_ref());
+
+                    if !buffered {
+                        // Une erreur du script en cours de route interrompt
+                        // la réponse sans dernier chunk
+                        println!("CGI output streamed in chunks");
+                        let source = io::Cursor::new(body_start).chain(output);
+                        return Some(Box::new(ChunkedResponse::new(builder.build_chunked(), source)));
                     }
-                    // Fermer stdin pour signaler la fin du body
-                    drop(stdin);
+                    (output, builder, body_start)
                 }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    self.state = Some(CgiState::Head(output));
+                    return None;
+                }
+                Err(e) => return Some(cgi_failed(&e, self.timeout)),
+            },
+            CgiState::Body(output, builder, body) => (output, builder, body),
+        };
+
+        // Ce qui a été lu avant un WouldBlock reste dans `body`
+        match output.read_to_end(&mut body) {
+            Ok(_) => {
+                println!("CGI execution successful");
+                Some(Box::new(SimpleResponse::new(builder.body(body).build())))
             }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                self.state = Some(CgiState::Body(output, builder, body));
+                None
+            }
+            Err(e) => Some(cgi_failed(&e, self.timeout)),
+        }
+    }
 
-            // Attendre la fin du processus
-            match child.wait_with_output() {
-                Ok(output) => {
-                    if output.status.success() {
-                        let body = output.stdout;
-                        let mut headers = HttpHeaders::new();
-                        let mut lines = body.split(|&b| b == b'\n');
-
-                        while let Some(line) = lines.next() {
-                            // Trim CR (\r) at the end
-                            let line = line.strip_suffix(&[b'\r']).unwrap_or(line);
-
-                            if line.is_empty() {
-                                break;
-                            }
-
-                            if let Some(colon_pos) = line.iter().position(|&b| b == b':') {
-                                let key = &line[..colon_pos];
-                                let value = &line[colon_pos + 1..];
-                                let key_str = String::from_utf8_lossy(key).trim().to_string();
-                                let value_str = String::from_utf8_lossy(value).trim().to_string();
-                                headers.insert(&key_str, &value_str);
-                            }
-                        }
+    /// En-têtes à renvoyer au client ; les X-Session-* sont appliqués à la
+    /// session et retirés
+    fn parse_head(&self, head: &[u8]) -> HttpHeaders {
+        let mut headers = HttpHeaders::new();
+        let mut session_updates = Vec::new();
+
+        for line in head.split(|&b| b == b'\n') {
+            // Trim CR (\r) at the end
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+
+            if let Some(colon_pos) = line.iter().position(|&b| b == b':') {
+                let key = &line[..colon_pos];
+                let value = &line[colon_pos + 1..];
+                let key_str = String::from_utf8_lossy(key).trim().to_string();
+                let value_str = String::from_utf8_lossy(value).trim().to_string();
+                // Consommés par le serveur, jamais renvoyés au client
+                if key_str.eq_ignore_ascii_case("x-session-set") {
+                    if let Some((k, v)) = value_str.split_once('=') {
+                        session_updates.push(SessionUpdate::Set(
+                            k.trim().to_string(),
+                            v.trim().to_string(),
+                        ));
+                    }
+                } else if key_str.eq_ignore_ascii_case("x-session-delete") {
+                    session_updates.push(SessionUpdate::Delete(value_str));
+                } else {
+                    headers.append(&key_str, &value_str);
+                }
+            }
+        }
 
-                        let body = lines
-                            .flat_map(|l| l.iter().cloned().chain(std::iter::once(b'\n')))
-                            .collect::<Vec<u8>>();
+        if let Some((store, session_id)) = &self.session {
+            apply_session_updates(store, session_id, session_updates);
+        }
+        headers
+    }
+}
 
-                        println!("CGI execution successful");
+impl HttpResponseCommon for CgiResponse {
+    fn peek(&self) -> &[u8] {
+        match &self.response {
+            Some(response) => response.peek(),
+            None => &[],
+        }
+    }
 
-                        // Construire la réponse HTTP
-                        let response = HttpResponseBuilder::new(200, "OK")
-                            .headers(headers)
-                            .body(body)
-                            .build();
+    fn next(&mut self, n: usize) {
+        if let Some(response) = &mut self.response {
+            response.next(n);
+        }
+    }
 
-                        socket_data.status.response = Some(Box::new(SimpleResponse::new(response)));
-                        socket_data.status.status = Status::Write;
-                        true
-                    } else {
-                        eprintln!("CGI script failed with status: {:?}", output.status);
-                        eprintln!("stderr: {}", String::from_utf8_lossy(&output.stderr));
+    fn is_finished(&self) -> bool {
+        self.response.as_ref().is_some_and(|r| r.is_finished())
+    }
+
+    fn fill_if_needed(&mut self) -> io::Result<()> {
+        if let Some(mut response) = self.advance() {
+            for (name, value) in &self.added_headers {
+                response.add_header(name, value);
+            }
+            if self.omit_body {
+                response.omit_body();
+            }
+            self.response = Some(response);
+        }
+        match &mut self.response {
+            Some(response) => response.fill_if_needed(),
+            None => Ok(()),
+        }
+    }
 
-                        send_error_response(socket_data, 500, "CGI script execution failed");
-                        true // On retourne true car on a géré l'erreur
+    fn add_header(&mut self, name: &str, value: &str) {
+        match &mut self.response {
+            Some(response) => response.add_header(name, value),
+            None => self.added_headers.push((name.to_string(), value.to_string())),
+        }
+    }
+
+    fn omit_body(&mut self) {
+        match &mut self.response {
+            Some(response) => response.omit_body(),
+            None => self.omit_body = true,
+        }
+    }
+
+    fn waiting_for_source(&self) -> bool {
+        self.response.as_ref().is_none_or(|r| r.waiting_for_source())
+    }
+}
+
+/// Applique les en-têtes X-Session-* du script à la session. La clé de
+/// connexion reste réservée au login intégré : un script ne peut pas
+/// authentifier quelqu'un en l'écrivant.
+fn apply_session_updates(store: &SessionStore, session_id: &str, updates: Vec<SessionUpdate>) {
+    if updates.is_empty() {
+        return;
+    }
+
+    store.with_session(session_id, |session| {
+        for update in &updates {
+            match update {
+                SessionUpdate::Set(key, _) | SessionUpdate::Delete(key)
+                    if key.is_empty() || key == SESSION_USER_KEY =>
+                {
+                    eprintln!("CGI: ignoring update of session key '{}'", key);
+                }
+                SessionUpdate::Set(key, value) => session.set_data(key, value),
+                SessionUpdate::Delete(key) => {
+                    session.remove_data(key);
+                }
+            }
+        }
+    });
+}
+
+/// Réponse d'erreur pour un script qui a échoué avant d'envoyer ses en-têtes
+fn cgi_failed(error: &io::Error, timeout: Duration) -> Box<dyn HttpResponseCommon> {
+    if error.kind() == io::ErrorKind::TimedOut {
+        eprintln!("CGI script timed out after {:?}", timeout);
+        Box::new(SimpleResponse::new(error_page(504, "Gateway Timeout")))
+    } else {
+        eprintln!("{}", error);
+        Box::new(SimpleResponse::new(error_page(500, "CGI script execution failed")))
+    }
+}
+
+/// Ce que le thread de lecture transmet de la sortie du script
+enum CgiChunk {
+    Data(Vec<u8>),
+    /// Fin du flux : succès du script, ou son erreur (`TimedOut` après un
+    /// dépassement)
+    End(io::Result<()>),
+}
+
+/// Sortie standard du script. Un thread la lit au fil de l'eau et la
+/// transmet par un canal, en réveillant la connexion à chaque morceau ; un
+/// autre surveille le processus et le tue si `timeout` est dépassé. La
+/// lecture ne bloque jamais : `WouldBlock` tant que rien n'est arrivé. La fin
+/// du flux n'est acceptée que si le script s'est terminé avec succès.
+struct CgiOutput {
+    chunks: Receiver<CgiChunk>,
+    /// Reste d'un morceau plus grand que le tampon du lecteur
+    pending: Vec<u8>,
+    /// Début de la sortie reçu avant la fin des en-têtes
+    head: Vec<u8>,
+    done: bool,
+}
+
+impl CgiOutput {
+    fn watch(mut child: Child, timeout: Duration, wakeup: Wakeup) -> io::Result<Self> {
+        let mut stdout = child
+            .stdout
+            .take()
+            .ok_or_else(|| io::Error::other("CGI stdout not captured"))?;
+
+        // stderr est lu dans un thread pour que le script ne bloque pas
+        // sur un pipe plein
+        let stderr = child.stderr.take().map(|mut pipe| {
+            thread::spawn(move || {
+                let mut buf = Vec::new();
+                let _ = pipe.read_to_end(&mut buf);
+                buf
+            })
+        });
+
+        let (exit_sender, exit) = mpsc::channel();
+        let deadline = Instant::now() + timeout;
+        thread::spawn(move || {
+            let status = loop {
+                match child.try_wait() {
+                    Ok(Some(status)) => break Some(status),
+                    Ok(None) if Instant::now() < deadline => {
+                        thread::sleep(Duration::from_millis(10))
+                    }
+                    _ => {
+                        let _ = child.kill();
+                        let _ = child.wait();
+                        break None;
                     }
                 }
-                Err(e) => {
-                    eprintln!("Failed to wait for CGI process: {:?}", e);
-                    send_error_response(socket_data, 500, "CGI process error");
-                    false
+            };
+
+            if status.is_some_and(|s| !s.success())
+                && let Some(stderr) = stderr.and_then(|t| t.join().ok())
+            {
+                eprintln!("stderr: {}", String::from_utf8_lossy(&stderr));
+            }
+            let _ = exit_sender.send(status);
+        });
+
+        // Le processus tué ferme sa sortie : la lecture s'arrête au plus
+        // tard à l'échéance
+        let (sender, chunks) = mpsc::channel();
+        thread::spawn(move || {
+            let mut buf = [0u8; 8192];
+            let end = loop {
+                match stdout.read(&mut buf) {
+                    Ok(0) => break exit_result(exit.recv().ok().flatten()),
+                    Ok(n) => {
+                        // Connexion fermée : le script perd sa sortie
+                        if sender.send(CgiChunk::Data(buf[..n].to_vec())).is_err() {
+                            return;
+                        }
+                        wakeup.wake();
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(e) => break Err(e),
                 }
+            };
+            if sender.send(CgiChunk::End(end)).is_ok() {
+                wakeup.wake();
+            }
+        });
+
+        Ok(Self {
+            chunks,
+            pending: Vec::new(),
+            head: Vec::new(),
+            done: false,
+        })
+    }
+
+    /// Renvoie les en-têtes du script et le début du corps déjà reçu, une
+    /// fois arrivée la ligne vide qui les termine (`WouldBlock` avant)
+    fn read_head(&mut self) -> io::Result<(Vec<u8>, Vec<u8>)> {
+        let mut chunk = [0u8; 4096];
+
+        loop {
+            if let Some((head_end, body_start)) = find_blank_line(&self.head) {
+                let mut head = std::mem::take(&mut self.head);
+                let body = head.split_off(body_start);
+                head.truncate(head_end);
+                return Ok((head, body));
+            }
+            let n = self.read(&mut chunk)?;
+            if n == 0 {
+                // Pas de ligne vide : tout est en-tête
+                return Ok((std::mem::take(&mut self.head), Vec::new()));
             }
+            self.head.extend_from_slice(&chunk[..n]);
         }
-        Err(e) => {
-            eprintln!("Failed to spawn CGI process: {:?}", e);
-            send_error_response(socket_data, 500, "Failed to start CGI script");
-            false
+    }
+}
+
+/// Fin de la sortie selon la façon dont le script s'est terminé (`None`
+/// pour un script tué à l'échéance)
+fn exit_result(status: Option<ExitStatus>) -> io::Result<()> {
+    match status {
+        Some(status) if status.success() => Ok(()),
+        Some(status) => Err(io::Error::other(format!(
+            "CGI script failed with status: {:?}",
+            status
+        ))),
+        None => Err(io::ErrorKind::TimedOut.into()),
+    }
+}
+
+impl Read for CgiOutput {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pending.is_empty() && !self.done {
+            match self.chunks.try_recv() {
+                Ok(CgiChunk::Data(data)) => self.pending = data,
+                Ok(CgiChunk::End(end)) => {
+                    self.done = true;
+                    end?;
+                }
+                Err(TryRecvError::Empty) => return Err(io::ErrorKind::WouldBlock.into()),
+                Err(TryRecvError::Disconnected) => {
+                    self.done = true;
+                    return Err(io::Error::other("CGI output reader stopped"));
+                }
+            }
         }
+
+        let n = self.pending.len().min(buf.len());
+        buf[..n].copy_from_slice(&self.pending[..n]);
+        self.pending.drain(..n);
+        Ok(n)
     }
 }
 
+/// Position de la ligne vide (`\n` ou `\r\n`) qui suit les en-têtes, et
+/// début du corps après elle
+fn find_blank_line(buf: &[u8]) -> Option<(usize, usize)> {
+    (0..buf.len()).find_map(|i| {
+        if i > 0 && buf[i - 1] != b'\n' {
+            None
+        } else if buf[i..].starts_with(b"\r\n") {
+            Some((i, i + 2))
+        } else if buf[i] == b'\n' {
+            Some((i, i + 1))
+        } else {
+            None
+        }
+    })
+}
+
 /// Helper pour envoyer une réponse d'erreur
 fn send_error_response(socket_data: &mut SocketData, status_code: u16, message: &str) {
+    socket_data.status.response = Some(Box::new(SimpleResponse::new(error_page(status_code, message))));
+    socket_data.status.status = Status::Write;
+}
+
+/// Page d'erreur complète, en-têtes compris
+fn error_page(status_code: u16, message: &str) -> Vec<u8> {
     let error_body = format!(
         "<html><body><h1>{} Error</h1><p>{}</p></body></html>",
         status_code, message
     );
 
-    let response = HttpResponseBuilder::new(status_code, message)
+    HttpResponseBuilder::new(status_code, message)
         .header("Content-Type", "text/html")
         .body(error_body.into_bytes())
-        .build();
-
-    socket_data.status.response = Some(Box::new(SimpleResponse::new(response)));
-    socket_data.status.status = Status::Write;
+        .build()
 }
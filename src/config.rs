@@ -1,21 +1,278 @@
+use std::fmt;
 use std::fs;
 use std::error::Error;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::time::Duration;
+
+use crate::request::RequestLimits;
+use crate::rewrite::{ReturnRule, RewriteCondition, RewriteRule};
+use crate::router::RouteMatcher;
+use crate::utils::HttpMethod;
+use crate::utils::cookie::SameSite;
+use crate::utils::session::{DEFAULT_SESSION_ID_LENGTH, MIN_SESSION_ID_LENGTH};
+use crate::vhost::ServerName;
+use crate::webdav::WEBDAV_METHODS;
 
 #[derive(Debug, Clone)]
 pub struct Config {
     pub servers: Vec<ServerConfig>,
+    pub session_store: SessionStoreConfig,
+    pub session: SessionConfig,
+    pub cookie_secrets: Vec<String>, // newest first; older ones only verify
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SessionBackendKind {
+    Memory, // lost on restart
+    File,   // one file per session in a directory
+    Log,    // append-only log replayed on startup
+}
+
+/// Top-level `session_store:` block, shared by all servers
+#[derive(Debug, Clone)]
+pub struct SessionStoreConfig {
+    pub backend: SessionBackendKind,
+    pub path: String, // directory (file) or log file (log), unused for memory
+}
+
+impl Default for SessionStoreConfig {
+    fn default() -> Self {
+        Self {
+            backend: SessionBackendKind::Memory,
+            path: String::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SessionExpiry {
+    Sliding, // every request pushes the expiry back by `idle_timeout`
+    Fixed,   // expires `idle_timeout` after creation, whatever the activity
+}
+
+/// Top-level `session:` block: session cookie attributes and lifetime
+#[derive(Debug, Clone)]
+pub struct SessionConfig {
+    pub cookie_name: String,
+    pub domain: Option<String>,
+    pub path: String,
+    pub secure: bool,
+    pub same_site: Option<SameSite>,
+    pub idle_timeout: Duration,
+    pub max_lifetime: Option<Duration>, // absolute cap from creation, even when sliding
+    pub expiry: SessionExpiry,
+    pub id_length: usize, // characters of the random session ID
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            cookie_name: "session_id".to_string(),
+            domain: None,
+            path: "/".to_string(),
+            secure: false,
+            same_site: Some(SameSite::Lax),
+            idle_timeout: Duration::from_secs(3600),
+            max_lifetime: None,
+            expiry: SessionExpiry::Sliding,
+            id_length: DEFAULT_SESSION_ID_LENGTH,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct ServerConfig {
-    pub server_name: String,        // NEW: For virtual hosting
-    pub host: String,
-    pub ports: Vec<u16>,
+    pub server_name: String,        // NEW: For virtual hosting (first name, for logs)
+    pub server_names: Vec<ServerName>, // every name answered, see `vhost`
+    pub listen: Vec<ListenAddr>,    // `listen:` entries, plus `host` on each of `ports`
     pub default_server: bool,       // NEW: Mark as default for this (host, port)
     pub error_pages: Vec<ErrorPage>,
     pub client_max_body_size: usize,
     pub root: String,       // NEW: Server-level root directory
     pub routes: Vec<Route>,
+    pub max_header_bytes: usize,
+    pub max_header_count: usize,
+    pub max_request_line: usize,
+    pub min_body_rate: usize, // bytes per second, 0 disables the check
+    pub keepalive_requests: usize, // requests served on one connection before it is closed
+    pub timeouts: Timeouts,
+    pub login: Option<LoginConfig>,
+    pub rewrite: Vec<RewriteRule>, // applied before a route is chosen
+    pub returns: Vec<ReturnRule>,  // `return:`, checked before the rewrites
+}
+
+/// Built-in form login backed by the session store
+#[derive(Debug, Clone)]
+pub struct LoginConfig {
+    pub path: String,             // endpoint receiving the POSTed login form
+    pub logout_path: String,      // endpoint destroying the session
+    pub credentials: String,      // htpasswd-style user file
+    pub login_page: String,       // where unauthenticated users are redirected
+    pub success_redirect: String, // default landing page after login
+}
+
+/// Per-phase connection timeouts
+#[derive(Debug, Clone, Copy)]
+pub struct Timeouts {
+    pub keepalive: Duration,     // waiting for the first byte of a request
+    pub client_header: Duration, // receiving the whole header section
+    pub client_body: Duration,   // between two reads of the body
+    pub send: Duration,          // between two writes of the response
+    pub cgi: Duration,           // total run time of a CGI script
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            keepalive: Duration::from_secs(15),
+            client_header: Duration::from_secs(10),
+            client_body: Duration::from_secs(60),
+            send: Duration::from_secs(60),
+            cgi: Duration::from_secs(30),
+        }
+    }
+}
+
+impl Timeouts {
+    /// Server timeouts with the overrides of a route applied
+    pub fn for_route(&self, route: &Route) -> Timeouts {
+        Timeouts {
+            keepalive: route.keepalive_timeout.unwrap_or(self.keepalive),
+            client_header: self.client_header,
+            client_body: route.client_body_timeout.unwrap_or(self.client_body),
+            send: route.send_timeout.unwrap_or(self.send),
+            cgi: route.cgi_timeout.unwrap_or(self.cgi),
+        }
+    }
+}
+
+impl Route {
+    /// Whether the route accepts `method`: HEAD comes with GET, OPTIONS is
+    /// always answered, and a WebDAV route takes what file managers need
+    pub fn allows(&self, method: &HttpMethod) -> bool {
+        let listed = |m: &HttpMethod| self.methods.iter().any(|l| HttpMethod::from(l.as_str()) == *m);
+        match method {
+            HttpMethod::OPTIONS => true,
+            HttpMethod::HEAD => listed(&HttpMethod::HEAD) || listed(&HttpMethod::GET) || self.webdav,
+            HttpMethod::GET | HttpMethod::PUT | HttpMethod::DELETE if self.webdav => true,
+            other if self.webdav && WEBDAV_METHODS.contains(&other.to_str()) => true,
+            other => listed(other),
+        }
+    }
+
+    /// Names tried in a directory: `index`, or the single `default_file`
+    pub fn index_files(&self) -> &[String] {
+        if self.index.is_empty() {
+            self.default_file.as_slice()
+        } else {
+            &self.index
+        }
+    }
+
+    /// Value of the `Allow` header for this route
+    pub fn allow_header(&self) -> String {
+        let mut allowed = self.methods.clone();
+        let implied = ["GET", "HEAD", "PUT", "DELETE", "OPTIONS"]
+            .iter()
+            .chain(WEBDAV_METHODS);
+        for &implied in implied {
+            if self.allows(&HttpMethod::from(implied)) && !allowed.iter().any(|m| m == implied) {
+                allowed.push(implied.to_string());
+            }
+        }
+        allowed.join(", ")
+    }
+}
+
+impl ServerConfig {
+    /// Methods accepted by at least one route, for `OPTIONS *`
+    pub fn allow_header(&self) -> String {
+        let mut allowed: Vec<String> = Vec::new();
+        for route in &self.routes {
+            for method in route.allow_header().split(", ") {
+                if !allowed.iter().any(|m| m == method) {
+                    allowed.push(method.to_string());
+                }
+            }
+        }
+        allowed.join(", ")
+    }
+
+    /// Header parsing limits for requests received by this server.
+    pub fn request_limits(&self) -> RequestLimits {
+        RequestLimits {
+            max_header_bytes: self.max_header_bytes,
+            max_header_count: self.max_header_count,
+            max_request_line: self.max_request_line,
+        }
+    }
+}
+
+/// Address a server listens on: an IP literal or a host name resolved at
+/// startup (every address it resolves to is bound), and a port.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListenAddr {
+    pub host: String, // IPv6 literals without their brackets
+    pub port: u16,
+    /// IPv6 sockets only take IPv6 (the default, so `[::]:80` and
+    /// `0.0.0.0:80` can both be listed); false makes `[::]` dual-stack
+    pub ipv6only: bool,
+}
+
+impl ListenAddr {
+    /// `"[::]:8080"`, `"0.0.0.0:80"`, `"localhost:8080"`, or a bare port for
+    /// every IPv4 address
+    pub fn parse(value: &str) -> Result<ListenAddr, String> {
+        let value = value.trim().trim_matches('"');
+        let (host, port) = if let Some(rest) = value.strip_prefix('[') {
+            rest.split_once("]:")
+                .ok_or_else(|| format!("Expected '[address]:port' in listen, got '{}'", value))?
+        } else if let Some((host, port)) = value.rsplit_once(':') {
+            if host.contains(':') {
+                return Err(format!("IPv6 listen addresses need brackets, e.g. '[::]:8080', got '{}'", value));
+            }
+            (host, port)
+        } else {
+            ("0.0.0.0", value)
+        };
+        if host.is_empty() {
+            return Err(format!("Missing address in listen '{}'", value));
+        }
+        let port = port
+            .parse::<u16>()
+            .map_err(|_| format!("Invalid port in listen '{}'", value))?;
+        Ok(ListenAddr {
+            host: host.to_string(),
+            port,
+            ipv6only: true,
+        })
+    }
+
+    /// Socket addresses to bind, resolving host names
+    pub fn resolve(&self) -> io::Result<Vec<SocketAddr>> {
+        let addrs: Vec<SocketAddr> = (self.host.as_str(), self.port)
+            .to_socket_addrs()
+            .map_err(|e| io::Error::new(e.kind(), format!("cannot resolve listen address {}: {}", self, e)))?
+            .collect();
+        if addrs.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("listen address {} resolves to nothing", self),
+            ));
+        }
+        Ok(addrs)
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "[{}]:{}", self.host, self.port)
+        } else {
+            write!(f, "{}:{}", self.host, self.port)
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -26,13 +283,267 @@ pub struct ErrorPage {
 
 #[derive(Debug, Clone)]
 pub struct Route {
-    pub path: String,
+    pub path: String,               // literal path or pattern, see `router`
+    pub matcher: RouteMatcher,
     pub methods: Vec<String>,
     pub root: String,
     pub default_file: Option<String>,
-    pub redirect: Option<String>,   // NEW: HTTP redirect
+    pub index: Vec<String>,         // index files tried in every directory
+    pub try_files: Vec<String>,     // candidate URIs ($uri), the last a fallback or =code
+    pub spa: bool,                  // unmatched page navigations get the root index
+    pub redirect: Option<String>,   // NEW: HTTP redirect, may use $path_suffix etc.
+    pub redirect_code: u16,         // 301, 302 (default), 303, 307 or 308
     pub cgi: Option<String>,        // NEW: CGI extension (e.g., ".py", ".php")
     pub list_directory: Option<bool>, // NEW: Enable/disable directory listing
+    pub show_hidden: bool,              // list dot files too
+    pub listing_template: Option<String>, // HTML page with {{rows}} etc.
+    pub archive_max_size: u64,            // bytes of file data in a ?download= archive
+    pub archive_max_files: usize,         // files and directories in one
+    pub keepalive_timeout: Option<Duration>,
+    pub client_body_timeout: Option<Duration>,
+    pub send_timeout: Option<Duration>,
+    pub cgi_timeout: Option<Duration>,
+    pub auth: Option<AuthConfig>,
+    pub require_session: bool,
+    pub session: bool, // false: never look up or mint a session (static assets)
+    pub webdav: bool,  // also accept PUT, DELETE and the WebDAV methods
+    pub rewrite: Vec<RewriteRule>,
+    pub captures: Vec<(String, String)>, // regex captures of the request being served
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AuthScheme {
+    Basic,
+    Digest,
+}
+
+impl AuthScheme {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuthScheme::Basic => "Basic",
+            AuthScheme::Digest => "Digest",
+        }
+    }
+}
+
+/// Route access control, e.g. `auth: { type: basic, realm: "Uploads", htpasswd: ./users.htpasswd }`
+#[derive(Debug, Clone)]
+pub struct AuthConfig {
+    pub scheme: AuthScheme,
+    pub realm: String,
+    pub user_file: String, // htpasswd for basic, htdigest for digest
+}
+
+/// Longest duration accepted in the config. Deadlines add durations to the
+/// current instant, which must not overflow.
+const MAX_DURATION: Duration = Duration::from_secs(10 * 365 * 86400);
+
+/// Parse a duration such as `30`, `30s`, `500ms`, `2m`, `12h` or `7d` (bare numbers are seconds)
+fn parse_duration(value: &str) -> Result<Duration, Box<dyn Error>> {
+    let v = value.trim().trim_matches('"');
+    let (number, unit_ms) = if let Some(ms) = v.strip_suffix("ms") {
+        (ms, 1)
+    } else if let Some(m) = v.strip_suffix('m') {
+        (m, 60_000)
+    } else if let Some(h) = v.strip_suffix('h') {
+        (h, 3_600_000)
+    } else if let Some(d) = v.strip_suffix('d') {
+        (d, 86_400_000)
+    } else {
+        (v.strip_suffix('s').unwrap_or(v), 1000)
+    };
+    let duration = number
+        .trim()
+        .parse::<u64>()?
+        .checked_mul(unit_ms)
+        .map(Duration::from_millis)
+        .filter(|duration| *duration <= MAX_DURATION)
+        .ok_or_else(|| {
+            format!(
+                "Duration '{}' is too long (at most {} days)",
+                v,
+                MAX_DURATION.as_secs() / 86400
+            )
+        })?;
+    Ok(duration)
+}
+
+/// Parse an inline map such as `{ type: basic, realm: "My Realm" }` into
+/// key/value pairs, keeping commas and colons inside quotes
+fn parse_inline_map(value: &str) -> Result<Vec<(String, String)>, Box<dyn Error>> {
+    let v = value.trim();
+    let inner = v
+        .strip_prefix('{')
+        .and_then(|v| v.strip_suffix('}'))
+        .ok_or_else(|| format!("Expected an inline map '{{ ... }}', got '{}'", v))?;
+
+    let mut entries = Vec::new();
+    let mut current = String::new();
+    let mut in_quotes = false;
+    for c in inner.chars().chain(std::iter::once(',')) {
+        match c {
+            '"' => {
+                in_quotes = !in_quotes;
+                current.push(c);
+            }
+            ',' if !in_quotes => {
+                if !current.trim().is_empty() {
+                    let (key, val) = current
+                        .split_once(':')
+                        .ok_or_else(|| format!("Expected 'key: value' in map, got '{}'", current.trim()))?;
+                    entries.push((key.trim().to_string(), val.trim().trim_matches('"').to_string()));
+                }
+                current.clear();
+            }
+            _ => current.push(c),
+        }
+    }
+
+    Ok(entries)
+}
+
+fn parse_auth(value: &str) -> Result<AuthConfig, Box<dyn Error>> {
+    let mut scheme = None;
+    let mut realm = String::from("Restricted");
+    let mut user_file = None;
+
+    for (key, val) in parse_inline_map(value)? {
+        match key.as_str() {
+            "type" => {
+                scheme = Some(match val.to_lowercase().as_str() {
+                    "basic" => AuthScheme::Basic,
+                    "digest" => AuthScheme::Digest,
+                    other => return Err(format!("Unknown auth type: {}", other).into()),
+                })
+            }
+            "realm" => realm = val,
+            "htpasswd" | "htdigest" => user_file = Some(val),
+            _ => return Err(format!("Unknown auth field: {}", key).into()),
+        }
+    }
+
+    Ok(AuthConfig {
+        scheme: scheme.ok_or("auth missing 'type'")?,
+        realm,
+        user_file: user_file.ok_or("auth missing 'htpasswd' or 'htdigest'")?,
+    })
+}
+
+/// A `rewrite:` list, items at `indent`: either `"pattern replacement [flag]"`
+/// or `{ pattern: ..., to: ..., flag: ..., header: ..., query: ..., file: ... }`
+fn parse_rewrites(lines: &[String], start: usize, indent: usize) -> Result<(Vec<RewriteRule>, usize), Box<dyn Error>> {
+    let mut rules = Vec::new();
+    let mut i = start + 1;
+
+    while i < lines.len() && indent_level(&lines[i]) == indent && lines[i].trim().starts_with('-') {
+        let item = lines[i].trim()[1..].trim();
+        let rule = if item.starts_with('{') {
+            let mut pattern = None;
+            let mut replacement = None;
+            let mut flag = String::new();
+            let mut conditions = Vec::new();
+            for (key, val) in parse_inline_map(item)? {
+                match key.as_str() {
+                    "pattern" => pattern = Some(val),
+                    "to" => replacement = Some(val),
+                    "flag" => flag = val,
+                    "header" => conditions.push(RewriteCondition::header(&val)?),
+                    "query" => conditions.push(RewriteCondition::query(&val)?),
+                    "file" => conditions.push(RewriteCondition::file(&val)?),
+                    _ => return Err(format!("Unknown rewrite field: {}", key).into()),
+                }
+            }
+            RewriteRule::new(
+                &pattern.ok_or("rewrite missing 'pattern'")?,
+                &replacement.ok_or("rewrite missing 'to'")?,
+                &flag,
+                conditions,
+            )?
+        } else {
+            RewriteRule::parse(item.trim_matches('"'))?
+        };
+        rules.push(rule);
+        i += 1;
+    }
+
+    Ok((rules, i))
+}
+
+/// A server `return:` list: `"code [target]"` or
+/// `{ code: 301, to: "https://example.com$request_uri", host: "!example.com", path: "^/old" }`
+fn parse_returns(lines: &[String], start: usize) -> Result<(Vec<ReturnRule>, usize), Box<dyn Error>> {
+    let mut rules = Vec::new();
+    let mut i = start + 1;
+
+    while i < lines.len() && indent_level(&lines[i]) == 6 && lines[i].trim().starts_with('-') {
+        let item = lines[i].trim()[1..].trim();
+        let rule = if item.starts_with('{') {
+            let mut code = None;
+            let mut target = None;
+            let mut host = None;
+            let mut path = None;
+            for (key, val) in parse_inline_map(item)? {
+                match key.as_str() {
+                    "code" => code = Some(val),
+                    "to" => target = Some(val),
+                    "host" => host = Some(val),
+                    "path" => path = Some(val),
+                    _ => return Err(format!("Unknown return field: {}", key).into()),
+                }
+            }
+            ReturnRule::new(
+                &code.ok_or("return missing 'code'")?,
+                target.as_deref(),
+                host.as_deref(),
+                path.as_deref(),
+            )?
+        } else {
+            ReturnRule::parse(item.trim_matches('"'))?
+        };
+        rules.push(rule);
+        i += 1;
+    }
+
+    Ok((rules, i))
+}
+
+/// A server `listen:` list: `"[::]:8080"` or
+/// `{ address: "[::]:8080", ipv6only: false }`
+fn parse_listen(lines: &[String], start: usize) -> Result<(Vec<ListenAddr>, usize), Box<dyn Error>> {
+    let mut addrs = Vec::new();
+    let mut i = start + 1;
+
+    while i < lines.len() && indent_level(&lines[i]) == 6 && lines[i].trim().starts_with('-') {
+        let item = lines[i].trim()[1..].trim();
+        let addr = if item.starts_with('{') {
+            let mut address = None;
+            let mut ipv6only = true;
+            for (key, val) in parse_inline_map(item)? {
+                match key.as_str() {
+                    "address" => address = Some(val),
+                    "ipv6only" => {
+                        let val = val.to_lowercase();
+                        ipv6only = val == "true" || val == "yes" || val == "1";
+                    }
+                    _ => return Err(format!("Unknown listen field: {}", key).into()),
+                }
+            }
+            ListenAddr {
+                ipv6only,
+                ..ListenAddr::parse(&address.ok_or("listen missing 'address'")?)?
+            }
+        } else {
+            ListenAddr::parse(item)?
+        };
+        addrs.push(addr);
+        i += 1;
+    }
+
+    if addrs.is_empty() {
+        return Err("listen must contain at least one address".into());
+    }
+
+    Ok((addrs, i))
 }
 
 fn indent_level(line: &str) -> usize {
@@ -98,15 +609,74 @@ fn parse_error_pages(lines: &[String], start: usize) -> Result<(Vec<ErrorPage>,
     Ok((pages, i))
 }
 
+fn parse_login(lines: &[String], start: usize) -> Result<(LoginConfig, usize), Box<dyn Error>> {
+    let mut i = start;
+
+    if indent_level(&lines[i]) != 4 || lines[i].trim() != "login:" {
+        return Err("Expected 'login:'".into());
+    }
+    i += 1;
+
+    let mut login = LoginConfig {
+        path: "/login".to_string(),
+        logout_path: "/logout".to_string(),
+        credentials: String::new(),
+        login_page: "/login.html".to_string(),
+        success_redirect: "/".to_string(),
+    };
+
+    while i < lines.len() && indent_level(&lines[i]) == 6 {
+        let line = lines[i].trim();
+        let (key, value) = line
+            .split_once(':')
+            .ok_or_else(|| format!("Expected 'key: value' in login block, got '{}'", line))?;
+        let value = value.trim().trim_matches('"').to_string();
+        match key.trim() {
+            "path" => login.path = value,
+            "logout_path" => login.logout_path = value,
+            "credentials" => login.credentials = value,
+            "login_page" => login.login_page = value,
+            "success_redirect" => login.success_redirect = value,
+            _ => return Err(format!("Unknown login field: {}", key).into()),
+        }
+        i += 1;
+    }
+
+    if login.credentials.is_empty() {
+        return Err("login missing 'credentials'".into());
+    }
+
+    Ok((login, i))
+}
+
 fn parse_route(lines: &[String], start: usize) -> Result<(Route, usize), Box<dyn Error>> {
     let mut route = Route {
         path: String::new(),
+        matcher: RouteMatcher::Prefix,
         methods: Vec::new(),
         root: "".to_string(),
         default_file: None,
+        index: Vec::new(),
+        try_files: Vec::new(),
+        spa: false,
         redirect: None,
+        redirect_code: 302,
         cgi: None,
         list_directory: None,
+        show_hidden: false,
+        listing_template: None,
+        archive_max_size: 1 << 30, // 1 GiB
+        archive_max_files: 10_000,
+        keepalive_timeout: None,
+        client_body_timeout: None,
+        send_timeout: None,
+        cgi_timeout: None,
+        auth: None,
+        require_session: false,
+        session: true,
+        webdav: false,
+        rewrite: Vec::new(),
+        captures: Vec::new(),
     };
 
     let mut i = start;
@@ -117,16 +687,22 @@ fn parse_route(lines: &[String], start: usize) -> Result<(Route, usize), Box<dyn
 
     // Parse first line (may contain inline key-value)
     let first_line = lines[i].trim()[1..].trim();
-    if !first_line.is_empty() {
-        if let Some((key, value)) = first_line.split_once(':') {
-            parse_route_field(&mut route, key, value)?;
-        }
+    if !first_line.is_empty()
+        && let Some((key, value)) = first_line.split_once(':')
+    {
+        parse_route_field(&mut route, key, value)?;
     }
     i += 1;
 
     // Parse subsequent indented fields
     while i < lines.len() && indent_level(&lines[i]) == 8 {
         let line = lines[i].trim();
+        if line == "rewrite:" {
+            let (rules, ni) = parse_rewrites(lines, i, 10)?;
+            route.rewrite = rules;
+            i = ni;
+            continue;
+        }
         if let Some((key, value)) = line.split_once(':') {
             parse_route_field(&mut route, key, value)?;
         }
@@ -137,37 +713,100 @@ fn parse_route(lines: &[String], start: usize) -> Result<(Route, usize), Box<dyn
     if route.path.is_empty() {
         return Err("Route missing 'path'".into());
     }
+    let (matcher, path) = RouteMatcher::parse(&route.path)
+        .map_err(|e| format!("Route '{}': {}", route.path, e))?;
+    route.matcher = matcher;
+    route.path = path;
     if route.methods.is_empty() {
         return Err("Route missing 'methods'".into());
     }
     if route.root.is_empty() {
         return Err("Route missing 'root'".into());
     }
+    if route.require_session && !route.session {
+        return Err(format!("Route '{}' cannot both require and disable sessions", route.path).into());
+    }
+    if route.webdav && (route.cgi.is_some() || route.redirect.is_some()) {
+        return Err(format!("WebDAV route '{}' cannot use cgi or redirect", route.path).into());
+    }
+    if let Some(code) = route.try_files.last().and_then(|last| last.strip_prefix('='))
+        && !code.parse::<u16>().is_ok_and(|code| (400..600).contains(&code))
+    {
+        return Err(format!("Route '{}': try_files ends with an invalid status '={}'", route.path, code).into());
+    }
+    if route.spa && route.index_files().is_empty() {
+        return Err(format!("SPA route '{}' needs 'index' or 'default_file'", route.path).into());
+    }
 
     Ok((route, i))
 }
 
+/// `[a, "b", c]` or a bare `a, b`
+fn parse_list(value: &str) -> Vec<String> {
+    let mut v = value.trim();
+    if v.starts_with('[') && v.ends_with(']') {
+        v = &v[1..v.len() - 1];
+    }
+    v.split(',')
+        .map(|s| s.trim().trim_matches('"').to_string())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
 fn parse_route_field(route: &mut Route, key: &str, value: &str) -> Result<(), Box<dyn Error>> {
     match key.trim() {
         "path" => route.path = value.trim().trim_matches('"').to_string(),
         "methods" => {
-            let mut v = value.trim();
-            if v.starts_with('[') && v.ends_with(']') {
-                v = &v[1..v.len()-1];
-            }
-            route.methods = v.split(',')
-                .map(|s| s.trim().trim_matches('"').to_uppercase())
-                .filter(|s| !s.is_empty())
-                .collect();
+            route.methods = parse_list(value).iter().map(|m| m.to_uppercase()).collect();
         }
         "root" => route.root = value.trim().trim_matches('"').to_string(),
         "default_file" => route.default_file = Some(value.trim().trim_matches('"').to_string()),
+        "index" => route.index = parse_list(value),
+        "try_files" => route.try_files = parse_list(value),
+        "spa" => {
+            let val = value.trim().to_lowercase();
+            route.spa = val == "true" || val == "yes" || val == "1";
+        }
         "redirect" => route.redirect = Some(value.trim().trim_matches('"').to_string()),
+        "redirect_code" => {
+            route.redirect_code = value.trim().parse::<u16>()?;
+            if ![301, 302, 303, 307, 308].contains(&route.redirect_code) {
+                return Err(format!("redirect_code must be 301, 302, 303, 307 or 308, got {}", route.redirect_code).into());
+            }
+        }
         "cgi" => route.cgi = Some(value.trim().trim_matches('"').to_string()),
         "list_directory" => {
             let val = value.trim().to_lowercase();
             route.list_directory = Some(val == "true" || val == "yes" || val == "1");
         }
+        "show_hidden" => {
+            let val = value.trim().to_lowercase();
+            route.show_hidden = val == "true" || val == "yes" || val == "1";
+        }
+        "listing_template" => route.listing_template = Some(value.trim().trim_matches('"').to_string()),
+        "archive_max_size" => route.archive_max_size = value.trim().parse::<u64>()?,
+        "archive_max_files" => route.archive_max_files = value.trim().parse::<usize>()?,
+        "keepalive_timeout" => route.keepalive_timeout = Some(parse_duration(value)?),
+        "client_body_timeout" => route.client_body_timeout = Some(parse_duration(value)?),
+        "send_timeout" => route.send_timeout = Some(parse_duration(value)?),
+        "cgi_timeout" => route.cgi_timeout = Some(parse_duration(value)?),
+        "auth" => route.auth = Some(parse_auth(value)?),
+        "require_session" => {
+            let val = value.trim().to_lowercase();
+            route.require_session = val == "true" || val == "yes" || val == "1";
+        }
+        "session" => {
+            let val = value.trim().to_lowercase();
+            route.session = val == "true" || val == "yes" || val == "1";
+        }
+        "webdav" => {
+            let val = value.trim().to_lowercase();
+            route.webdav = val == "true" || val == "yes" || val == "1";
+        }
+        "client_header_timeout" => {
+            // Headers are read before a route can be chosen
+            return Err("client_header_timeout is only allowed at server level".into());
+        }
         _ => return Err(format!("Unknown route field: {}", key).into()),
     }
     Ok(())
@@ -175,13 +814,25 @@ fn parse_route_field(route: &mut Route, key: &str, value: &str) -> Result<(), Bo
 
 fn parse_server(lines: &[String], start: usize) -> Result<(ServerConfig, usize), Box<dyn Error>> {
     let mut server_name = None;
+    let mut server_names = Vec::new();
     let mut host = None;
     let mut default_server = false;
     let mut client_max_body_size = None;
     let mut root = String::from(".");
     let mut ports = Vec::new();
+    let mut listen = Vec::new();
     let mut error_pages = Vec::new();
     let mut routes = Vec::new();
+    let default_limits = RequestLimits::default();
+    let mut max_header_bytes = default_limits.max_header_bytes;
+    let mut max_header_count = default_limits.max_header_count;
+    let mut max_request_line = default_limits.max_request_line;
+    let mut min_body_rate = 500;
+    let mut keepalive_requests = 100;
+    let mut timeouts = Timeouts::default();
+    let mut login = None;
+    let mut rewrite = Vec::new();
+    let mut returns = Vec::new();
 
     let mut i = start;
 
@@ -208,6 +859,10 @@ fn parse_server(lines: &[String], start: usize) -> Result<(ServerConfig, usize),
         let line = lines[i].trim();
 
         match line {
+            _ if lvl == 4 && line.starts_with("server_names:") => {
+                server_names = parse_list(&line[13..]);
+                i += 1;
+            }
             _ if lvl == 4 && line.starts_with("server_name:") => {
                 server_name = Some(line[12..].trim().trim_matches('"').to_string());
                 i += 1;
@@ -221,6 +876,11 @@ fn parse_server(lines: &[String], start: usize) -> Result<(ServerConfig, usize),
                 ports = p;
                 i = ni;
             }
+            _ if lvl == 4 && line == "listen:" => {
+                let (l, ni) = parse_listen(lines, i)?;
+                listen = l;
+                i = ni;
+            }
             _ if lvl == 4 && line.starts_with("default_server:") => {
                 let val = line[15..].trim().to_lowercase();
                 default_server = val == "true" || val == "yes" || val == "1";
@@ -235,6 +895,68 @@ fn parse_server(lines: &[String], start: usize) -> Result<(ServerConfig, usize),
                 client_max_body_size = Some(line[21..].trim().parse::<usize>()?);
                 i += 1;
             }
+            _ if lvl == 4 && line.starts_with("max_header_bytes:") => {
+                max_header_bytes = line[17..].trim().parse::<usize>()?;
+                i += 1;
+            }
+            _ if lvl == 4 && line.starts_with("max_header_count:") => {
+                max_header_count = line[17..].trim().parse::<usize>()?;
+                i += 1;
+            }
+            _ if lvl == 4 && line.starts_with("max_request_line:") => {
+                max_request_line = line[17..].trim().parse::<usize>()?;
+                i += 1;
+            }
+            _ if lvl == 4 && line.starts_with("min_body_rate:") => {
+                min_body_rate = line[14..].trim().parse::<usize>()?;
+                i += 1;
+            }
+            _ if lvl == 4 && line.starts_with("keepalive_requests:") => {
+                keepalive_requests = line[19..].trim().parse::<usize>()?;
+                if keepalive_requests == 0 {
+                    return Err("keepalive_requests must be at least 1".into());
+                }
+                i += 1;
+            }
+            _ if lvl == 4 && line.starts_with("keepalive_timeout:") => {
+                timeouts.keepalive = parse_duration(&line[18..])?;
+                i += 1;
+            }
+            _ if lvl == 4 && line.starts_with("client_header_timeout:") => {
+                timeouts.client_header = parse_duration(&line[22..])?;
+                i += 1;
+            }
+            _ if lvl == 4 && line.starts_with("client_body_timeout:") => {
+                timeouts.client_body = parse_duration(&line[20..])?;
+                i += 1;
+            }
+            _ if lvl == 4 && line.starts_with("send_timeout:") => {
+                timeouts.send = parse_duration(&line[13..])?;
+                i += 1;
+            }
+            _ if lvl == 4 && line.starts_with("cgi_timeout:") => {
+                timeouts.cgi = parse_duration(&line[12..])?;
+                i += 1;
+            }
+            _ if lvl == 4 && line == "login:" => {
+                let (l, ni) = parse_login(lines, i)?;
+                login = Some(l);
+                i = ni;
+            }
+            _ if lvl == 4 && line == "return:" => {
+                let (rules, ni) = parse_returns(lines, i)?;
+                returns = rules;
+                i = ni;
+            }
+            _ if lvl == 4 && line.starts_with("return:") => {
+                returns = vec![ReturnRule::parse(line[7..].trim().trim_matches('"'))?];
+                i += 1;
+            }
+            _ if lvl == 4 && line == "rewrite:" => {
+                let (rules, ni) = parse_rewrites(lines, i, 6)?;
+                rewrite = rules;
+                i = ni;
+            }
             _ if lvl == 4 && line.starts_with("root:") => {
                 root = line[5..].trim().trim_matches('"').to_string();
                 i += 1;
@@ -257,25 +979,170 @@ fn parse_server(lines: &[String], start: usize) -> Result<(ServerConfig, usize),
         }
     }
 
+    if login.is_none() && routes.iter().any(|r| r.require_session) {
+        return Err("Routes with 'require_session' need a server 'login' block".into());
+    }
+
+    // `server_name` comes first, then `server_names`; without either the
+    // server answers to its host
+    let mut names: Vec<String> = server_name.into_iter().chain(server_names).collect();
+    if names.is_empty() {
+        names.push(host.clone().unwrap_or_else(|| "_".to_string()));
+    }
+    match &host {
+        Some(host) => {
+            let host = host.trim_matches('"').trim_start_matches('[').trim_end_matches(']');
+            for port in if ports.is_empty() { vec![80] } else { ports } {
+                listen.push(ListenAddr {
+                    host: host.to_string(),
+                    port,
+                    ipv6only: true,
+                });
+            }
+        }
+        None if listen.is_empty() => return Err("Missing 'host' or 'listen'".into()),
+        None if !ports.is_empty() => return Err("'ports' needs a 'host'; use 'listen' entries instead".into()),
+        None => {}
+    }
+
+    let server_names = names
+        .iter()
+        .map(|name| ServerName::parse(name))
+        .collect::<Result<Vec<_>, _>>()?;
+
     // Build server config with defaults
     Ok((
         ServerConfig {
-            server_name: server_name.unwrap_or_else(|| host.clone().unwrap_or_else(|| "_".to_string())),
-            host: host.ok_or("Missing 'host'")?,
-            ports: if ports.is_empty() { vec![80] } else { ports },
+            server_name: names.swap_remove(0),
+            server_names,
+            listen,
             default_server,
             error_pages,
             client_max_body_size: client_max_body_size.unwrap_or(1_000_000), // 1MB default
             root,
             routes,
+            max_header_bytes,
+            max_header_count,
+            max_request_line,
+            min_body_rate,
+            keepalive_requests,
+            timeouts,
+            login,
+            rewrite,
+            returns,
         },
         i,
     ))
 }
 
+fn parse_session_store(
+    lines: &[String],
+    start: usize,
+) -> Result<(SessionStoreConfig, usize), Box<dyn Error>> {
+    let mut i = start + 1;
+    let mut backend = SessionBackendKind::Memory;
+    let mut path = None;
+
+    while i < lines.len() && indent_level(&lines[i]) == 2 {
+        let line = lines[i].trim();
+        let (key, value) = line
+            .split_once(':')
+            .ok_or_else(|| format!("Expected 'key: value' in session_store, got '{}'", line))?;
+        let value = value.trim().trim_matches('"');
+        match key.trim() {
+            "backend" => {
+                backend = match value {
+                    "memory" => SessionBackendKind::Memory,
+                    "file" => SessionBackendKind::File,
+                    "log" => SessionBackendKind::Log,
+                    _ => return Err(format!("Unknown session backend: {}", value).into()),
+                }
+            }
+            "path" => path = Some(value.to_string()),
+            _ => return Err(format!("Unknown session_store field: {}", key).into()),
+        }
+        i += 1;
+    }
+
+    let path = path.unwrap_or_else(|| {
+        match backend {
+            SessionBackendKind::Memory => "",
+            SessionBackendKind::File => "./sessions",
+            SessionBackendKind::Log => "./sessions.log",
+        }
+        .to_string()
+    });
+
+    Ok((SessionStoreConfig { backend, path }, i))
+}
+
+fn parse_session(lines: &[String], start: usize) -> Result<(SessionConfig, usize), Box<dyn Error>> {
+    let mut i = start + 1;
+    let mut session = SessionConfig::default();
+
+    while i < lines.len() && indent_level(&lines[i]) == 2 {
+        let line = lines[i].trim();
+        let (key, value) = line
+            .split_once(':')
+            .ok_or_else(|| format!("Expected 'key: value' in session, got '{}'", line))?;
+        let value = value.trim().trim_matches('"');
+        match key.trim() {
+            "cookie_name" => {
+                if value.is_empty() || value.contains([';', '=', ',', ' ']) {
+                    return Err(format!("Invalid session cookie_name: '{}'", value).into());
+                }
+                session.cookie_name = value.to_string();
+            }
+            "domain" => session.domain = Some(value.to_string()),
+            "path" => session.path = value.to_string(),
+            "secure" => session.secure = value == "true" || value == "yes" || value == "1",
+            "same_site" => {
+                session.same_site = match value.to_lowercase().as_str() {
+                    "strict" => Some(SameSite::Strict),
+                    "lax" => Some(SameSite::Lax),
+                    "none" => Some(SameSite::None),
+                    "off" => None,
+                    _ => return Err(format!("Unknown same_site value: {}", value).into()),
+                }
+            }
+            "idle_timeout" => session.idle_timeout = parse_duration(value)?,
+            "max_lifetime" => session.max_lifetime = Some(parse_duration(value)?),
+            "expiry" => {
+                session.expiry = match value {
+                    "sliding" => SessionExpiry::Sliding,
+                    "fixed" => SessionExpiry::Fixed,
+                    _ => return Err(format!("Unknown session expiry: {}", value).into()),
+                }
+            }
+            "id_length" => {
+                session.id_length = value.parse()?;
+                if session.id_length < MIN_SESSION_ID_LENGTH {
+                    return Err(format!(
+                        "session id_length must be at least {}",
+                        MIN_SESSION_ID_LENGTH
+                    )
+                    .into());
+                }
+            }
+            _ => return Err(format!("Unknown session field: {}", key).into()),
+        }
+        i += 1;
+    }
+
+    // Browsers drop SameSite=None cookies that are not also Secure
+    if matches!(session.same_site, Some(SameSite::None)) && !session.secure {
+        return Err("session same_site: none requires secure: true".into());
+    }
+
+    Ok((session, i))
+}
+
 pub fn load_config(path: &str) -> Result<Config, Box<dyn Error>> {
-    let content = fs::read_to_string(path)?;
+    parse_config(&fs::read_to_string(path)?)
+}
 
+/// Parse the content of a config file
+pub(crate) fn parse_config(content: &str) -> Result<Config, Box<dyn Error>> {
     let mut lines = Vec::new();
     for raw in content.lines() {
         let clean = raw.split('#').next().unwrap();
@@ -288,20 +1155,51 @@ pub fn load_config(path: &str) -> Result<Config, Box<dyn Error>> {
         return Err("Empty config file".into());
     }
 
-    if indent_level(&lines[0]) != 0 || lines[0].trim() != "servers:" {
-        return Err("Config must start with 'servers:'".into());
-    }
-
     let mut servers = Vec::new();
-    let mut i = 1;
+    let mut session_store = SessionStoreConfig::default();
+    let mut session = SessionConfig::default();
+    let mut cookie_secrets = Vec::new();
+    let mut i = 0;
 
     while i < lines.len() {
-        if indent_level(&lines[i]) == 2 && lines[i].trim().starts_with("-") {
-            let (server, ni) = parse_server(&lines, i)?;
-            servers.push(server);
-            i = ni;
-        } else {
-            return Err(format!("Expected server list item at line {}: {}", i + 1, lines[i]).into());
+        if indent_level(&lines[i]) != 0 {
+            return Err(format!("Expected a top-level section at line {}: {}", i + 1, lines[i]).into());
+        }
+
+        match lines[i].trim() {
+            "servers:" => {
+                i += 1;
+                while i < lines.len() && indent_level(&lines[i]) > 0 {
+                    if indent_level(&lines[i]) == 2 && lines[i].trim().starts_with("-") {
+                        let (server, ni) = parse_server(&lines, i)?;
+                        servers.push(server);
+                        i = ni;
+                    } else {
+                        return Err(format!("Expected server list item at line {}: {}", i + 1, lines[i]).into());
+                    }
+                }
+            }
+            "session_store:" => {
+                let (store, ni) = parse_session_store(&lines, i)?;
+                session_store = store;
+                i = ni;
+            }
+            "session:" => {
+                let (parsed, ni) = parse_session(&lines, i)?;
+                session = parsed;
+                i = ni;
+            }
+            "cookie_secret:" => {
+                i += 1;
+                while i < lines.len() && indent_level(&lines[i]) == 2 && lines[i].trim().starts_with("-") {
+                    cookie_secrets.push(lines[i].trim()[1..].trim().trim_matches('"').to_string());
+                    i += 1;
+                }
+                if cookie_secrets.iter().any(|s| s.len() < 16) {
+                    return Err("cookie_secret entries must be at least 16 characters".into());
+                }
+            }
+            other => return Err(format!("Unknown top-level section: {}", other).into()),
         }
     }
 
@@ -309,5 +1207,10 @@ pub fn load_config(path: &str) -> Result<Config, Box<dyn Error>> {
         return Err("Config must contain at least one server".into());
     }
 
-    Ok(Config { servers })
+    Ok(Config {
+        servers,
+        session_store,
+        session,
+        cookie_secrets,
+    })
 }
\ No newline at end of file
@@ -1,73 +1,190 @@
+use crate::auth::verify_user;
 use crate::error::get_error_page_path;
 use crate::models::{FileResponse, HttpResponseCommon, SimpleResponse};
+use crate::utils::HttpMethod;
 use crate::utils::cookie::{ Cookie};
+use crate::utils::cookie_keys::CookieKeys;
+use crate::utils::session::{SESSION_RETURN_KEY, SESSION_USER_KEY, SessionStore};
 use crate::{
-    config::ServerConfig,
+    archive::{ArchiveFormat, directory_archive},
+    config::{LoginConfig, Route, ServerConfig},
+    listing::{directory_listing, encode_path},
     request::HttpRequest,
-    response::{HttpResponseBuilder, extract_boundary, extract_multipart_files, write_file},
+    read::resolve_file_path,
+    response::{HttpResponseBuilder, extract_boundary, extract_multipart_files, reason_phrase, write_file},
 };
 use std::fs;
+use std::path::Path;
 use uuid::Uuid;
 
+/// GET and HEAD of static files. With `try_files` each candidate URI is
+/// tried in turn (`$uri` being the request path, a trailing `/` asking for
+/// a directory) and the last one is served whatever happens, or names the
+/// error status (`=404`); otherwise the request path is served. Directories
+/// are listed on listing routes and otherwise answered with their first
+/// existing index file.
 pub fn handle_get(
     request_path: &str,
     server: &ServerConfig,
+    route: &Route,
     request: &HttpRequest,
-    cookie: &Cookie,
+    cookie: Option<&Cookie>,
 ) -> Box<dyn HttpResponseCommon> {
-    let path = request.path.trim_matches('/');
+    let uri = urlencoding::decode(&request.path)
+        .map(|p| p.into_owned())
+        .unwrap_or_else(|_| request.path.clone());
 
-    if let Some(route) = server
-        .routes
-        .iter()
-        .find(|r| r.path.trim_matches('/') == path)
-    {
-        if route.list_directory == Some(true) {
-            let content = HttpResponseBuilder::serve_directory_listing(
-                &server.root,
-                &route.root,
-                &route.path,
-                &cookie,
-            );
-            return Box::new(SimpleResponse::new(content));
+    let Some((last, candidates)) = route.try_files.split_last() else {
+        return serve_path(request_path, &uri, server, route, request, cookie);
+    };
+
+    for candidate in candidates {
+        let candidate = candidate.replace("$uri", &uri);
+        let Some(path) = route_file_path(server, route, &candidate) else {
+            continue;
+        };
+        if candidate.ends_with('/') {
+            if Path::new(&path).is_dir()
+                && let Some(response) = serve_directory(Path::new(&path), &candidate, route, request, cookie)
+            {
+                return response;
+            }
+        } else if Path::new(&path).is_file()
+            && let Ok(file) = FileResponse::new(&path, cookie)
+        {
+            return Box::new(file);
         }
+    }
 
-        if let Some(default_file) = &route.default_file {
-            let (_key, _value) = cookie.to_header_pair();
-            let full_path = format!("{}/{}/{}", server.root, route.root, default_file);
-
-            return match FileResponse::new(&full_path , cookie) {
-                Ok(fr) => Box::new(fr),
-                Err(_) => {
-                    let not_found = get_error_page_path(server, 404);
-                    match FileResponse::new(&not_found , cookie) {
-                        Ok(fr) => Box::new(fr),
-                        Err(_) => Box::new(SimpleResponse::new(
-                            HttpResponseBuilder::not_found().build(),
-                        )),
-                    }
-                }
-            };
+    if let Some(code) = last.strip_prefix('=').and_then(|code| code.parse().ok()) {
+        let error_path = get_error_page_path(server, code);
+        return Box::new(SimpleResponse::new(HttpResponseBuilder::serve_error_page(
+            &error_path,
+            code,
+            reason_phrase(code),
+            cookie,
+        )));
+    }
+    let fallback = last.replace("$uri", &uri);
+    let path = route_file_path(server, route, &fallback).unwrap_or_default();
+    serve_path(&path, &fallback, server, route, request, cookie)
+}
+
+/// File system path of a URI served by this route; None for a URI outside
+/// it (a `try_files` entry pointing elsewhere) or outside its root
+fn route_file_path(server: &ServerConfig, route: &Route, uri: &str) -> Option<String> {
+    let prefix = route.path.trim_end_matches('/');
+    if uri != route.path && !uri.starts_with(&format!("{}/", prefix)) {
+        return None;
+    }
+    resolve_file_path(server, route, uri)
+}
+
+/// The file or directory at `path`, reached at the decoded `uri`
+fn serve_path(
+    path: &str,
+    uri: &str,
+    server: &ServerConfig,
+    route: &Route,
+    request: &HttpRequest,
+    cookie: Option<&Cookie>,
+) -> Box<dyn HttpResponseCommon> {
+    let fs_path = Path::new(path);
+    if fs_path.is_dir() {
+        if let Some(response) = serve_directory(fs_path, uri, route, request, cookie) {
+            return response;
         }
+    } else if fs_path.is_file()
+        && let Ok(file) = FileResponse::new(path, cookie)
+    {
+        return Box::new(file);
     }
+    not_found(server, route, request, cookie)
+}
 
-    // Fallback: try to serve requested file
-    let (_key, _value) = cookie.to_header_pair();
-    match FileResponse::new(&request_path , cookie) {
-        Ok(fr) => Box::new(fr),
-        Err(_) => {
-            let not_found = get_error_page_path(server, 404);
-            match FileResponse::new(&not_found , cookie) {
-                Ok(fr) => Box::new(fr),
-                Err(_) => Box::new(SimpleResponse::new(
-                    HttpResponseBuilder::not_found().cookie(cookie).build(),
+/// Listing (or archive) of a directory on listing routes, which always took
+/// precedence, else its first index file; None when it has neither. A
+/// directory reached without its trailing slash is redirected to it first,
+/// or relative links in the page would resolve against its parent.
+fn serve_directory(
+    dir: &Path,
+    uri: &str,
+    route: &Route,
+    request: &HttpRequest,
+    cookie: Option<&Cookie>,
+) -> Option<Box<dyn HttpResponseCommon>> {
+    if !uri.ends_with('/') {
+        let mut location = format!("{}/", encode_path(uri));
+        if !request.query_string.is_empty() {
+            location.push('?');
+            location.push_str(&request.query_string);
+        }
+        return Some(Box::new(SimpleResponse::new(
+            HttpResponseBuilder::redirect(301, &location).cookie(cookie).build(),
+        )));
+    }
+
+    if route.list_directory == Some(true) {
+        if let Some(download) = request.query_param("download") {
+            return Some(match ArchiveFormat::from_query(&download) {
+                Some(format) => directory_archive(dir, uri, format, route, request, cookie),
+                None => Box::new(SimpleResponse::new(
+                    HttpResponseBuilder::bad_request().cookie(cookie).build(),
                 )),
+            });
+        }
+        let content = directory_listing(dir, uri, route, request, cookie);
+        return Some(Box::new(SimpleResponse::new(content)));
+    }
+
+    route.index_files().iter().find_map(|name| {
+        let index = dir.join(name);
+        if !index.is_file() {
+            return None;
+        }
+        FileResponse::new(&index.to_string_lossy(), cookie)
+            .ok()
+            .map(|file| Box::new(file) as Box<dyn HttpResponseCommon>)
+    })
+}
+
+/// 404 page, or on SPA routes the index of the route root for page
+/// navigations (`Accept: text/html`), the frontend doing its own routing;
+/// assets that are missing still get their 404
+fn not_found(
+    server: &ServerConfig,
+    route: &Route,
+    request: &HttpRequest,
+    cookie: Option<&Cookie>,
+) -> Box<dyn HttpResponseCommon> {
+    let navigation = request
+        .headers
+        .get("accept")
+        .is_some_and(|accept| accept.contains("text/html"));
+    if route.spa
+        && navigation
+        && let Some(root) = resolve_file_path(server, route, &route.path)
+    {
+        for name in route.index_files() {
+            let index = Path::new(&root).join(name);
+            if index.is_file()
+                && let Ok(file) = FileResponse::new(&index.to_string_lossy(), cookie)
+            {
+                return Box::new(file);
             }
         }
     }
+
+    let error_path = get_error_page_path(server, 404);
+    Box::new(SimpleResponse::new(HttpResponseBuilder::serve_error_page(
+        &error_path,
+        404,
+        "Not Found",
+        cookie,
+    )))
 }
 
-pub fn handle_delete(file_path: &str, error_page_path: &str, cookie: &Cookie) -> Vec<u8> {
+pub fn handle_delete(file_path: &str, error_page_path: &str, cookie: Option<&Cookie>) -> Vec<u8> {
     match fs::remove_file(file_path) {
         Ok(_) => {
             println!("DELETE: Successfully deleted {}", file_path);
@@ -80,7 +197,57 @@ pub fn handle_delete(file_path: &str, error_page_path: &str, cookie: &Cookie) ->
     }
 }
 
-pub fn handle_post(file_path: &str, request: &HttpRequest, cookie: &Cookie) -> Vec<u8> {
+/// PUT stores the body at the exact request path, creating the file (201) or
+/// replacing it (204). The body goes to a temporary file first and is renamed
+/// into place, so a failed upload never leaves a half-written resource.
+pub fn handle_put(file_path: &str, request: &HttpRequest, cookie: Option<&Cookie>) -> Vec<u8> {
+    // A partial body would be stored as if it were the whole resource
+    if request.headers.contains("content-range") {
+        return HttpResponseBuilder::bad_request()
+            .body(b"Content-Range is not allowed with PUT".to_vec())
+            .cookie(cookie)
+            .build();
+    }
+
+    let path = Path::new(file_path);
+    if file_path.is_empty() || request.path.ends_with('/') || path.is_dir() {
+        return HttpResponseBuilder::conflict()
+            .body(b"PUT target must be a file in an existing directory".to_vec())
+            .cookie(cookie)
+            .build();
+    }
+
+    let existed = path.exists();
+    let temp_path = format!("{}.{}.tmp", file_path, Uuid::new_v4());
+    let body = request.body.as_deref().unwrap_or_default();
+    if let Err(e) = fs::write(&temp_path, body).and_then(|_| fs::rename(&temp_path, file_path)) {
+        let _ = fs::remove_file(&temp_path);
+        return HttpResponseBuilder::internal_error()
+            .body(e.to_string().into_bytes())
+            .cookie(cookie)
+            .build();
+    }
+
+    println!("PUT: {} {}", if existed { "replaced" } else { "created" }, file_path);
+    if existed {
+        HttpResponseBuilder::no_content().cookie(cookie).build()
+    } else {
+        HttpResponseBuilder::created()
+            .header("Location", &request.path)
+            .cookie(cookie)
+            .build()
+    }
+}
+
+/// OPTIONS lists the methods of a route, or of the whole server for `OPTIONS *`
+pub fn handle_options(allow_header: &str, cookie: Option<&Cookie>) -> Vec<u8> {
+    HttpResponseBuilder::ok()
+        .header("Allow", allow_header)
+        .cookie(cookie)
+        .build()
+}
+
+pub fn handle_post(file_path: &str, request: &HttpRequest, cookie: Option<&Cookie>) -> Vec<u8> {
     let body = match &request.body {
         Some(b) => b,
         None => {
@@ -112,7 +279,7 @@ pub fn handle_post(file_path: &str, request: &HttpRequest, cookie: &Cookie) -> V
         // For direct uploads, extract filename from the request path
 
         let filename: String = {
-            let last_segment = request.path.split('/').last().unwrap_or("");
+            let last_segment = request.path.split('/').next_back().unwrap_or("");
 
             if !last_segment.is_empty() {
                 "".to_string()
@@ -126,7 +293,7 @@ pub fn handle_post(file_path: &str, request: &HttpRequest, cookie: &Cookie) -> V
     }
 
     if content_type.starts_with("multipart/form-data") {
-        let boundary = match extract_boundary(content_type) {
+        let boundary = match extract_boundary(&content_type) {
             Some(b) => b,
             None => {
                 return HttpResponseBuilder::bad_request()
@@ -181,3 +348,62 @@ pub fn handle_post(file_path: &str, request: &HttpRequest, cookie: &Cookie) -> V
             .build()
     }
 }
+
+/// Built-in login endpoint: checks the posted form against the credentials
+/// file and ties the session to the user under a freshly generated ID
+pub fn handle_login(
+    login: &LoginConfig,
+    request: &HttpRequest,
+    session_store: &SessionStore,
+    session_id: &str,
+    cookie: &Cookie,
+    keys: &CookieKeys,
+) -> Vec<u8> {
+    if request.method != HttpMethod::POST {
+        return HttpResponseBuilder::see_other(&login.login_page)
+            .cookie(cookie)
+            .build();
+    }
+
+    let username = request.form_param("username").unwrap_or_default();
+    let password = request.form_param("password").unwrap_or_default();
+
+    if username.is_empty() || !verify_user(&login.credentials, &username, &password) {
+        println!("LOGIN: rejected credentials for '{}'", username);
+        return HttpResponseBuilder::see_other(&format!("{}?error=1", login.login_page))
+            .cookie(cookie)
+            .build();
+    }
+
+    // Prevent session fixation: the pre-login ID is discarded
+    let Some(mut session) = session_store.regenerate(session_id) else {
+        return HttpResponseBuilder::see_other(&login.login_page)
+            .cookie(cookie)
+            .build();
+    };
+
+    // Send the user back to the protected page that redirected them, if any
+    session.set_data(SESSION_USER_KEY, &username);
+    let target = session
+        .remove_data(SESSION_RETURN_KEY)
+        .unwrap_or_else(|| login.success_redirect.clone());
+    session_store.update(&session);
+
+    println!("LOGIN: '{}' logged in", username);
+    HttpResponseBuilder::see_other(&target)
+        .cookie(&session_store.cookie(&session, keys))
+        .build()
+}
+
+/// Built-in logout endpoint: destroys the session and expires its cookie
+pub fn handle_logout(
+    login: &LoginConfig,
+    session_store: &SessionStore,
+    session_id: &str,
+) -> Vec<u8> {
+    session_store.destroy(session_id);
+
+    HttpResponseBuilder::see_other(&login.login_page)
+        .cookie(&session_store.removal_cookie())
+        .build()
+}
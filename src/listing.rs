@@ -0,0 +1,387 @@
+//! Directory listings for routes with `list_directory: true`: an HTML page
+//! (optionally from the route's `listing_template`) or, for clients that
+//! prefer `application/json`, a machine-readable list.
+
+use std::fs;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+use crate::config::Route;
+use crate::request::HttpRequest;
+use crate::response::{HttpResponseBuilder, detect_content_type};
+use crate::utils::cookie::Cookie;
+use crate::utils::json::json_string;
+use crate::utils::xml::escape;
+
+/// Page used when the route has no `listing_template`. Placeholders:
+/// `{{title}}`, `{{breadcrumbs}}`, `{{header}}`, `{{rows}}`, `{{summary}}` and
+/// `{{downloads}}` (links to the `?download=` archives).
+const DEFAULT_TEMPLATE: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>Index of {{title}}</title>
+<style>
+body { font-family: sans-serif; margin: 2em; }
+table { border-collapse: collapse; min-width: 60%; }
+th, td { text-align: left; padding: 0.25em 1em 0.25em 0; }
+td.size { text-align: right; }
+th a { color: inherit; }
+</style>
+</head>
+<body>
+<h1>Index of {{breadcrumbs}}</h1>
+<table>
+<thead>{{header}}</thead>
+<tbody>
+{{rows}}
+</tbody>
+</table>
+<p>{{summary}}</p>
+<p>{{downloads}}</p>
+</body>
+</html>
+"#;
+
+struct Entry {
+    name: String,
+    is_dir: bool,
+    size: u64,
+    modified: Option<u64>, // Unix seconds
+}
+
+impl Entry {
+    fn kind(&self) -> &'static str {
+        if self.is_dir {
+            "directory"
+        } else {
+            detect_content_type(&self.name)
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum SortKey {
+    Name,
+    Size,
+    Modified,
+    Type,
+}
+
+impl SortKey {
+    fn from_query(value: Option<&str>) -> SortKey {
+        match value {
+            Some("size") => SortKey::Size,
+            Some("mtime") | Some("modified") => SortKey::Modified,
+            Some("type") => SortKey::Type,
+            _ => SortKey::Name,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            SortKey::Name => "name",
+            SortKey::Size => "size",
+            SortKey::Modified => "mtime",
+            SortKey::Type => "type",
+        }
+    }
+}
+
+/// Listing of `dir`, reached at the decoded URL path `href`. Entries are
+/// sorted with `?sort=name|size|mtime|type&order=asc|desc`, directories
+/// first; dot files are left out unless the route sets `show_hidden`.
+pub fn directory_listing(
+    dir: &Path,
+    href: &str,
+    route: &Route,
+    request: &HttpRequest,
+    cookie: Option<&Cookie>,
+) -> Vec<u8> {
+    let href = if href.ends_with('/') {
+        href.to_string()
+    } else {
+        format!("{}/", href)
+    };
+
+    let Ok(read_dir) = fs::read_dir(dir) else {
+        return HttpResponseBuilder::not_found().cookie(cookie).build();
+    };
+    let mut entries: Vec<Entry> = read_dir
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.starts_with('.') && !route.show_hidden {
+                return None;
+            }
+            // Follows symbolic links; dangling ones are left out
+            let metadata = fs::metadata(entry.path()).ok()?;
+            Some(Entry {
+                name,
+                is_dir: metadata.is_dir(),
+                size: metadata.len(),
+                modified: metadata
+                    .modified()
+                    .ok()
+                    .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                    .map(|d| d.as_secs()),
+            })
+        })
+        .collect();
+
+    let sort = SortKey::from_query(request.query_param("sort").as_deref());
+    let descending = request.query_param("order").as_deref() == Some("desc");
+    entries.sort_by(|a, b| {
+        let order = match sort {
+            SortKey::Name => a.name.to_lowercase().cmp(&b.name.to_lowercase()),
+            SortKey::Size => a.size.cmp(&b.size),
+            SortKey::Modified => a.modified.cmp(&b.modified),
+            SortKey::Type => a.kind().cmp(b.kind()),
+        }
+        .then_with(|| a.name.cmp(&b.name));
+        let order = if descending { order.reverse() } else { order };
+        b.is_dir.cmp(&a.is_dir).then(order)
+    });
+
+    let at_route_root = href.trim_end_matches('/') == route.path.trim_end_matches('/');
+
+    if prefers_json(request) {
+        let body = json_listing(&href, &entries);
+        return HttpResponseBuilder::ok()
+            .header("Content-Type", "application/json")
+            .header("Vary", "Accept")
+            .body(body.into_bytes())
+            .cookie(cookie)
+            .build();
+    }
+
+    let template = match &route.listing_template {
+        Some(path) => fs::read_to_string(path).unwrap_or_else(|e| {
+            eprintln!("Listing template '{}' unreadable ({}), using the default", path, e);
+            DEFAULT_TEMPLATE.to_string()
+        }),
+        None => DEFAULT_TEMPLATE.to_string(),
+    };
+
+    let mut rows = String::new();
+    if !at_route_root {
+        rows.push_str("<tr><td><a href=\"../\">../</a></td><td>directory</td><td></td><td></td></tr>\n");
+    }
+    for entry in &entries {
+        let suffix = if entry.is_dir { "/" } else { "" };
+        rows.push_str(&format!(
+            "<tr><td><a href=\"{}{}{}\">{}{}</a></td><td>{}</td><td class=\"size\">{}</td><td>{}</td></tr>\n",
+            escape(&encode_path(&href)),
+            urlencoding::encode(&entry.name),
+            suffix,
+            escape(&entry.name),
+            suffix,
+            entry.kind(),
+            if entry.is_dir { String::new() } else { human_size(entry.size) },
+            entry.modified.map(format_date).unwrap_or_default(),
+        ));
+    }
+
+    let header: String = [
+        (SortKey::Name, "Name"),
+        (SortKey::Type, "Type"),
+        (SortKey::Size, "Size"),
+        (SortKey::Modified, "Modified"),
+    ]
+    .iter()
+    .map(|(key, label)| {
+        // Clicking the current column flips the order
+        let order = if *key == sort && !descending { "desc" } else { "asc" };
+        format!("<th><a href=\"?sort={}&amp;order={}\">{}</a></th>", key.as_str(), order, label)
+    })
+    .collect();
+
+    let directories = entries.iter().filter(|e| e.is_dir).count();
+    let summary = format!(
+        "{} director{}, {} file{}",
+        directories,
+        if directories == 1 { "y" } else { "ies" },
+        entries.len() - directories,
+        if entries.len() - directories == 1 { "" } else { "s" }
+    );
+
+    let page = fill_template(
+        &template,
+        &[
+            ("title", escape(&href)),
+            ("breadcrumbs", breadcrumbs(&href)),
+            ("header", format!("<tr>{}</tr>", header)),
+            ("rows", rows),
+            ("summary", summary),
+            (
+                "downloads",
+                "Download as <a href=\"?download=zip\">zip</a>, <a href=\"?download=tar\">tar</a> or <a href=\"?download=tar.gz\">tar.gz</a>".to_string(),
+            ),
+        ],
+    );
+
+    HttpResponseBuilder::ok()
+        .header("Content-Type", "text/html; charset=utf-8")
+        .header("Vary", "Accept")
+        .body(page.into_bytes())
+        .cookie(cookie)
+        .build()
+}
+
+/// Replace `{{name}}` placeholders in one pass, so values (file names) are
+/// never scanned for placeholders themselves; unknown ones are kept
+fn fill_template(template: &str, values: &[(&str, String)]) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        match after.find("}}") {
+            Some(end) => {
+                let name = after[..end].trim();
+                match values.iter().find(|(key, _)| *key == name) {
+                    Some((_, value)) => out.push_str(value),
+                    None => out.push_str(&rest[start..start + 2 + end + 2]),
+                }
+                rest = &after[end + 2..];
+            }
+            None => {
+                out.push_str(&rest[start..]);
+                rest = "";
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Whether `Accept` ranks `application/json` above `text/html`; ties (as
+/// with `*/*`) go to HTML. The most specific matching range gives the
+/// quality, so `application/json;q=0, */*` refuses JSON.
+fn prefers_json(request: &HttpRequest) -> bool {
+    let Some(accept) = request.headers.get("accept") else {
+        return false;
+    };
+
+    let quality = |wanted: &str| -> f32 {
+        let kind = wanted.split('/').next().unwrap_or(wanted);
+        accept
+            .split(',')
+            .filter_map(|range| {
+                let mut params = range.split(';');
+                let media = params.next()?.trim().to_ascii_lowercase();
+                let q = params
+                    .find_map(|p| p.trim().strip_prefix("q=").and_then(|q| q.parse().ok()))
+                    .unwrap_or(1.0);
+                let specificity = if media == wanted {
+                    2
+                } else if media == format!("{}/*", kind) {
+                    1
+                } else if media == "*/*" {
+                    0
+                } else {
+                    return None;
+                };
+                Some((specificity, q))
+            })
+            .max_by_key(|(specificity, _)| *specificity)
+            .map_or(0.0, |(_, q)| q)
+    };
+
+    quality("application/json") > quality("text/html")
+}
+
+fn json_listing(href: &str, entries: &[Entry]) -> String {
+    let items: Vec<String> = entries
+        .iter()
+        .map(|entry| {
+            let modified = entry
+                .modified
+                .map(|secs| json_string(&format_rfc3339(secs)))
+                .unwrap_or_else(|| "null".to_string());
+            format!(
+                "{{\"name\":{},\"href\":{},\"type\":{},\"content_type\":{},\"size\":{},\"modified\":{}}}",
+                json_string(&entry.name),
+                json_string(&format!(
+                    "{}{}{}",
+                    encode_path(href),
+                    urlencoding::encode(&entry.name),
+                    if entry.is_dir { "/" } else { "" }
+                )),
+                json_string(if entry.is_dir { "directory" } else { "file" }),
+                json_string(entry.kind()),
+                entry.size,
+                modified
+            )
+        })
+        .collect();
+    format!("{{\"path\":{},\"entries\":[{}]}}", json_string(href), items.join(","))
+}
+
+/// `/ blog / photos /`, each level linking to itself
+fn breadcrumbs(href: &str) -> String {
+    let mut out = String::from("<a href=\"/\">/</a>");
+    let mut current = String::from("/");
+    for segment in href.split('/').filter(|s| !s.is_empty()) {
+        current.push_str(&urlencoding::encode(segment));
+        current.push('/');
+        out.push_str(&format!(
+            " <a href=\"{}\">{}</a> /",
+            escape(&current),
+            escape(segment)
+        ));
+    }
+    out
+}
+
+/// Percent-encode each segment of a decoded path
+pub(crate) fn encode_path(path: &str) -> String {
+    path.split('/')
+        .map(|segment| urlencoding::encode(segment).into_owned())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+/// Calendar date and time (UTC) of a Unix timestamp
+pub(crate) fn civil(secs: u64) -> (i64, u32, u32, u64, u64, u64) {
+    let days = (secs / 86400) as i64;
+    let rem = secs % 86400;
+
+    // Days since 1970-01-01 to a proleptic Gregorian date (Howard Hinnant)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = (if mp < 10 { mp + 3 } else { mp - 9 }) as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    (year, month, day, rem / 3600, rem % 3600 / 60, rem % 60)
+}
+
+fn format_date(secs: u64) -> String {
+    let (y, mo, d, h, mi, _) = civil(secs);
+    format!("{:04}-{:02}-{:02} {:02}:{:02}", y, mo, d, h, mi)
+}
+
+fn format_rfc3339(secs: u64) -> String {
+    let (y, mo, d, h, mi, s) = civil(secs);
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", y, mo, d, h, mi, s)
+}
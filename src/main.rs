@@ -1,14 +1,21 @@
+pub mod archive;
+pub mod auth;
 pub mod cgi;
 pub mod config;
 pub mod error;
 pub mod request;
 pub mod router;
 pub mod server;
+pub mod timer;
 pub mod utils;
+pub mod vhost;
 pub(crate) mod response;
 pub mod handler;
+pub mod listing;
 pub mod models;
 pub mod read;
+pub mod rewrite;
+pub mod webdav;
 pub mod write;
 
 use server::Server;
@@ -1,11 +1,50 @@
 use std::{fs::File, io::{self, BufReader, Read}};
 
-use crate::{response::detect_content_type, utils::cookie::Cookie};
+use crate::{response::detect_content_type, utils::{HttpHeaders, cookie::Cookie}};
 pub trait HttpResponseCommon {
     fn peek(&self) -> &[u8];
     fn next(&mut self, n: usize);
     fn is_finished(&self) -> bool;
     fn fill_if_needed(&mut self) -> io::Result<()>;
+    /// Add a header line; only possible before anything has been sent
+    fn add_header(&mut self, name: &str, value: &str);
+    /// Send the head only, as the answer to a HEAD request: the headers
+    /// stay those of the full response
+    fn omit_body(&mut self);
+    /// Nothing can be sent until the source produces more (a CGI script
+    /// still running), as opposed to waiting for the client to read
+    fn waiting_for_source(&self) -> bool {
+        false
+    }
+}
+
+/// Insert `name: value` at the end of the header section of `head`,
+/// replacing a field of the same name the handler may already have set
+fn insert_header_line(head: &mut Vec<u8>, name: &str, value: &str) {
+    let Some(end) = head.windows(4).position(|w| w == b"\r\n\r\n") else {
+        return;
+    };
+
+    // Drop an existing occurrence (first line is the status line)
+    let mut lines: Vec<&[u8]> = head[..end]
+        .split(|&b| b == b'\n')
+        .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+        .collect();
+    let status_line = lines.remove(0);
+    lines.retain(|line| {
+        line.iter().position(|&b| b == b':').is_none_or(|colon| {
+            !String::from_utf8_lossy(&line[..colon]).trim().eq_ignore_ascii_case(name)
+        })
+    });
+
+    let mut rebuilt = status_line.to_vec();
+    for line in lines {
+        rebuilt.extend_from_slice(b"\r\n");
+        rebuilt.extend_from_slice(line);
+    }
+    rebuilt.extend_from_slice(format!("\r\n{}: {}", name, value).as_bytes());
+    rebuilt.extend_from_slice(&head[end..]);
+    *head = rebuilt;
 }
 
 pub struct SimpleResponse {
@@ -34,6 +73,18 @@ impl HttpResponseCommon for SimpleResponse {
     fn fill_if_needed(&mut self) -> io::Result<()> {
         Ok(())
     } // no-op
+
+    fn add_header(&mut self, name: &str, value: &str) {
+        if self.index == 0 {
+            insert_header_line(&mut self.data, name, value);
+        }
+    }
+
+    fn omit_body(&mut self) {
+        if let Some(end) = self.data.windows(4).position(|w| w == b"\r\n\r\n") {
+            self.data.truncate(end + 4);
+        }
+    }
 }
 
 pub struct FileResponse {
@@ -48,16 +99,19 @@ pub struct FileResponse {
 }
 
 impl FileResponse {
-    pub fn new(file_path: &str, cookie: &Cookie) -> io::Result<Self> {
+    pub fn new(file_path: &str, cookie: Option<&Cookie>) -> io::Result<Self> {
         let content_type = detect_content_type(file_path);
         let file = File::open(file_path)?;
         let metadata = file.metadata()?;
 
+        let set_cookie = cookie
+            .map(|c| format!("Set-Cookie: {}\r\n", c.to_header_value()))
+            .unwrap_or_default();
         let headers = format!(
-            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nContent-Type: {}\r\nSet-Cookie: {}\r\n\r\n",
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nContent-Type: {}\r\n{}\r\n",
             metadata.len(),
             content_type,
-            cookie.to_header_value()
+            set_cookie
         )
         .into_bytes();
 
@@ -117,4 +171,281 @@ impl HttpResponseCommon for FileResponse {
         }
         Ok(())
     }
-}
\ No newline at end of file
+
+    fn add_header(&mut self, name: &str, value: &str) {
+        if self.headers_index == 0 {
+            insert_header_line(&mut self.headers, name, value);
+        }
+    }
+
+    fn omit_body(&mut self) {
+        self.finished = true;
+        self.buf_len = 0;
+        self.buf_index = 0;
+    }
+}
+
+/// Body of a length known in advance read from any source (a generated
+/// archive), without `Transfer-Encoding` so HTTP/1.0 clients can have it
+/// too. The source must produce exactly the announced length; a read error
+/// stops the response short of it.
+pub struct StreamResponse {
+    headers: Vec<u8>,
+    headers_index: usize,
+    source: Box<dyn Read>,
+    buffer: [u8; 8192],
+    buf_len: usize,
+    buf_index: usize,
+    finished: bool,
+}
+
+impl StreamResponse {
+    /// `headers` is a head built with `HttpResponseBuilder::build_sized`
+    pub fn new(headers: Vec<u8>, source: impl Read + 'static) -> Self {
+        Self {
+            headers,
+            headers_index: 0,
+            source: Box::new(source),
+            buffer: [0; 8192],
+            buf_len: 0,
+            buf_index: 0,
+            finished: false,
+        }
+    }
+}
+
+impl HttpResponseCommon for StreamResponse {
+    fn peek(&self) -> &[u8] {
+        if self.headers_index < self.headers.len() {
+            &self.headers[self.headers_index..]
+        } else {
+            &self.buffer[self.buf_index..self.buf_len]
+        }
+    }
+
+    fn next(&mut self, n: usize) {
+        if self.headers_index < self.headers.len() {
+            self.headers_index += n;
+        } else {
+            self.buf_index += n;
+        }
+    }
+
+    fn is_finished(&self) -> bool {
+        self.headers_index >= self.headers.len() && self.finished && self.buf_index >= self.buf_len
+    }
+
+    fn fill_if_needed(&mut self) -> io::Result<()> {
+        if self.headers_index >= self.headers.len()
+            && self.buf_index >= self.buf_len
+            && !self.finished
+        {
+            let n = self.source.read(&mut self.buffer)?;
+            self.buf_index = 0;
+            self.buf_len = n;
+            if n == 0 {
+                self.finished = true;
+            }
+        }
+        Ok(())
+    }
+
+    fn add_header(&mut self, name: &str, value: &str) {
+        if self.headers_index == 0 {
+            insert_header_line(&mut self.headers, name, value);
+        }
+    }
+
+    fn omit_body(&mut self) {
+        self.finished = true;
+        self.buf_len = 0;
+        self.buf_index = 0;
+    }
+}
+
+/// Body of unknown length sent with `Transfer-Encoding: chunked`, read from
+/// any source (CGI output, a compressor, an upstream connection). Each read
+/// becomes one chunk; the end of the source sends the last chunk and the
+/// trailers. A source with nothing to give yet returns `WouldBlock`, which
+/// leaves the response with nothing to send until it is filled again. Any
+/// other read error stops the response without its last chunk, which tells
+/// the client the body is incomplete.
+pub struct ChunkedResponse {
+    headers: Vec<u8>,
+    headers_index: usize,
+    source: Box<dyn Read>,
+    trailers: HttpHeaders,
+    /// Encoded bytes waiting to be written (one chunk at a time)
+    pending: Vec<u8>,
+    pending_index: usize,
+    /// The last read found nothing to send yet
+    waiting: bool,
+    finished: bool,
+}
+
+impl ChunkedResponse {
+    /// `headers` is a head built with `HttpResponseBuilder::build_chunked`
+    pub fn new(headers: Vec<u8>, source: impl Read + 'static) -> Self {
+        Self {
+            headers,
+            headers_index: 0,
+            source: Box::new(source),
+            trailers: HttpHeaders::new(),
+            pending: Vec::new(),
+            pending_index: 0,
+            waiting: false,
+            finished: false,
+        }
+    }
+
+    /// Fields sent after the last chunk; the head should announce them in
+    /// a `Trailer` header
+    pub fn trailers(mut self, trailers: HttpHeaders) -> Self {
+        self.trailers = trailers;
+        self
+    }
+
+    fn encode_next(&mut self) -> io::Result<()> {
+        let mut buffer = [0u8; 8192];
+        let n = match self.source.read(&mut buffer) {
+            Ok(n) => n,
+            // Nothing yet; the response is filled again once there is
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                self.waiting = true;
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        self.waiting = false;
+
+        self.pending.clear();
+        self.pending_index = 0;
+        if n == 0 {
+            self.pending.extend_from_slice(b"0\r\n");
+            for (name, value) in self.trailers.iter() {
+                self.pending
+                    .extend_from_slice(format!("{}: {}\r\n", name, value).as_bytes());
+            }
+            self.pending.extend_from_slice(b"\r\n");
+            self.finished = true;
+        } else {
+            self.pending
+                .extend_from_slice(format!("{:x}\r\n", n).as_bytes());
+            self.pending.extend_from_slice(&buffer[..n]);
+            self.pending.extend_from_slice(b"\r\n");
+        }
+        Ok(())
+    }
+}
+
+impl HttpResponseCommon for ChunkedResponse {
+    fn peek(&self) -> &[u8] {
+        if self.headers_index < self.headers.len() {
+            &self.headers[self.headers_index..]
+        } else {
+            &self.pending[self.pending_index..]
+        }
+    }
+
+    fn next(&mut self, n: usize) {
+        if self.headers_index < self.headers.len() {
+            self.headers_index += n;
+        } else {
+            self.pending_index += n;
+        }
+    }
+
+    fn is_finished(&self) -> bool {
+        self.headers_index >= self.headers.len()
+            && self.finished
+            && self.pending_index >= self.pending.len()
+    }
+
+    fn fill_if_needed(&mut self) -> io::Result<()> {
+        if self.headers_index >= self.headers.len()
+            && self.pending_index >= self.pending.len()
+            && !self.finished
+        {
+            self.encode_next()?;
+        }
+        Ok(())
+    }
+
+    fn add_header(&mut self, name: &str, value: &str) {
+        if self.headers_index == 0 {
+            insert_header_line(&mut self.headers, name, value);
+        }
+    }
+
+    fn omit_body(&mut self) {
+        self.finished = true;
+        self.pending.clear();
+        self.pending_index = 0;
+    }
+
+    fn waiting_for_source(&self) -> bool {
+        self.waiting && !self.finished
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    /// Source handing out scripted reads; `None` stands for `WouldBlock`
+    struct Script(VecDeque<Option<&'static [u8]>>);
+
+    impl Read for Script {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.0.pop_front() {
+                Some(Some(data)) => {
+                    buf[..data.len()].copy_from_slice(data);
+                    Ok(data.len())
+                }
+                Some(None) => Err(io::ErrorKind::WouldBlock.into()),
+                None => Ok(0),
+            }
+        }
+    }
+
+    /// Everything the response has to send right now
+    fn drain(response: &mut dyn HttpResponseCommon) -> Vec<u8> {
+        let mut out = Vec::new();
+        loop {
+            response.fill_if_needed().expect("fill");
+            let data = response.peek().to_vec();
+            if data.is_empty() {
+                return out;
+            }
+            response.next(data.len());
+            out.extend_from_slice(&data);
+        }
+    }
+
+    #[test]
+    fn chunked_response_waits_for_its_source() {
+        let source = Script(VecDeque::from([Some(&b"hello"[..]), None, Some(&b" world!"[..])]));
+        let mut trailers = HttpHeaders::new();
+        trailers.insert("X-Done", "yes");
+        let mut response = ChunkedResponse::new(b"HEAD\r\n\r\n".to_vec(), source).trailers(trailers);
+
+        assert_eq!(drain(&mut response), b"HEAD\r\n\r\n5\r\nhello\r\n");
+        assert!(response.waiting_for_source());
+        assert!(!response.is_finished());
+
+        assert_eq!(drain(&mut response), b"7\r\n world!\r\n0\r\nX-Done: yes\r\n\r\n");
+        assert!(!response.waiting_for_source());
+        assert!(response.is_finished());
+    }
+
+    #[test]
+    fn chunked_response_without_body_sends_the_head_only() {
+        let source = Script(VecDeque::from([Some(&b"data"[..])]));
+        let mut response = ChunkedResponse::new(b"HTTP/1.1 200 OK\r\n\r\n".to_vec(), source);
+        response.add_header("Connection", "close");
+        response.omit_body();
+        assert_eq!(drain(&mut response), b"HTTP/1.1 200 OK\r\nConnection: close\r\n\r\n");
+        assert!(response.is_finished());
+    }
+}
@@ -1,12 +1,16 @@
-use std::{io::{self, Read}, path::Path, time::Instant};
+use std::{borrow::Cow, io::{self, Read}, path::Path, time::Instant};
 use mio::net::TcpStream;
+use crate::auth::{AuthUser, authenticate};
 use crate::cgi::run_cgi;
+use crate::webdav::handle_webdav;
 use crate::handler::*;
-use crate::{config::Route, utils::{HttpHeaders, session::handle_session}};
+use crate::rewrite::{RewriteStop, expand_variables, rewrite_request};
+use crate::vhost::{find_server, normalize_hostname};
+use crate::{utils::{HttpHeaders, session::{SESSION_RETURN_KEY, handle_session}}};
 use crate::response::{HttpResponseBuilder, handle_method_not_allowed};
-use crate::{config::ServerConfig, models::{HttpResponseCommon, SimpleResponse}, request::{HttpRequest, ParserState}, server::{ListenerInfo, SocketData, SocketStatus, Status}, utils::{HttpMethod, cookie::Cookie}};
+use crate::{config::{Route, ServerConfig}, models::{HttpResponseCommon, SimpleResponse}, request::{HttpRequest, ParseError, ParserState}, server::{ListenerInfo, Outgoing, SocketData, SocketStatus, Status}, utils::{HttpMethod, cookie::Cookie}};
 
-fn resolve_file_path(
+pub(crate) fn resolve_file_path(
     server: &ServerConfig,
     route: &crate::config::Route,
     request_path: &str,
@@ -29,7 +33,12 @@ fn resolve_file_path(
         .unwrap_or("")
         .trim_start_matches('/');
 
-    let full_path = base_path.join(relative_path);
+    // A regex route's root may name the file itself
+    let full_path = if relative_path.is_empty() {
+        base_path.clone()
+    } else {
+        base_path.join(relative_path)
+    };
     let canonical = match full_path.canonicalize() {
         Ok(path) => path,
         Err(_) => {
@@ -50,26 +59,22 @@ fn resolve_file_path(
 }
 
 
-fn find_matching_route<'a>(server: &'a ServerConfig, request_path: &str) -> Option<&'a Route> {
-    server
-        .routes
-        .iter()
-        .filter(|route| {
-            if route.path == "/" {
-                true
-            } else {
-                request_path == route.path || request_path.starts_with(&(route.path.clone() + "/"))
-            }
-        })
-        .max_by_key(|route| route.path.len())
-}
-
-
-fn extract_hostname(headers: &HttpHeaders) -> &str {
-    headers
-        .get("host")
-        .and_then(|h| h.split(':').next())
-        .unwrap_or("")
+/// Host name of the `Host` header, without port, normalized; an IPv6
+/// literal keeps its brackets (`[::1]:8080` gives `[::1]`)
+pub(crate) fn extract_hostname(headers: &HttpHeaders) -> String {
+    let Some(host) = headers.get("host") else {
+        return String::new();
+    };
+    let host = host.trim();
+    let name = if host.starts_with('[') {
+        match host.find(']') {
+            Some(end) => &host[..=end],
+            None => host,
+        }
+    } else {
+        host.split(':').next().unwrap_or_default()
+    };
+    normalize_hostname(name)
 }
 
 
@@ -83,11 +88,7 @@ fn get_error_page_path(server: &ServerConfig, status_code: u16) -> String {
 }
 
 fn select_server<'a>(listener_info: &'a ListenerInfo, hostname: &str) -> &'a ServerConfig {
-    if let Some(srv) = listener_info
-        .servers
-        .iter()
-        .find(|s| s.server_name == hostname)
-    {
+    if let Some(srv) = find_server(&listener_info.servers, hostname) {
         println!(
             "Selected server '{}' for Host: {}",
             srv.server_name, hostname
@@ -112,43 +113,159 @@ fn select_server<'a>(listener_info: &'a ListenerInfo, hostname: &str) -> &'a Ser
     default_srv
 }
 
+/// Answer a request that could not be parsed with the matching error status.
+/// The request never completes, so the connection is closed once the
+/// response has been written.
+fn reject_request(
+    socket: &mut SocketStatus,
+    listener_info: Option<&ListenerInfo>,
+    error: &ParseError,
+) {
+    let (status_code, status_text) = error.status();
+    println!("Rejecting request with {}: {:?}", status_code, error);
+
+    let error_path = match listener_info.and_then(|info| info.servers.get(info.default_server_index)) {
+        Some(server) => get_error_page_path(server, status_code),
+        None => format!("./error_pages/{}.html", status_code),
+    };
+
+    let response = HttpResponseBuilder::error_page(&error_path, status_code, status_text).build();
+    socket.response = Some(Box::new(SimpleResponse::new(response)));
+    socket.status = Status::Write;
+}
+
+/// Whether the route refuses the request on its headers alone (method or
+/// credentials), so a client expecting `100 Continue` never sends the body.
+/// The credentials are checked once: the outcome is kept in `auth` for the
+/// dispatch, as a Digest nonce count cannot be presented twice.
+fn refuse_before_body(
+    route: &Route,
+    request: &HttpRequest,
+    auth: &mut Option<Result<AuthUser, String>>,
+) -> bool {
+    if !route.allows(&request.method) {
+        return true;
+    }
+    match &route.auth {
+        Some(config) => auth.insert(authenticate(config, request)).is_err(),
+        None => false,
+    }
+}
+
+/// Add received bytes to the request being parsed. Returns `Some(true)` once
+/// the request is complete (or has been rejected) and `Some(false)` when more
+/// bytes are needed.
+fn feed_request(
+    socket: &mut SocketStatus,
+    listener_info: Option<&ListenerInfo>,
+    data: Vec<u8>,
+) -> Option<bool> {
+    if socket.request_started.is_none() {
+        socket.request_started = Some(Instant::now());
+    }
+
+    if let Err(e) = socket.request.append(data) {
+        reject_request(socket, listener_info, &e);
+        return Some(true);
+    }
+
+    if socket.request.header_done() && !socket.server_selected {
+        let hostname = extract_hostname(&socket.request.get_before_done()?.headers);
+        let info = listener_info?;
+        let selected = select_server(info, &hostname);
+        let body_pending = !socket.request.done();
+        let request = socket.request.get_mut_before_done()?;
+
+        // Rewritten and routed once, on the headers: the route decides the
+        // timeouts and the answer to `Expect: 100-continue` as well as the
+        // dispatch of the complete request
+        let target = rewrite_request(selected, request).map(|route| route.map(Cow::into_owned));
+        let request: &HttpRequest = request;
+
+        socket.max_body_size = Some(selected.client_max_body_size);
+        socket.min_body_rate = selected.min_body_rate;
+        socket.timeouts = match &target {
+            Ok(Some(route)) => selected.timeouts.for_route(route),
+            _ => selected.timeouts,
+        };
+        socket.body_started = Some(Instant::now());
+        socket.server_selected = true;
+
+        let mut refused = false;
+        let mut too_large = false;
+        if body_pending && request.expects_continue() {
+            too_large = request
+                .content_length()
+                .is_some_and(|len| len > selected.client_max_body_size);
+            // A redirect or `return` rule needs no body either
+            refused = match &target {
+                Ok(Some(route)) => refuse_before_body(route, request, &mut socket.auth),
+                Ok(None) => false,
+                Err(_) => true,
+            };
+            if !too_large && !refused {
+                // Queued behind the responses already waiting, so it never
+                // overtakes them; written like any response
+                socket.outbox.push_back(Outgoing {
+                    response: Box::new(SimpleResponse::new(b"HTTP/1.1 100 Continue\r\n\r\n".to_vec())),
+                    keep_alive: true,
+                });
+            }
+        }
+        socket.target = Some(target);
+
+        if too_large {
+            socket.body_too_large = true;
+            socket.request.set_state(ParserState::Complete);
+            return Some(true);
+        }
+        if refused {
+            // Answer from the headers; the final response (413, 405 or
+            // 401) is built as usual and the connection closed after it
+            socket.body_skipped = true;
+            socket.request.set_state(ParserState::Complete);
+            return Some(true);
+        }
+    }
+
+    if let Some(max) = socket.max_body_size
+        && socket.request.body_len() > max
+    {
+        socket.body_too_large = true;
+        socket.request.set_state(ParserState::Complete);
+        return Some(true);
+    }
+
+    Some(socket.request.done())
+}
+
 fn read_request(
     stream: &mut TcpStream,
     socket: &mut SocketStatus,
     listener_info: Option<&ListenerInfo>,
 ) -> Option<bool> {
+    // A pipelined request may already be (partly) buffered
+    if socket.request.take_carried_over() {
+        match feed_request(socket, listener_info, Vec::new()) {
+            Some(false) => {}
+            other => return other,
+        }
+    }
+
     let mut buf = [0u8; 4096];
 
     loop {
-        socket.ttl = Instant::now();
-
         match stream.read(&mut buf) {
-            Ok(0) => return None,
+            Ok(0) => {
+                socket.peer_closed = true;
+                return None;
+            }
 
             Ok(n) => {
-                socket.request.append(buf[..n].to_vec()).ok()?;
-
-                if socket.request.header_done() && !socket.server_selected {
-                    println!("hello");
-                    let request = socket.request.get_before_done()?;
-                    let hostname = extract_hostname(&request.headers);
-                    let info = listener_info?;
-
-                    let selected = select_server(info, hostname);
-                    socket.max_body_size = Some(selected.client_max_body_size);
-                    socket.server_selected = true;
-                }
-
-                if let Some(max) = socket.max_body_size {
-                    if socket.request.body_len() > max {
-                        socket.body_too_large = true;
-                        socket.request.set_state(ParserState::Complete);
-                        return Some(true);
-                    }
-                }
-
-                if socket.request.done() {
-                    return Some(true);
+                socket.ttl = Instant::now();
+                match feed_request(socket, listener_info, buf[..n].to_vec()) {
+                    Some(false) => {}
+                    other => return other,
                 }
             }
 
@@ -177,15 +294,62 @@ pub fn handle_read_state(
         other => return other,
     }
 
-    let request: &HttpRequest = socket_data.status.request.get()?;
-
-    // handle cookies and sessions
-    let cookie: Cookie = handle_session(request, &mut socket_data.session_store);
+    // The request was rejected while parsing; the error response is ready.
+    if socket_data.status.status == Status::Write {
+        return Some(true);
+    }
 
     // Select server based on Host header
-    let hostname = extract_hostname(&request.headers);
+    let hostname = extract_hostname(&socket_data.status.request.get()?.headers);
     let info = listener_info.expect("No listener info available");
-    let selected_server: &ServerConfig = select_server(info, hostname);
+    let selected_server: &ServerConfig = select_server(info, &hostname);
+
+    // Rewrite rules may change the target and pick the route themselves;
+    // they already ran when the headers were complete
+    let target = match socket_data.status.target.take() {
+        Some(target) => target,
+        None => rewrite_request(selected_server, socket_data.status.request.get_mut()?)
+            .map(|route| route.map(Cow::into_owned)),
+    };
+    let matched_route = match target {
+        Ok(route) => route,
+        Err(stop) => {
+            let response_bytes = match stop {
+                RewriteStop::Redirect(code, location) => {
+                    HttpResponseBuilder::redirect(code, &location).build()
+                }
+                RewriteStop::Return(response_bytes) => response_bytes,
+                RewriteStop::TooManyCycles => {
+                    eprintln!("Rewrite cycle limit reached, giving up on the request");
+                    let error_path = get_error_page_path(selected_server, 500);
+                    HttpResponseBuilder::error_page(&error_path, 500, "Internal Server Error").build()
+                }
+            };
+            socket_data.status.response = Some(Box::new(SimpleResponse::new(response_bytes)));
+            socket_data.status.status = Status::Write;
+            return Some(true);
+        }
+    };
+    let selected_route = matched_route.as_ref();
+    let request: &HttpRequest = socket_data.status.request.get()?;
+
+    // handle cookies and sessions (routes may opt out, e.g. static assets)
+    let login_endpoint = selected_server
+        .login
+        .as_ref()
+        .is_some_and(|login| request.path == login.path || request.path == login.logout_path);
+    let session: Option<(String, Cookie)> =
+        if login_endpoint || selected_route.is_none_or(|route| route.session) {
+            Some(handle_session(
+                request,
+                &socket_data.session_store,
+                &socket_data.cookie_keys,
+            ))
+        } else {
+            None
+        };
+    let session_id = session.as_ref().map(|(id, _)| id.as_str());
+    let cookie = session.as_ref().map(|(_, cookie)| cookie);
 
     // check if the socket says body too large
     match socket_data.status.body_too_large {
@@ -206,55 +370,154 @@ pub fn handle_read_state(
         }
     }
 
-    let selected_route = find_matching_route(selected_server, &request.path);
+    // Built-in login and logout endpoints take precedence over routes
+    if let Some(login) = &selected_server.login
+        && let Some((session_id, cookie)) = &session
+    {
+        let response_bytes = if request.path == login.path {
+            Some(handle_login(
+                login,
+                request,
+                &socket_data.session_store,
+                session_id,
+                cookie,
+                &socket_data.cookie_keys,
+            ))
+        } else if request.path == login.logout_path {
+            Some(handle_logout(login, &socket_data.session_store, session_id))
+        } else {
+            None
+        };
+
+        if let Some(response_bytes) = response_bytes {
+            socket_data.status.response = Some(Box::new(SimpleResponse::new(response_bytes)));
+            socket_data.status.status = Status::Write;
+            return Some(true);
+        }
+    }
+
+    // The request target `*` is only accepted for OPTIONS
+    if request.path == "*" {
+        let response_bytes = handle_options(&selected_server.allow_header(), cookie);
+        socket_data.status.response = Some(Box::new(SimpleResponse::new(response_bytes)));
+        socket_data.status.status = Status::Write;
+        return Some(true);
+    }
 
     if let Some(route) = selected_route {
+        let auth_user = match &route.auth {
+            // Checked before the body for a client expecting `100 Continue`
+            Some(auth) => match socket_data.status.auth.take().unwrap_or_else(|| authenticate(auth, request)) {
+                Ok(user) => Some(user),
+                Err(challenge) => {
+                    let error_path = get_error_page_path(selected_server, 401);
+                    let response_bytes =
+                        HttpResponseBuilder::error_page(&error_path, 401, "Unauthorized")
+                            .header("WWW-Authenticate", &challenge)
+                            .cookie(cookie)
+                            .build();
+                    socket_data.status.response = Some(Box::new(SimpleResponse::new(response_bytes)));
+                    socket_data.status.status = Status::Write;
+                    return Some(true);
+                }
+            },
+            None => None,
+        };
+
+        if let Some(login) = &selected_server.login
+            && route.require_session
+            && session_id.is_none_or(|id| socket_data.session_store.logged_in_user(id).is_none())
+        {
+            // Remember where the user was going so login can send them back
+            let return_to = if request.query_string.is_empty() {
+                request.path.clone()
+            } else {
+                format!("{}?{}", request.path, request.query_string)
+            };
+            if let Some(session_id) = session_id {
+                socket_data.session_store.with_session(session_id, |session| {
+                    session.set_data(SESSION_RETURN_KEY, &return_to);
+                });
+            }
+
+            let response_bytes = HttpResponseBuilder::see_other(&login.login_page)
+                .cookie(cookie)
+                .build();
+            socket_data.status.response = Some(Box::new(SimpleResponse::new(response_bytes)));
+            socket_data.status.status = Status::Write;
+            return Some(true);
+        }
+
         if let Some(redirect) = &route.redirect {
-            let response_bytes = HttpResponseBuilder::redirect(redirect)
-                .cookie(&cookie)
+            let location = expand_variables(redirect, request, Some(route), &route.captures);
+            let response_bytes = HttpResponseBuilder::redirect(route.redirect_code, &location)
+                .cookie(cookie)
                 .build();
             socket_data.status.response = Some(Box::new(SimpleResponse::new(response_bytes)));
         } else {
-            let request_method = &request.method;
-            let method_allowed = route
-                .methods
-                .iter()
-                .any(|m| HttpMethod::from_str(m) == *request_method);
-
-            if !method_allowed {
-                let allowed = &route.methods;
-                let response_bytes = handle_method_not_allowed(&allowed, &selected_server, &cookie);
+            if !route.allows(&request.method) {
+                let response_bytes =
+                    handle_method_not_allowed(&route.allow_header(), selected_server, cookie);
+                socket_data.status.response = Some(Box::new(SimpleResponse::new(response_bytes)));
+            } else if route.webdav && request.method != HttpMethod::POST {
+                let response = handle_webdav(selected_server, route, request, &socket_data.dav, cookie);
+                socket_data.status.response = Some(response);
+            } else if request.method == HttpMethod::OPTIONS {
+                let response_bytes = handle_options(&route.allow_header(), cookie);
                 socket_data.status.response = Some(Box::new(SimpleResponse::new(response_bytes)));
             } else {
-                let file_path = resolve_file_path(selected_server, route, &request.path)
-                    .unwrap_or_else(|| "".to_string());
-
-                if let Some(cgi_ext) = &route.cgi {
-                    if request.path.ends_with(cgi_ext) {
-                        let cgi_context = crate::cgi::CgiContext::from_request(request);
-                        if run_cgi(route, cgi_context, &file_path, socket_data) {
-                            return Some(true);
-                        } else {
-                            return None;
-                        }
+                // Links in listings are percent-encoded; the file system is not
+                let decoded_path = urlencoding::decode(&request.path)
+                    .map(|p| p.into_owned())
+                    .unwrap_or_else(|_| request.path.clone());
+                let file_path = resolve_file_path(selected_server, route, &decoded_path)
+                    .unwrap_or_default();
+
+                // The resolved script, which a regex route can map any path to
+                if let Some(cgi_ext) = &route.cgi
+                    && file_path.ends_with(cgi_ext)
+                {
+                    let mut cgi_context = crate::cgi::CgiContext::from_request(request);
+                    cgi_context.auth = auth_user;
+                    cgi_context.session =
+                        session_id.and_then(|id| socket_data.session_store.get(id));
+                    cgi_context.session_cookie = cookie.cloned();
+                    if run_cgi(route, cgi_context, &file_path, socket_data) {
+                        return Some(true);
+                    } else {
+                        return None;
                     }
                 }
 
-                let response: Box<dyn HttpResponseCommon> = match request_method {
-                    HttpMethod::GET => handle_get(&file_path, &selected_server, &request, &cookie),
+                let response: Box<dyn HttpResponseCommon> = match &request.method {
+                    // HEAD is answered as GET; the body is dropped when queued
+                    HttpMethod::GET | HttpMethod::HEAD => {
+                        handle_get(&file_path, selected_server, route, request, cookie)
+                    }
                     HttpMethod::POST => {
-                        let response_bytes = handle_post(&file_path, &request, &cookie);
+                        let response_bytes = handle_post(&file_path, request, cookie);
+                        Box::new(SimpleResponse::new(response_bytes))
+                    }
+                    HttpMethod::PUT => {
+                        let response_bytes = handle_put(&file_path, request, cookie);
                         Box::new(SimpleResponse::new(response_bytes))
                     }
                     HttpMethod::DELETE => {
                         let error_path = get_error_page_path(selected_server, 404);
-                        let response_bytes = handle_delete(&file_path, &error_path, &cookie);
+                        let response_bytes = handle_delete(&file_path, &error_path, cookie);
                         Box::new(SimpleResponse::new(response_bytes))
                     }
-                    HttpMethod::Other(_) => {
-                        let allowed = &route.methods;
+                    // PATCH only has a meaning for CGI scripts, and the WebDAV
+                    // methods outside WebDAV routes
+                    _ => {
+                        let allow_header = route
+                            .allow_header()
+                            .split(", ")
+                            .filter(|m| *m != "PATCH")
+                            .collect::<Vec<_>>()
+                            .join(", ");
                         let response_bytes =
-                            handle_method_not_allowed(&allowed, &selected_server, &cookie);
+                            handle_method_not_allowed(&allow_header, selected_server, cookie);
                         Box::new(SimpleResponse::new(response_bytes))
                     }
                 };
@@ -265,7 +528,7 @@ pub fn handle_read_state(
     } else {
         let error_path = get_error_page_path(selected_server, 404);
         let response_bytes =
-            HttpResponseBuilder::serve_error_page(&error_path, 404, "Not Found", &cookie);
+            HttpResponseBuilder::serve_error_page(&error_path, 404, "Not Found", cookie);
         socket_data.status.response = Some(Box::new(SimpleResponse::new(response_bytes)));
     }
 
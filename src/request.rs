@@ -6,13 +6,62 @@ pub struct HttpRequest {
     pub method: HttpMethod,
     pub path: String,
     pub query_string: String,
+    /// Target as received (path and query), kept when rewrites change `path`
+    pub uri: String,
     pub version: String,
     pub headers: HttpHeaders,
     pub body: Option<Vec<u8>>,
     pub session_id: Option<String>, 
+    /// Fields sent after a chunked body, kept apart from `headers`
+    pub trailers: HttpHeaders,
 }
 
 
+/// Limits applied while reading the request line and header section, before
+/// any server block has been selected for the request.
+#[derive(Debug, Clone, Copy)]
+pub struct RequestLimits {
+    pub max_header_bytes: usize,
+    pub max_header_count: usize,
+    pub max_request_line: usize,
+}
+
+impl Default for RequestLimits {
+    fn default() -> Self {
+        Self {
+            max_header_bytes: 16 * 1024,
+            max_header_count: 100,
+            max_request_line: 8 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ParseError {
+    HeaderTooLarge,
+    TooManyHeaders,
+    UriTooLong,
+    Malformed(&'static str),
+    NotImplemented(&'static str),
+    VersionNotSupported,
+    ExpectationFailed,
+}
+
+impl ParseError {
+    pub fn status(&self) -> (u16, &'static str) {
+        match self {
+            ParseError::HeaderTooLarge | ParseError::TooManyHeaders => {
+                (431, "Request Header Fields Too Large")
+            }
+            ParseError::UriTooLong => (414, "URI Too Long"),
+            ParseError::Malformed(_) => (400, "Bad Request"),
+            ParseError::NotImplemented(_) => (501, "Not Implemented"),
+            ParseError::VersionNotSupported => (505, "HTTP Version Not Supported"),
+            ParseError::ExpectationFailed => (417, "Expectation Failed"),
+        }
+    }
+}
+
 pub enum ParserState {
     ParsingHeaders,
     ParsingBody {
@@ -38,23 +87,73 @@ pub struct HttpRequestBuilder {
     buffer: Vec<u8>,
     state: ParserState,
     request: Option<HttpRequest>,
+    limits: RequestLimits,
+    /// Offset just past the complete request in `buffer`
+    request_end: usize,
+    /// `buffer` holds pipelined bytes that have not been parsed yet
+    carried_over: bool,
+}
+
+impl Default for HttpRequestBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl HttpRequestBuilder {
     pub fn new() -> Self {
+        Self::with_limits(RequestLimits::default())
+    }
+
+    pub fn with_limits(limits: RequestLimits) -> Self {
         Self {
             buffer: Vec::new(),
             state: ParserState::ParsingHeaders,
             request: None,
+            limits,
+            request_end: 0,
+            carried_over: false,
         }
     }
 
-    pub fn append(&mut self, data: Vec<u8>) -> Result<(), &'static str> {
+    /// Builder for the next request on the connection, starting with the
+    /// bytes a pipelining client already sent after the current request
+    pub fn next_request(&mut self) -> Self {
+        let mut next = Self::with_limits(self.limits);
+        if self.done() && self.request_end < self.buffer.len() {
+            next.buffer = self.buffer.split_off(self.request_end);
+            next.carried_over = true;
+        }
+        next
+    }
+
+    /// Whether pipelined bytes are waiting to be parsed; clears the flag
+    pub fn take_carried_over(&mut self) -> bool {
+        std::mem::take(&mut self.carried_over)
+    }
+
+    pub fn has_carried_over(&self) -> bool {
+        self.carried_over
+    }
+
+    pub fn limits(&self) -> RequestLimits {
+        self.limits
+    }
+
+    pub fn append(&mut self, data: Vec<u8>) -> Result<(), ParseError> {
         self.buffer.extend(data);
 
         match &self.state {
             ParserState::ParsingHeaders => {
-                if let Some(headers_end) = self.find_headers_end() {
+                // Empty lines before the request line are ignored (RFC 9112 §2.2)
+                let leading = self.buffer.chunks(2).take_while(|c| *c == b"\r\n").count();
+                self.buffer.drain(..leading * 2);
+
+                let end = self.find_headers_end();
+                let scanned = end.unwrap_or(self.buffer.len());
+                self.check_header_limits(scanned)?;
+                self.check_line_endings(scanned)?;
+                if let Some(headers_end) = end {
                     self.parse_headers(headers_end)?;
                 }
             }
@@ -69,8 +168,16 @@ impl HttpRequestBuilder {
 
     pub fn body_len(&self) -> usize {
         match &self.state {
-            ParserState::ParsingBody { headers_end, .. } => {
-                self.buffer.len().saturating_sub(*headers_end)
+            ParserState::ParsingBody {
+                headers_end,
+                body_type,
+            } => {
+                let available = self.buffer.len().saturating_sub(*headers_end);
+                match body_type {
+                    // Bytes past the declared length belong to the next request
+                    BodyType::ContentLength(length) => available.min(*length),
+                    _ => available,
+                }
             }
             ParserState::Complete => {
                 if let Some(req) = &self.request {
@@ -86,16 +193,114 @@ impl HttpRequestBuilder {
         self.state = state;
     }
 
+    /// Reject the header section as soon as it exceeds the configured
+    /// limits, so a client cannot make the buffer grow without bound.
+    fn check_header_limits(&self, headers_end: usize) -> Result<(), ParseError> {
+        let section = &self.buffer[..headers_end];
+
+        let request_line_len = section
+            .iter()
+            .position(|&b| b == b'\n')
+            .unwrap_or(section.len());
+        if request_line_len > self.limits.max_request_line {
+            return Err(ParseError::UriTooLong);
+        }
+
+        if headers_end > self.limits.max_header_bytes {
+            return Err(ParseError::HeaderTooLarge);
+        }
+
+        // Every line after the request line is a header (the final blank
+        // line is not counted once the section is complete).
+        let header_count = section
+            .split(|&b| b == b'\n')
+            .skip(1)
+            .filter(|line| !line.is_empty() && *line != b"\r")
+            .count();
+        if header_count > self.limits.max_header_count {
+            return Err(ParseError::TooManyHeaders);
+        }
+
+        Ok(())
+    }
+
+    /// Every line of the header section must end with CRLF: a lone CR or LF
+    /// is how request smuggling slips past proxies that split lines differently
+    fn check_line_endings(&self, end: usize) -> Result<(), ParseError> {
+        let section = &self.buffer[..end];
+        for (i, &b) in section.iter().enumerate() {
+            if b == b'\n' && (i == 0 || section[i - 1] != b'\r') {
+                return Err(ParseError::Malformed("Bare LF in header section"));
+            }
+            // A CR at the very end may still be followed by its LF
+            if b == b'\r' && i + 1 < section.len() && section[i + 1] != b'\n' {
+                return Err(ParseError::Malformed("Bare CR in header section"));
+            }
+        }
+        Ok(())
+    }
+
     fn find_headers_end(&self) -> Option<usize> {
-        if let Some(pos) = self.buffer.windows(4).position(|w| w == b"\r\n\r\n") {
-            return Some(pos + 4);
+        self.buffer
+            .windows(4)
+            .position(|w| w == b"\r\n\r\n")
+            .map(|pos| pos + 4)
+    }
+
+    /// Split `method SP request-target SP HTTP-version`, with exactly one
+    /// space between the three parts
+    fn parse_request_line(line: &[u8]) -> Result<(HttpMethod, &str, &str), ParseError> {
+        if !line.iter().all(|&b| b.is_ascii_graphic() || b == b' ') {
+            return Err(ParseError::Malformed("Invalid character in request line"));
+        }
+        let line = std::str::from_utf8(line).map_err(|_| ParseError::Malformed("Invalid request line"))?;
+
+        let parts: Vec<&str> = line.split(' ').collect();
+        let [method, target, version] = parts[..] else {
+            return Err(ParseError::Malformed("Invalid request line"));
+        };
+        if method.is_empty() || !method.bytes().all(is_tchar) {
+            return Err(ParseError::Malformed("Invalid method"));
+        }
+        if target.is_empty() {
+            return Err(ParseError::Malformed("Empty request target"));
+        }
+
+        let digits = version
+            .strip_prefix("HTTP/")
+            .map(|v| v.as_bytes())
+            .filter(|v| v.len() == 3 && v[0].is_ascii_digit() && v[1] == b'.' && v[2].is_ascii_digit())
+            .ok_or(ParseError::Malformed("Invalid HTTP version"))?;
+        if digits[0] != b'1' {
+            return Err(ParseError::VersionNotSupported);
         }
 
-        if let Some(pos) = self.buffer.windows(2).position(|w| w == b"\n\n") {
-            return Some(pos + 2);
+        let method = HttpMethod::from(method);
+        if let HttpMethod::Other(_) = method {
+            return Err(ParseError::NotImplemented("Unknown method"));
         }
 
-        None
+        Ok((method, target, version))
+    }
+
+    /// Single value of one or more `Content-Length` fields; `5, 5` and
+    /// repeated identical fields are allowed, differing values are not
+    fn parse_content_length(values: &[String]) -> Result<Option<usize>, ParseError> {
+        let mut length = None;
+        for item in values.iter().flat_map(|v| v.split(',')) {
+            let item = item.trim_matches([' ', '\t']);
+            if item.is_empty() || !item.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(ParseError::Malformed("Invalid Content-Length"));
+            }
+            let n = item
+                .parse::<usize>()
+                .map_err(|_| ParseError::Malformed("Invalid Content-Length"))?;
+            if length.is_some_and(|l| l != n) {
+                return Err(ParseError::Malformed("Conflicting Content-Length values"));
+            }
+            length = Some(n);
+        }
+        Ok(length)
     }
 
     fn parse_path_and_query(full_path: &str) -> (String, String) {
@@ -108,50 +313,119 @@ impl HttpRequestBuilder {
         }
     }
 
-    fn parse_headers(&mut self, headers_end: usize) -> Result<(), &'static str> {
-        let headers_section = &self.buffer[..headers_end];
-        let s = String::from_utf8_lossy(headers_section);
-        let mut lines = s.lines();
+    fn parse_headers(&mut self, headers_end: usize) -> Result<(), ParseError> {
+        // Section without the final empty line, split on CRLF (bare CR/LF
+        // were already rejected)
+        let section = &self.buffer[..headers_end - 4];
+        let mut lines = section
+            .split(|&b| b == b'\n')
+            .map(|line| line.strip_suffix(b"\r").unwrap_or(line));
 
-        let request_line = lines.next().ok_or("Missing request line")?;
-        let parts: Vec<&str> = request_line.split_whitespace().collect();
-        if parts.len() != 3 {
-            return Err("Invalid request line");
-        }
+        let request_line = lines
+            .next()
+            .ok_or(ParseError::Malformed("Missing request line"))?;
+        let (method, target, version) = Self::parse_request_line(request_line)?;
 
         let mut headers = HttpHeaders::new();
+        let mut content_lengths = Vec::new();
+        let mut transfer_encodings = Vec::new();
+        let mut host_count = 0;
+
         for line in lines {
-            let line = line.trim();
-            if line.is_empty() {
-                break;
-            }
-            if let Some((key, val)) = line.split_once(":") {
-                headers.insert(key, val);
+            let (name, value) = parse_field_line(line)?;
+            match name.to_ascii_lowercase().as_str() {
+                "content-length" => content_lengths.push(value.to_string()),
+                "transfer-encoding" => transfer_encodings.push(value.to_string()),
+                "host" => host_count += 1,
+                _ => {}
             }
+            headers.append(name, &value);
         }
 
-        // Add keep-alive by default if not specified
-        if !headers.get("connection").is_some() {
-            headers.insert("connection", "keep-alive");
+        // 100-continue is the only expectation defined; HTTP/1.0 clients
+        // cannot know about it, so theirs is ignored (RFC 9110 §10.1.1)
+        if version != "HTTP/1.0"
+            && headers
+                .get("expect")
+                .is_some_and(|e| !e.eq_ignore_ascii_case("100-continue"))
+        {
+            return Err(ParseError::ExpectationFailed);
         }
 
+        if version == "HTTP/1.1" && host_count != 1 {
+            return Err(ParseError::Malformed("HTTP/1.1 requires exactly one Host header"));
+        }
+
+        // Message framing (RFC 9112 §6): ambiguous combinations are refused
+        // rather than guessed, as that is what request smuggling relies on
+        let content_length = Self::parse_content_length(&content_lengths)?;
+        if let Some(length) = content_length {
+            headers.insert("content-length", &length.to_string());
+        }
+        if !transfer_encodings.is_empty() {
+            if version == "HTTP/1.0" {
+                return Err(ParseError::Malformed("Transfer-Encoding in an HTTP/1.0 request"));
+            }
+            if content_length.is_some() {
+                return Err(ParseError::Malformed("Both Content-Length and Transfer-Encoding"));
+            }
+            let codings: Vec<String> = transfer_encodings
+                .iter()
+                .flat_map(|v| v.split(','))
+                .map(|c| c.trim().to_ascii_lowercase())
+                .collect();
+            if codings.last().map(|c| c.as_str()) != Some("chunked") {
+                return Err(ParseError::Malformed("Transfer-Encoding must end with chunked"));
+            }
+            if codings.len() > 1 {
+                return Err(ParseError::NotImplemented("Unsupported transfer coding"));
+            }
+            headers.insert("transfer-encoding", "chunked");
+        }
+
+        // Request target: origin-form, absolute-form (whose authority
+        // replaces Host) or `*` for OPTIONS
+        let full_path = if target.starts_with('/') {
+            target.to_string()
+        } else if let Some(rest) = target
+            .strip_prefix("http://")
+            .or_else(|| target.strip_prefix("https://"))
+        {
+            let split = rest.find(['/', '?']).unwrap_or(rest.len());
+            let (authority, path) = rest.split_at(split);
+            if authority.is_empty() {
+                return Err(ParseError::Malformed("Missing authority in absolute-form target"));
+            }
+            headers.insert("host", authority);
+            if path.starts_with('/') {
+                path.to_string()
+            } else {
+                format!("/{}", path)
+            }
+        } else if target == "*" && method.to_str() == "OPTIONS" {
+            target.to_string()
+        } else {
+            return Err(ParseError::Malformed("Invalid request target"));
+        };
+
         // Extract session ID from Cookie header
-        let cookie_header = headers.get("cookie").map(|s| s.as_str());
-        let session_id = extract_session_id(cookie_header);
+        let session_id = extract_session_id(headers.get("cookie").as_deref());
 
         let body_type = self.determine_body_type(&headers);
 
         // Parse path and query string
-        let (path, query_string) = Self::parse_path_and_query(parts[1]);
+        let (path, query_string) = Self::parse_path_and_query(&full_path);
 
         self.request = Some(HttpRequest {
-            method: HttpMethod::from_str(parts[0]),
+            method,
             path,
             query_string,
-            version: parts[2].to_string(),
+            uri: full_path,
+            version: version.to_string(),
             headers,
             body: None,
             session_id,
+            trailers: HttpHeaders::new(),
         });
 
         self.state = ParserState::ParsingBody {
@@ -165,26 +439,24 @@ impl HttpRequestBuilder {
     }
 
     fn determine_body_type(&self, headers: &HttpHeaders) -> BodyType {
-        if let Some(transfer_encoding) = headers.get("transfer-encoding") {
-            if transfer_encoding.to_lowercase().contains("chunked") {
-                return BodyType::Chunked {
-                    bytes_read: 0,
-                    current_chunk_size: None,
-                    current_chunk_read: 0,
-                };
-            }
+        if headers.get("transfer-encoding").is_some() {
+            return BodyType::Chunked {
+                bytes_read: 0,
+                current_chunk_size: None,
+                current_chunk_read: 0,
+            };
         }
 
-        if let Some(content_length) = headers.get("content-length") {
-            if let Ok(length) = content_length.trim().parse::<usize>() {
-                return BodyType::ContentLength(length);
-            }
+        if let Some(content_length) = headers.get("content-length")
+            && let Ok(length) = content_length.trim().parse::<usize>()
+        {
+            return BodyType::ContentLength(length);
         }
 
         BodyType::None
     }
 
-    fn parse_body(&mut self) -> Result<(), &'static str> {
+    fn parse_body(&mut self) -> Result<(), ParseError> {
         let (headers_end, body_type) = match &self.state {
             ParserState::ParsingBody {
                 headers_end,
@@ -195,6 +467,7 @@ impl HttpRequestBuilder {
 
         match body_type {
             BodyType::None => {
+                self.request_end = headers_end;
                 self.state = ParserState::Complete;
                 Ok(())
             }
@@ -207,6 +480,7 @@ impl HttpRequestBuilder {
                     if let Some(ref mut req) = self.request {
                         req.body = Some(body);
                     }
+                    self.request_end = body_start + expected_length;
                     self.state = ParserState::Complete;
                 }
                 Ok(())
@@ -215,7 +489,7 @@ impl HttpRequestBuilder {
         }
     }
 
-    fn parse_chunked_body(&mut self, headers_end: usize) -> Result<(), &'static str> {
+    fn parse_chunked_body(&mut self, headers_end: usize) -> Result<(), ParseError> {
         let mut body_data = Vec::new();
         let mut pos = headers_end;
 
@@ -228,35 +502,39 @@ impl HttpRequestBuilder {
 
             let chunk_header_end = match chunk_header_end {
                 Some(end) => end,
+                None if self.buffer.len() - pos > MAX_CHUNK_LINE => {
+                    return Err(ParseError::Malformed("Chunk size line too long"));
+                }
                 None => return Ok(()), // Need more data for chunk size
             };
 
-            // Parse chunk size
-            let chunk_size_str = String::from_utf8_lossy(&self.buffer[pos..chunk_header_end]);
-            let chunk_size_str = chunk_size_str.split(';').next().unwrap_or("").trim();
-            let chunk_size =
-                usize::from_str_radix(chunk_size_str, 16).map_err(|_| "Invalid chunk size")?;
+            let chunk_size = parse_chunk_line(&self.buffer[pos..chunk_header_end])?;
 
             // Move past chunk size line
             pos = chunk_header_end + 2;
 
             if chunk_size == 0 {
-                // Last chunk - check for trailing \r\n
-                if self.buffer.len() >= pos + 2 {
-                    if let Some(ref mut req) = self.request {
-                        req.body = Some(body_data);
-                    }
-                    self.state = ParserState::Complete;
-                    return Ok(());
-                } else {
-                    return Ok(()); // Need more data for final \r\n
+                // Last chunk, then the trailer section up to an empty line
+                let Some(trailers) = self.parse_trailers(pos)? else {
+                    return Ok(()); // Need more data for the trailer section
+                };
+                let (trailers, end) = trailers;
+                if let Some(ref mut req) = self.request {
+                    req.body = Some(body_data);
+                    req.trailers = trailers;
                 }
+                self.request_end = end;
+                self.state = ParserState::Complete;
+                return Ok(());
             }
 
             // Check if we have the full chunk + trailing \r\n
             if self.buffer.len() < pos + chunk_size + 2 {
                 return Ok(()); // Need more data
             }
+            if &self.buffer[pos + chunk_size..pos + chunk_size + 2] != b"\r\n" {
+                return Err(ParseError::Malformed("Chunk data not followed by CRLF"));
+            }
 
             // Read chunk data
             body_data.extend_from_slice(&self.buffer[pos..pos + chunk_size]);
@@ -264,6 +542,47 @@ impl HttpRequestBuilder {
         }
     }
 
+    /// Trailer section starting at `start`, with the offset just past it, or
+    /// `None` while it is incomplete. Fields that affect framing, routing or
+    /// authentication are only honoured in the header section and dropped.
+    fn parse_trailers(&self, start: usize) -> Result<Option<(HttpHeaders, usize)>, ParseError> {
+        let rest = &self.buffer[start..];
+        let end = if rest.starts_with(b"\r\n") {
+            Some(2)
+        } else {
+            rest.windows(4).position(|w| w == b"\r\n\r\n").map(|p| p + 4)
+        };
+
+        let scanned = end.unwrap_or(rest.len());
+        if scanned > self.limits.max_header_bytes {
+            return Err(ParseError::HeaderTooLarge);
+        }
+        let Some(end) = end else {
+            return Ok(None);
+        };
+
+        let mut trailers = HttpHeaders::new();
+        let section = &rest[..end - 2];
+        for line in section.split(|&b| b == b'\n').filter(|l| !l.is_empty()) {
+            let line = line
+                .strip_suffix(b"\r")
+                .ok_or(ParseError::Malformed("Bare LF in trailer section"))?;
+            if line.contains(&b'\r') {
+                return Err(ParseError::Malformed("Bare CR in trailer section"));
+            }
+            let (name, value) = parse_field_line(line)?;
+            if FORBIDDEN_TRAILERS.iter().any(|f| f.eq_ignore_ascii_case(name)) {
+                continue;
+            }
+            trailers.append(name, &value);
+        }
+        if trailers.len() > self.limits.max_header_count {
+            return Err(ParseError::TooManyHeaders);
+        }
+
+        Ok(Some((trailers, start + end)))
+    }
+
     pub fn done(&self) -> bool {
         matches!(self.state, ParserState::Complete)
     }
@@ -276,6 +595,11 @@ impl HttpRequestBuilder {
         self.request.as_ref()
     }
 
+    /// The request as soon as its headers are parsed, for the rewrite phase
+    pub fn get_mut_before_done(&mut self) -> Option<&mut HttpRequest> {
+        self.request.as_mut()
+    }
+
     pub fn get(&self) -> Option<&HttpRequest> {
         if self.done() {
             self.request.as_ref()
@@ -283,15 +607,175 @@ impl HttpRequestBuilder {
             None
         }
     }
+
+    /// The complete request, for the rewrite phase to change its target
+    pub fn get_mut(&mut self) -> Option<&mut HttpRequest> {
+        if self.done() {
+            self.request.as_mut()
+        } else {
+            None
+        }
+    }
+}
+
+/// `tchar` from RFC 9110 §5.6.2, the characters of methods and field names
+fn is_tchar(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+/// Longest chunk size line accepted, extensions included
+const MAX_CHUNK_LINE: usize = 4096;
+
+/// Fields a sender may not put in a trailer (RFC 9110 §6.5.1)
+const FORBIDDEN_TRAILERS: &[&str] = &[
+    "authorization",
+    "cache-control",
+    "content-encoding",
+    "content-length",
+    "content-range",
+    "content-type",
+    "cookie",
+    "expect",
+    "host",
+    "max-forwards",
+    "proxy-authorization",
+    "range",
+    "te",
+    "trailer",
+    "transfer-encoding",
+];
+
+/// `field-name ":" OWS field-value OWS`, shared by headers and trailers
+fn parse_field_line(line: &[u8]) -> Result<(&str, String), ParseError> {
+    if line.starts_with(b" ") || line.starts_with(b"\t") {
+        return Err(ParseError::Malformed("Obsolete line folding"));
+    }
+    let colon = line
+        .iter()
+        .position(|&b| b == b':')
+        .ok_or(ParseError::Malformed("Header line without colon"))?;
+
+    // No whitespace is allowed between the field name and the colon
+    let name = &line[..colon];
+    if name.is_empty() || !name.iter().copied().all(is_tchar) {
+        return Err(ParseError::Malformed("Invalid header name"));
+    }
+    let value = line[colon + 1..].trim_ascii();
+    if value.iter().any(|&b| (b < 0x20 && b != b'\t') || b == 0x7f) {
+        return Err(ParseError::Malformed("Invalid character in header value"));
+    }
+
+    // Field names are ASCII; values may carry obs-text, kept lossily
+    let name = std::str::from_utf8(name).unwrap_or_default();
+    Ok((name, String::from_utf8_lossy(value).into_owned()))
+}
+
+/// Size of a `chunk-size [ chunk-ext ]` line. Extensions are checked for
+/// syntax (RFC 9112 §7.1.1) and otherwise ignored, as none are understood.
+fn parse_chunk_line(line: &[u8]) -> Result<usize, ParseError> {
+    let size_len = line.iter().take_while(|b| b.is_ascii_hexdigit()).count();
+    if size_len == 0 || size_len > 16 {
+        return Err(ParseError::Malformed("Invalid chunk size"));
+    }
+    let size = std::str::from_utf8(&line[..size_len])
+        .ok()
+        .and_then(|s| usize::from_str_radix(s, 16).ok())
+        .ok_or(ParseError::Malformed("Invalid chunk size"))?;
+
+    let is_ws = |b: &u8| *b == b' ' || *b == b'\t';
+    let skip_ws = |i: &mut usize| {
+        while line.get(*i).is_some_and(is_ws) {
+            *i += 1;
+        }
+    };
+    let token_end = |i: usize| i + line[i..].iter().take_while(|&&b| is_tchar(b)).count();
+
+    let mut i = size_len;
+    loop {
+        skip_ws(&mut i);
+        if i == line.len() {
+            return Ok(size);
+        }
+        if line[i] != b';' {
+            return Err(ParseError::Malformed("Invalid chunk extension"));
+        }
+        i += 1;
+        skip_ws(&mut i);
+
+        let name_end = token_end(i);
+        if name_end == i {
+            return Err(ParseError::Malformed("Invalid chunk extension"));
+        }
+        i = name_end;
+        skip_ws(&mut i);
+        if line.get(i) != Some(&b'=') {
+            continue;
+        }
+        i += 1;
+        skip_ws(&mut i);
+
+        if line.get(i) == Some(&b'"') {
+            // quoted-string, with backslash escapes
+            i += 1;
+            loop {
+                match line.get(i) {
+                    Some(b'"') => break,
+                    Some(b'\\') if i + 1 < line.len() => i += 2,
+                    Some(&b) if b == b'\t' || (b >= 0x20 && b != 0x7f) => i += 1,
+                    _ => return Err(ParseError::Malformed("Invalid chunk extension")),
+                }
+            }
+            i += 1;
+        } else {
+            let value_end = token_end(i);
+            if value_end == i {
+                return Err(ParseError::Malformed("Invalid chunk extension"));
+            }
+            i = value_end;
+        }
+    }
 }
 
 impl HttpRequest {
+    /// Whether the client waits for `100 Continue` before sending its body
+    pub fn expects_continue(&self) -> bool {
+        self.version != "HTTP/1.0"
+            && self
+                .headers
+                .get("expect")
+                .is_some_and(|e| e.eq_ignore_ascii_case("100-continue"))
+    }
+
+    /// Body length announced by `Content-Length`, if any
+    pub fn content_length(&self) -> Option<usize> {
+        self.headers.get("content-length")?.parse().ok()
+    }
+
     pub fn parse_query(&self) -> Vec<(String, String)> {
-        if self.query_string.is_empty() {
+        Self::parse_urlencoded(&self.query_string)
+    }
+
+    /// Fields of an `application/x-www-form-urlencoded` body
+    pub fn parse_form(&self) -> Vec<(String, String)> {
+        match &self.body {
+            Some(body) => Self::parse_urlencoded(&String::from_utf8_lossy(body)),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn form_param(&self, key: &str) -> Option<String> {
+        self.parse_form()
+            .into_iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v)
+    }
+
+    fn parse_urlencoded(input: &str) -> Vec<(String, String)> {
+        if input.is_empty() {
             return Vec::new();
         }
 
-        self.query_string
+        input
             .split('&')
             .filter_map(|pair| {
                 let mut parts = pair.splitn(2, '=');
@@ -320,11 +804,11 @@ impl HttpRequest {
         while let Some(ch) = chars.next() {
             if ch == '%' {
                 let hex: String = chars.by_ref().take(2).collect();
-                if hex.len() == 2 {
-                    if let Ok(byte) = u8::from_str_radix(&hex, 16) {
-                        result.push(byte as char);
-                        continue;
-                    }
+                if hex.len() == 2
+                    && let Ok(byte) = u8::from_str_radix(&hex, 16)
+                {
+                    result.push(byte as char);
+                    continue;
                 }
                 result.push('%');
                 result.push_str(&hex);
@@ -342,3 +826,160 @@ impl HttpRequest {
         self.session_id.as_ref()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feed `raw` in one piece; the error the parser stopped on, if any
+    fn feed(builder: &mut HttpRequestBuilder, raw: &[u8]) -> Option<ParseError> {
+        builder.append(raw.to_vec()).err()
+    }
+
+    fn limited(max_header_bytes: usize, max_header_count: usize, max_request_line: usize) -> HttpRequestBuilder {
+        HttpRequestBuilder::with_limits(RequestLimits {
+            max_header_bytes,
+            max_header_count,
+            max_request_line,
+        })
+    }
+
+    #[test]
+    fn request_within_limits_is_parsed() {
+        let mut builder = limited(1024, 4, 128);
+        assert_eq!(feed(&mut builder, b"GET /a?b=c HTTP/1.1\r\nHost: x\r\n\r\n"), None);
+        let request = builder.get().expect("complete request");
+        assert_eq!(request.path, "/a");
+        assert_eq!(request.query_string, "b=c");
+    }
+
+    #[test]
+    fn oversized_header_section_is_refused_before_it_ends() {
+        let mut builder = limited(64, 100, 1024);
+        let error = feed(&mut builder, format!("GET / HTTP/1.1\r\nX: {}\r\n", "a".repeat(80)).as_bytes());
+        assert_eq!(error, Some(ParseError::HeaderTooLarge));
+        assert_eq!(ParseError::HeaderTooLarge.status().0, 431);
+    }
+
+    #[test]
+    fn header_count_is_limited() {
+        let mut builder = limited(1024, 2, 1024);
+        let error = feed(&mut builder, b"GET / HTTP/1.1\r\nHost: x\r\nA: 1\r\nB: 2\r\n\r\n");
+        assert_eq!(error, Some(ParseError::TooManyHeaders));
+
+        let mut builder = limited(1024, 2, 1024);
+        assert_eq!(feed(&mut builder, b"GET / HTTP/1.1\r\nHost: x\r\nA: 1\r\n\r\n"), None);
+    }
+
+    #[test]
+    fn long_request_line_is_uri_too_long() {
+        let mut builder = limited(4096, 100, 32);
+        let error = feed(&mut builder, format!("GET /{} HTTP/1.1\r\n", "a".repeat(40)).as_bytes());
+        assert_eq!(error, Some(ParseError::UriTooLong));
+        assert_eq!(ParseError::UriTooLong.status().0, 414);
+    }
+
+    #[test]
+    fn limits_apply_across_appends() {
+        let mut builder = limited(64, 100, 1024);
+        assert_eq!(feed(&mut builder, b"GET / HTTP/1.1\r\nX: "), None);
+        assert!(!builder.header_done());
+        assert_eq!(feed(&mut builder, "a".repeat(64).as_bytes()), Some(ParseError::HeaderTooLarge));
+    }
+    /// Status the parser answers `raw` with
+    fn status_of(raw: &str) -> Option<u16> {
+        feed(&mut HttpRequestBuilder::new(), raw.as_bytes()).map(|e| e.status().0)
+    }
+
+    #[test]
+    fn malformed_requests_map_to_their_status() {
+        assert_eq!(status_of("GET / HTTP/1.1\nHost: x\r\n\r\n"), Some(400));
+        assert_eq!(status_of("GET / HTTP/1.1\r\nHost: x\rY: 1\r\n\r\n"), Some(400));
+        assert_eq!(status_of("GET  / HTTP/1.1\r\nHost: x\r\n\r\n"), Some(400));
+        assert_eq!(status_of("GET / HTTP/1.1\r\n\r\n"), Some(400));
+        assert_eq!(status_of("GET / HTTP/1.1\r\nHost: x\r\nHost: y\r\n\r\n"), Some(400));
+        assert_eq!(status_of("GET / HTTP/1.1\r\nHost: x\r\n folded\r\n\r\n"), Some(400));
+        assert_eq!(status_of("GET / HTTP/1.1\r\nHost : x\r\n\r\n"), Some(400));
+        assert_eq!(status_of("GET / HTTP/2.0\r\nHost: x\r\n\r\n"), Some(505));
+        assert_eq!(status_of("BREW / HTTP/1.1\r\nHost: x\r\n\r\n"), Some(501));
+        assert_eq!(status_of("GET / HTTP/1.1\r\nHost: x\r\nExpect: tea\r\n\r\n"), Some(417));
+    }
+
+    #[test]
+    fn ambiguous_framing_is_refused() {
+        let both = "POST / HTTP/1.1\r\nHost: x\r\nContent-Length: 1\r\nTransfer-Encoding: chunked\r\n\r\n";
+        assert_eq!(status_of(both), Some(400));
+        let conflicting = "POST / HTTP/1.1\r\nHost: x\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\n";
+        assert_eq!(status_of(conflicting), Some(400));
+        let not_last = "POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked, gzip\r\n\r\n";
+        assert_eq!(status_of(not_last), Some(400));
+        let unsupported = "POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: gzip, chunked\r\n\r\n";
+        assert_eq!(status_of(unsupported), Some(501));
+        let http10 = "POST / HTTP/1.0\r\nTransfer-Encoding: chunked\r\n\r\n";
+        assert_eq!(status_of(http10), Some(400));
+
+        // Repeated identical lengths are one length
+        let mut builder = HttpRequestBuilder::new();
+        let same = b"POST / HTTP/1.1\r\nHost: x\r\nContent-Length: 2, 2\r\n\r\nok";
+        assert_eq!(feed(&mut builder, same), None);
+        assert_eq!(builder.get().unwrap().body.as_deref(), Some(&b"ok"[..]));
+    }
+
+    #[test]
+    fn absolute_form_target_replaces_host() {
+        let mut builder = HttpRequestBuilder::new();
+        let raw = b"GET http://example.com:8080/p?q=1 HTTP/1.1\r\nHost: other\r\n\r\n";
+        assert_eq!(feed(&mut builder, raw), None);
+        let request = builder.get().unwrap();
+        assert_eq!(request.headers.get("host").as_deref(), Some("example.com:8080"));
+        assert_eq!((request.path.as_str(), request.query_string.as_str()), ("/p", "q=1"));
+        assert_eq!(request.uri, "/p?q=1");
+    }
+
+    #[test]
+    fn leading_empty_lines_are_ignored() {
+        let mut builder = HttpRequestBuilder::new();
+        assert_eq!(feed(&mut builder, b"\r\n\r\nGET / HTTP/1.0\r\n\r\n"), None);
+        assert!(builder.done());
+    }
+
+    #[test]
+    fn chunked_body_is_decoded_across_appends() {
+        let mut builder = HttpRequestBuilder::new();
+        let head = b"POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n";
+        assert_eq!(feed(&mut builder, head), None);
+        assert_eq!(feed(&mut builder, b"5;name=\"quoted value\"\r\nhel"), None);
+        assert!(!builder.done());
+        assert_eq!(feed(&mut builder, b"lo\r\n6 ; ext\r\n world\r\n0\r\n"), None);
+        assert!(!builder.done());
+        assert_eq!(feed(&mut builder, b"X-Checksum: 42\r\nContent-Length: 1\r\n\r\n"), None);
+
+        let request = builder.get().expect("complete request");
+        assert_eq!(request.body.as_deref(), Some(&b"hello world"[..]));
+        assert_eq!(request.trailers.get("x-checksum").as_deref(), Some("42"));
+        // Framing fields are only honoured in the header section
+        assert!(!request.trailers.contains("content-length"));
+    }
+
+    #[test]
+    fn invalid_chunks_are_malformed() {
+        let head = "POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n";
+        for body in ["z\r\n", "5;\r\nhello\r\n", "5 x\r\nhello\r\n", "5\r\nhelloXX0\r\n\r\n"] {
+            assert_eq!(status_of(&format!("{}{}", head, body)), Some(400), "{:?}", body);
+        }
+        assert_eq!(status_of(&format!("{}5\r\nhello\r\n0\r\nA: 1\nB: 2\r\n\r\n", head)), Some(400));
+    }
+
+    #[test]
+    fn pipelined_bytes_carry_over_to_the_next_request() {
+        let mut builder = HttpRequestBuilder::new();
+        let raw = b"POST / HTTP/1.1\r\nHost: x\r\nContent-Length: 2\r\n\r\nokGET /next HTTP/1.1\r\n";
+        assert_eq!(feed(&mut builder, raw), None);
+        assert_eq!(builder.get().unwrap().body.as_deref(), Some(&b"ok"[..]));
+
+        let mut next = builder.next_request();
+        assert!(next.take_carried_over());
+        assert_eq!(feed(&mut next, b"Host: x\r\n\r\n"), None);
+        assert_eq!(next.get().unwrap().path, "/next");
+    }
+}
@@ -34,9 +34,11 @@ impl HttpResponseBuilder {
         self.headers = headers;
         self
     }
-    // Add a cookie
-    pub fn cookie(mut self, cookie: &Cookie) -> Self {
-        self.cookies.push(cookie.clone());
+    // Add a cookie (`None` when the route does not use sessions)
+    pub fn cookie<'a>(mut self, cookie: impl Into<Option<&'a Cookie>>) -> Self {
+        if let Some(cookie) = cookie.into() {
+            self.cookies.push(cookie.clone());
+        }
         self
     }
 
@@ -46,13 +48,42 @@ impl HttpResponseBuilder {
     }
 
     pub fn build(mut self) -> Vec<u8> {
-        // Auto-add Content-Length if not present
-        self.headers
-            .insert("Content-Length", &self.body.len().to_string());
-        // Inject all cookies as headers
+        // Auto-add Content-Length, except on 1xx, 204 and 304 responses which
+        // never carry a body (RFC 9110 §8.6)
+        if matches!(self.status_code, 100..=199 | 204 | 304) {
+            self.headers.remove("Content-Length");
+            self.body.clear();
+        } else {
+            self.headers
+                .insert("Content-Length", &self.body.len().to_string());
+        }
+
+        let body = std::mem::take(&mut self.body);
+        let mut bytes = self.head();
+        bytes.extend_from_slice(&body);
+        bytes
+    }
+
+    /// Status line and headers of a response whose `len` bytes of body are
+    /// sent afterwards with `StreamResponse`
+    pub fn build_sized(mut self, len: u64) -> Vec<u8> {
+        self.headers.insert("Content-Length", &len.to_string());
+        self.head()
+    }
+
+    /// Status line and headers of a response whose body is sent afterwards
+    /// with `ChunkedResponse`; a body set on the builder is not included
+    pub fn build_chunked(mut self) -> Vec<u8> {
+        self.headers.remove("Content-Length");
+        self.headers.insert("Transfer-Encoding", "chunked");
+        self.head()
+    }
+
+    fn head(mut self) -> Vec<u8> {
+        // Inject all cookies as headers, one Set-Cookie line each
         for cookie in self.cookies.iter() {
             let (key, value) = cookie.to_header_pair();
-            self.headers.insert(&key, &value);
+            self.headers.append(&key, &value);
         }
 
         let mut response = format!("HTTP/1.1 {} {}\r\n", self.status_code, self.status_text);
@@ -62,10 +93,7 @@ impl HttpResponseBuilder {
         }
 
         response.push_str("\r\n");
-
-        let mut bytes = response.into_bytes();
-        bytes.extend_from_slice(&self.body);
-        bytes
+        response.into_bytes()
     }
 
     // === Convenience methods ===
@@ -77,8 +105,12 @@ impl HttpResponseBuilder {
         Self::new(201, "Created")
     }
 
-    pub fn redirect(location: &String) -> Self {
-        Self::new(302, "Found").header("Location", location)
+    pub fn redirect(status_code: u16, location: &str) -> Self {
+        Self::new(status_code, reason_phrase(status_code)).header("Location", location)
+    }
+
+    pub fn see_other(location: &str) -> Self {
+        Self::new(303, "See Other").header("Location", location)
     }
 
     pub fn not_found() -> Self {
@@ -93,6 +125,10 @@ impl HttpResponseBuilder {
         Self::new(204, "No Content")
     }
 
+    pub fn conflict() -> Self {
+        Self::new(409, "Conflict")
+    }
+
     pub fn internal_error() -> Self {
         Self::new(500, "Internal Server Error")
     }
@@ -104,44 +140,16 @@ impl HttpResponseBuilder {
         Self::new(415, "Unsupported Media Type")
     }
 
-    // === File serving methods ===
-    /// Serve a directory listing as HTML
-    pub fn serve_directory_listing(
-        server_root: &str,
-        route_root: &str,
-        route_path: &str,
-        cookie: &Cookie,
-    ) -> Vec<u8> {
-        let mut listing = String::from("<html><body><h1>Directory Listing</h1><ul>");
-
-        let dir_path = format!("{}/{}", server_root, route_root);
-        if let Ok(entries) = fs::read_dir(dir_path) {
-            for entry in entries {
-                if let Ok(entry) = entry {
-                    let file_name = entry.file_name();
-                    let file_name_str = file_name.to_string_lossy();
-                    listing.push_str(&format!(
-                        "<li><a href=\"{}\\{}\">{}</a></li>",
-                        route_path, file_name_str, file_name_str
-                    ));
-                }
-            }
-        }
-
-        listing.push_str("</ul></body></html>");
-
-        let (_key, _value) = cookie.to_header_pair();
-
-        Self::ok()
-            .body(listing.into_bytes().to_vec())
+    /// Serve a custom error page or fall back to minimal response
+    pub fn serve_error_page(error_page_path: &str, status_code: u16, status_text: &str , cookie: Option<&Cookie>) -> Vec<u8> {
+        Self::error_page(error_page_path, status_code, status_text)
             .cookie(cookie)
             .build()
     }
 
-
-
-    /// Serve a custom error page or fall back to minimal response
-    pub fn serve_error_page(error_page_path: &str, status_code: u16, status_text: &str , cookie :&Cookie   ) -> Vec<u8> {
+    /// Builder for a custom error page, for responses that still need
+    /// headers added before being built
+    pub fn error_page(error_page_path: &str, status_code: u16, status_text: &str) -> Self {
         match fs::read(error_page_path) {
             Ok(content) => {
                 println!(
@@ -151,15 +159,13 @@ impl HttpResponseBuilder {
                 Self::new(status_code, status_text)
                     .header("Content-Type", "text/html")
                     .body(content)
-                    .cookie(cookie)
-                    .build()
             }
             Err(_) => {
                 println!(
                     "Error page '{}' not found, sending minimal {} response",
                     error_page_path, status_code
                 );
-                Self::new(status_code, status_text).cookie(cookie).build()
+                Self::new(status_code, status_text)
             }
         }
     }
@@ -187,15 +193,35 @@ pub fn detect_content_type(path: &str) -> &'static str {
     }
 }
 
+/// Reason phrase for the status codes a configuration can name
+pub fn reason_phrase(status_code: u16) -> &'static str {
+    match status_code {
+        301 => "Moved Permanently",
+        302 => "Found",
+        303 => "See Other",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        410 => "Gone",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        _ => "Error",
+    }
+}
+
 // === Handler functions for different HTTP methods ===
 
 pub fn handle_method_not_allowed(
-    allowed_methods: &[String],
+    allow_header: &str,
     server: &ServerConfig,
-    cookie: &Cookie,
+    cookie: Option<&Cookie>,
 ) -> Vec<u8> {
-    let allow_header = allowed_methods.join(", ");
-
     // Get the path of the 405 error page, fallback to default
     let path = server
         .error_pages
@@ -207,13 +233,13 @@ pub fn handle_method_not_allowed(
     // Read the file content
     match fs::read(path) {
         Ok(content) => HttpResponseBuilder::method_not_allowed()
-            .header("Allow", &allow_header)
+            .header("Allow", allow_header)
             .header("Content-Type", "text/html")
             .body(content)
             .cookie(cookie)
             .build(),
         Err(_) => HttpResponseBuilder::method_not_allowed()
-            .header("Allow", &allow_header)
+            .header("Allow", allow_header)
             .header("Content-Type", "text/plain")
             .body(b"Method Not Allowed".to_vec())
             .cookie(cookie)
@@ -303,7 +329,7 @@ fn extract_filename_from_disposition(part: &str) -> Option<String> {
     if let Some(start) = disposition_line.find("filename=") {
         let start = start + 9; // length of 'filename='
         let end = disposition_line[start..]
-            .find(|c: char| c == ';' || c == '\r' || c == '\n')
+            .find([';', '\r', '\n'])
             .unwrap_or(disposition_line[start..].len());
         return Some(disposition_line[start..start + end].trim().to_string());
     }
@@ -318,7 +344,7 @@ pub(crate) fn extract_boundary(content_type: &str) -> Option<String> {
         .map(|s| s.trim().trim_start_matches("boundary=").to_string())
 }
 
-pub(crate) fn write_file(path: &str, data: &[u8], cookie: &Cookie        ) -> Vec<u8> {
+pub(crate) fn write_file(path: &str, data: &[u8], cookie: Option<&Cookie>) -> Vec<u8> {
     if let Ok(s) = std::str::from_utf8(data) {
         println!("body as string: {}", s);
     } else {
@@ -0,0 +1,591 @@
+//! Internal URL rewriting, per server and per route (`rewrite:` lists).
+//! Rules run in order on the request path; a matching rule replaces it
+//! (`$1`, `${name}` from the pattern's captures) and its flag decides what
+//! happens next:
+//!
+//! - none: go on with the following rules; a route whose rules changed the
+//!   path is searched again at the end
+//! - `last`: stop here and search the route again for the new path
+//! - `break`: stop here and serve the new path from the current route
+//! - `redirect` / `permanent`: answer 302 / 301 with the new location, as
+//!   does any replacement that is an absolute `http(s)://` URL
+//!
+//! A `?` in the replacement sets the query string, the original one being
+//! appended unless the replacement ends with `?`. Server rules run before
+//! any route is chosen, so `last` and `break` just end them; server
+//! `return` rules run before those.
+//!
+//! Replacements, `return` targets and route `redirect`s may use variables:
+//! `$request_uri` (target as received), `$uri` (current path),
+//! `$path_suffix` (path after the route's own), `$query` or `$args`,
+//! `$is_args` (`?` when there is a query), `$host` and `$scheme`.
+
+use std::borrow::Cow;
+use std::path::Path;
+
+use regex::Regex;
+
+use crate::config::{Route, ServerConfig};
+use crate::error::get_error_page_path;
+use crate::read::{extract_hostname, resolve_file_path};
+use crate::request::HttpRequest;
+use crate::response::{HttpResponseBuilder, reason_phrase};
+use crate::router::find_matching_route;
+
+/// Route searches a request may go through before it is given up on
+const MAX_REWRITE_CYCLES: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RewriteFlag {
+    None,
+    Last,
+    Break,
+    Redirect,
+    Permanent,
+}
+
+impl RewriteFlag {
+    pub fn parse(value: &str) -> Result<RewriteFlag, String> {
+        match value {
+            "" => Ok(RewriteFlag::None),
+            "last" => Ok(RewriteFlag::Last),
+            "break" => Ok(RewriteFlag::Break),
+            "redirect" => Ok(RewriteFlag::Redirect),
+            "permanent" => Ok(RewriteFlag::Permanent),
+            other => Err(format!("Unknown rewrite flag: {}", other)),
+        }
+    }
+}
+
+/// Condition a rule needs besides its pattern
+#[derive(Debug, Clone)]
+pub enum RewriteCondition {
+    /// `header: "User-Agent ~ regex"` (`!~` negates), `header: "X-Legacy"`
+    /// for presence, `header: "!X-Legacy"` for absence
+    Header {
+        name: String,
+        regex: Option<Regex>,
+        negate: bool,
+    },
+    /// `query: "regex"` on the query string, `"!regex"` negated
+    Query { regex: Regex, negate: bool },
+    /// `file: exists` or `file: missing`, for the current path as served by
+    /// the route it falls in
+    File { exists: bool },
+}
+
+impl RewriteCondition {
+    pub fn header(value: &str) -> Result<RewriteCondition, String> {
+        let (name, test) = match value.find('~') {
+            Some(tilde) => (&value[..tilde], Some(&value[tilde + 1..])),
+            None => (value, None),
+        };
+        let name = name.trim();
+        let (name, negate) = match test {
+            Some(_) => match name.strip_suffix('!') {
+                Some(name) => (name.trim(), true),
+                None => (name, false),
+            },
+            None => match name.strip_prefix('!') {
+                Some(name) => (name.trim(), true),
+                None => (name, false),
+            },
+        };
+        if name.is_empty() {
+            return Err(format!("Rewrite header condition without a name: '{}'", value));
+        }
+        let regex = test
+            .map(|pattern| Regex::new(pattern.trim()))
+            .transpose()
+            .map_err(|e| format!("Invalid regex in header condition: {}", e))?;
+        Ok(RewriteCondition::Header {
+            name: name.to_string(),
+            regex,
+            negate,
+        })
+    }
+
+    pub fn query(value: &str) -> Result<RewriteCondition, String> {
+        let (pattern, negate) = match value.strip_prefix('!') {
+            Some(pattern) => (pattern, true),
+            None => (value, false),
+        };
+        let regex =
+            Regex::new(pattern).map_err(|e| format!("Invalid regex in query condition: {}", e))?;
+        Ok(RewriteCondition::Query { regex, negate })
+    }
+
+    pub fn file(value: &str) -> Result<RewriteCondition, String> {
+        match value {
+            "exists" => Ok(RewriteCondition::File { exists: true }),
+            "missing" => Ok(RewriteCondition::File { exists: false }),
+            other => Err(format!("Rewrite file condition must be 'exists' or 'missing', got '{}'", other)),
+        }
+    }
+
+    fn holds(&self, server: &ServerConfig, request: &HttpRequest) -> bool {
+        match self {
+            RewriteCondition::Header { name, regex, negate } => {
+                let matched = match (request.headers.get(name), regex) {
+                    (Some(value), Some(regex)) => regex.is_match(&value),
+                    (Some(_), None) => true,
+                    (None, _) => false,
+                };
+                matched != *negate
+            }
+            RewriteCondition::Query { regex, negate } => {
+                regex.is_match(&request.query_string) != *negate
+            }
+            RewriteCondition::File { exists } => path_exists(server, &request.path) == *exists,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RewriteRule {
+    pub pattern: Regex,
+    pub replacement: String,
+    pub flag: RewriteFlag,
+    pub conditions: Vec<RewriteCondition>,
+}
+
+impl RewriteRule {
+    /// The short form `"pattern replacement [flag]"`
+    pub fn parse(value: &str) -> Result<RewriteRule, String> {
+        let parts: Vec<&str> = value.split_whitespace().collect();
+        let (pattern, replacement, flag) = match parts.as_slice() {
+            [pattern, replacement] => (*pattern, *replacement, ""),
+            [pattern, replacement, flag] => (*pattern, *replacement, *flag),
+            _ => return Err(format!("Expected 'pattern replacement [flag]', got '{}'", value)),
+        };
+        RewriteRule::new(pattern, replacement, flag, Vec::new())
+    }
+
+    pub fn new(
+        pattern: &str,
+        replacement: &str,
+        flag: &str,
+        conditions: Vec<RewriteCondition>,
+    ) -> Result<RewriteRule, String> {
+        Ok(RewriteRule {
+            pattern: Regex::new(pattern).map_err(|e| format!("Invalid rewrite pattern: {}", e))?,
+            replacement: replacement.to_string(),
+            flag: RewriteFlag::parse(flag)?,
+            conditions,
+        })
+    }
+}
+
+/// Server-level `return`: answer with `code` right away, e.g. to send every
+/// other host name to the canonical one. For a 3xx code `target` is the
+/// location, otherwise an optional plain-text body (the error page if none).
+#[derive(Debug, Clone)]
+pub struct ReturnRule {
+    pub code: u16,
+    pub target: Option<String>,
+    /// `"example.com"` or `"!example.com"`, compared without case
+    pub host: Option<(String, bool)>,
+    pub path: Option<Regex>,
+}
+
+impl ReturnRule {
+    /// The short form `"code [target]"`
+    pub fn parse(value: &str) -> Result<ReturnRule, String> {
+        let (code, target) = match value.split_once(char::is_whitespace) {
+            Some((code, target)) => (code, Some(target.trim())),
+            None => (value, None),
+        };
+        ReturnRule::new(code, target, None, None)
+    }
+
+    pub fn new(
+        code: &str,
+        target: Option<&str>,
+        host: Option<&str>,
+        path: Option<&str>,
+    ) -> Result<ReturnRule, String> {
+        let code = code
+            .trim()
+            .parse::<u16>()
+            .ok()
+            .filter(|code| (200..600).contains(code))
+            .ok_or_else(|| format!("Invalid return code: {}", code))?;
+        if (300..400).contains(&code) && target.is_none() {
+            return Err(format!("return {} needs a target", code));
+        }
+        let host = host.map(|host| match host.strip_prefix('!') {
+            Some(host) => (host.trim().to_string(), true),
+            None => (host.trim().to_string(), false),
+        });
+        let path = path
+            .map(Regex::new)
+            .transpose()
+            .map_err(|e| format!("Invalid regex in return path: {}", e))?;
+        Ok(ReturnRule {
+            code,
+            target: target.map(|t| t.trim_matches('"').to_string()),
+            host,
+            path,
+        })
+    }
+
+    fn applies(&self, request: &HttpRequest) -> bool {
+        let host_ok = self.host.as_ref().is_none_or(|(host, negate)| {
+            extract_hostname(&request.headers)
+                .trim_end_matches('.')
+                .eq_ignore_ascii_case(host)
+                != *negate
+        });
+        host_ok && self.path.as_ref().is_none_or(|path| path.is_match(&request.path))
+    }
+
+    fn response(&self, server: &ServerConfig, request: &HttpRequest) -> Vec<u8> {
+        let target = self.target.as_ref().map(|t| expand_variables(t, request, None, &[]));
+        match target {
+            Some(location) if (300..400).contains(&self.code) => {
+                HttpResponseBuilder::redirect(self.code, &location).build()
+            }
+            Some(text) => HttpResponseBuilder::new(self.code, reason_phrase(self.code))
+                .header("Content-Type", "text/plain; charset=utf-8")
+                .body(format!("{}\n", text).into_bytes())
+                .build(),
+            None => {
+                let error_path = get_error_page_path(server, self.code);
+                HttpResponseBuilder::error_page(&error_path, self.code, reason_phrase(self.code)).build()
+            }
+        }
+    }
+}
+
+/// How a request leaves the rewrite phase when it is not simply served
+pub enum RewriteStop {
+    Redirect(u16, String),
+    /// Response of a server `return` rule
+    Return(Vec<u8>),
+    /// Rules keep sending the request from route to route
+    TooManyCycles,
+}
+
+enum RulesOutcome {
+    /// Path unchanged or rewritten, the route to be searched again or not
+    Continue { redispatch: bool },
+    Redirect(u16, String),
+}
+
+/// Apply the server's rules, then those of each route the request lands in,
+/// and return the route that serves it. The request path and query string
+/// are rewritten in place.
+pub fn rewrite_request<'a>(
+    server: &'a ServerConfig,
+    request: &mut HttpRequest,
+) -> Result<Option<Cow<'a, Route>>, RewriteStop> {
+    if let Some(rule) = server.returns.iter().find(|rule| rule.applies(request)) {
+        println!("Return: {} for {}", rule.code, request.path);
+        return Err(RewriteStop::Return(rule.response(server, request)));
+    }
+    if let RulesOutcome::Redirect(code, location) = apply_rules(&server.rewrite, server, request) {
+        return Err(RewriteStop::Redirect(code, location));
+    }
+
+    for _ in 0..MAX_REWRITE_CYCLES {
+        let Some(route) = find_matching_route(server, &request.path) else {
+            return Ok(None);
+        };
+        match apply_rules(&route.rewrite, server, request) {
+            RulesOutcome::Continue { redispatch: false } => return Ok(Some(route)),
+            RulesOutcome::Continue { redispatch: true } => {
+                println!("Rewrite: searching the route again for {}", request.path);
+            }
+            RulesOutcome::Redirect(code, location) => {
+                return Err(RewriteStop::Redirect(code, location));
+            }
+        }
+    }
+    Err(RewriteStop::TooManyCycles)
+}
+
+fn apply_rules(rules: &[RewriteRule], server: &ServerConfig, request: &mut HttpRequest) -> RulesOutcome {
+    let mut changed = false;
+    for rule in rules {
+        if !rule.conditions.iter().all(|condition| condition.holds(server, request)) {
+            continue;
+        }
+        let Some(captures) = rule.pattern.captures(&request.path) else {
+            continue;
+        };
+
+        let mut values = Vec::new();
+        for (i, name) in rule.pattern.capture_names().enumerate() {
+            let value = captures.get(i).map_or("", |m| m.as_str());
+            values.push((i.to_string(), value.to_string()));
+            if let Some(name) = name {
+                values.push((name.to_string(), value.to_string()));
+            }
+        }
+        let target = expand_variables(&rule.replacement, request, None, &values);
+
+        // An explicit query replaces the original one, which is appended
+        // unless the replacement ends with '?'
+        let (path, query) = match target.split_once('?') {
+            Some((path, "")) => (path.to_string(), String::new()),
+            Some((path, query)) if !request.query_string.is_empty() => {
+                (path.to_string(), format!("{}&{}", query, request.query_string))
+            }
+            Some((path, query)) => (path.to_string(), query.to_string()),
+            None => (target.clone(), request.query_string.clone()),
+        };
+
+        let absolute = path.starts_with("http://") || path.starts_with("https://");
+        if absolute || matches!(rule.flag, RewriteFlag::Redirect | RewriteFlag::Permanent) {
+            let code = if rule.flag == RewriteFlag::Permanent { 301 } else { 302 };
+            let location = if query.is_empty() {
+                path
+            } else {
+                format!("{}?{}", path, query)
+            };
+            println!("Rewrite: {} redirected to {}", request.path, location);
+            return RulesOutcome::Redirect(code, location);
+        }
+
+        println!("Rewrite: {} -> {}", request.path, path);
+        request.path = path;
+        request.query_string = query;
+        changed = true;
+
+        match rule.flag {
+            RewriteFlag::Last => return RulesOutcome::Continue { redispatch: true },
+            RewriteFlag::Break => return RulesOutcome::Continue { redispatch: false },
+            _ => {}
+        }
+    }
+    RulesOutcome::Continue { redispatch: changed }
+}
+
+/// Fill the request variables listed in the module documentation, `$name`
+/// or `${name}`, and the pattern's `captures`, `$1` or `${name}` (a capture
+/// wins over a variable of the same name); `route` gives `$path_suffix` (the
+/// whole path without one). Everything is filled in one pass, so inserted
+/// values are never expanded again. Unknown names are left as written.
+pub fn expand_variables(
+    template: &str,
+    request: &HttpRequest,
+    route: Option<&Route>,
+    captures: &[(String, String)],
+) -> String {
+    let value = |name: &str| -> Option<String> {
+        Some(match name {
+            "request_uri" => request.uri.clone(),
+            "uri" => request.path.clone(),
+            "path_suffix" => match route {
+                Some(route) => request
+                    .path
+                    .strip_prefix(route.path.trim_end_matches('/'))
+                    .unwrap_or(&request.path)
+                    .to_string(),
+                None => request.path.clone(),
+            },
+            "query" | "args" => request.query_string.clone(),
+            "is_args" => if request.query_string.is_empty() { "" } else { "?" }.to_string(),
+            "host" => extract_hostname(&request.headers),
+            "scheme" => "http".to_string(),
+            _ => return None,
+        })
+    };
+
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(dollar) = rest.find('$') {
+        out.push_str(&rest[..dollar]);
+        let after = &rest[dollar + 1..];
+        let (name, len, braced) = match after.strip_prefix('{').and_then(|inner| inner.find('}').map(|end| (inner, end))) {
+            Some((inner, end)) => (&inner[..end], end + 2, true),
+            None => {
+                let end = if after.starts_with(|c: char| c.is_ascii_digit()) {
+                    after.bytes().take_while(u8::is_ascii_digit).count()
+                } else {
+                    after
+                        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                        .unwrap_or(after.len())
+                };
+                (&after[..end], end, false)
+            }
+        };
+        // `$name` is always a variable, `$1` and `${name}` may be captures
+        let capture = (braced || name.starts_with(|c: char| c.is_ascii_digit()))
+            .then(|| captures.iter().find(|(key, _)| !name.is_empty() && key == name))
+            .flatten()
+            .map(|(_, value)| value.clone());
+        match capture.or_else(|| value(name)) {
+            Some(value) => {
+                out.push_str(&value);
+                rest = &after[len..];
+            }
+            None => {
+                out.push('$');
+                rest = after;
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Whether `path` names an existing file or directory under the route that
+/// would serve it
+fn path_exists(server: &ServerConfig, path: &str) -> bool {
+    let decoded = urlencoding::decode(path)
+        .map(|p| p.into_owned())
+        .unwrap_or_else(|_| path.to_string());
+    find_matching_route(server, path)
+        .and_then(|route| resolve_file_path(server, &route, &decoded))
+        .is_some_and(|file| Path::new(&file).exists())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::parse_config;
+    use crate::request::HttpRequestBuilder;
+
+    /// Server with the given server-level lines (`return:`, `rewrite:`) and
+    /// routes, each route `(path, rewrite rules)` with root `/srv/<index>`
+    fn server(server_lines: &str, routes: &[(&str, &[&str])]) -> ServerConfig {
+        let mut yaml = String::from("servers:\n  - server_name: \"test\"\n    host: 127.0.0.1\n    ports:\n      - 8080\n");
+        yaml += server_lines;
+        yaml += "    routes:\n";
+        for (i, (path, rules)) in routes.iter().enumerate() {
+            yaml += &format!("      - path: \"{}\"\n        methods: [GET]\n        root: \"/srv/{}\"\n", path, i);
+            if !rules.is_empty() {
+                yaml += "        rewrite:\n";
+                for rule in *rules {
+                    yaml += &format!("          - {}\n", rule);
+                }
+            }
+        }
+        parse_config(&yaml).expect("test config").servers.remove(0)
+    }
+
+    /// Request for `target`, to example.com unless `headers` name a host
+    fn request(target: &str, headers: &str) -> HttpRequest {
+        let host = if headers.starts_with("Host:") { "" } else { "Host: example.com\r\n" };
+        let mut builder = HttpRequestBuilder::new();
+        builder
+            .append(format!("GET {} HTTP/1.1\r\n{}{}\r\n", target, host, headers).into_bytes())
+            .expect("valid request");
+        builder.get().expect("complete request").clone()
+    }
+
+    /// What the rewrite phase made of `target`, in one comparable line
+    fn outcome(server: &ServerConfig, target: &str, headers: &str) -> String {
+        let mut request = request(target, headers);
+        match rewrite_request(server, &mut request) {
+            Ok(Some(route)) => format!("{} {}?{}", route.root, request.path, request.query_string),
+            Ok(None) => "no route".to_string(),
+            Err(RewriteStop::Redirect(code, location)) => format!("{} {}", code, location),
+            Err(RewriteStop::Return(response)) => {
+                let response = String::from_utf8_lossy(&response).into_owned();
+                response.lines().next().unwrap_or_default().to_string()
+            }
+            Err(RewriteStop::TooManyCycles) => "too many cycles".to_string(),
+        }
+    }
+
+    #[test]
+    fn captures_win_over_variables_and_are_not_expanded_again() {
+        let request = request("/a/b?x=1", "");
+        let captures = [
+            ("1".to_string(), "$uri".to_string()),
+            ("host".to_string(), "captured".to_string()),
+        ];
+        assert_eq!(expand_variables("$1|${host}|$host", &request, None, &captures), "$uri|captured|example.com");
+        assert_eq!(
+            expand_variables("$scheme://$host$request_uri $uri$is_args$args $nope ${nope} $", &request, None, &[]),
+            "http://example.com/a/b?x=1 /a/b?x=1 $nope ${nope} $"
+        );
+    }
+
+    #[test]
+    fn path_suffix_is_relative_to_the_route() {
+        let server = server("", &[("/a/", &[])]);
+        let request = request("/a/b/c", "");
+        assert_eq!(expand_variables("$path_suffix", &request, Some(&server.routes[0]), &[]), "/b/c");
+        assert_eq!(expand_variables("$path_suffix", &request, None, &[]), "/a/b/c");
+    }
+
+    #[test]
+    fn flags_decide_where_the_rewritten_path_goes() {
+        let server = server(
+            "    rewrite:\n      - \"^/old/(.*)$ /new/$1\"\n",
+            &[
+                ("/new", &[]),
+                ("/last", &["\"^/last/(.*)$ /new/$1 last\""]),
+                ("/break", &["\"^/break/(.*)$ /new/$1 break\""]),
+                ("/go", &["\"^/go/(.*)$ /new/$1 redirect\""]),
+                ("/moved", &["\"^/moved/(.*)$ /new/$1 permanent\""]),
+                ("/away", &["\"^/away/(.*)$ https://example.org/$1\""]),
+                ("/chain", &["\"^/chain/(.*)$ /new/$1\""]),
+            ],
+        );
+        assert_eq!(outcome(&server, "/old/x", ""), "/srv/0 /new/x?");
+        assert_eq!(outcome(&server, "/last/x", ""), "/srv/0 /new/x?");
+        assert_eq!(outcome(&server, "/break/x", ""), "/srv/2 /new/x?");
+        assert_eq!(outcome(&server, "/go/x?q=1", ""), "302 /new/x?q=1");
+        assert_eq!(outcome(&server, "/moved/x", ""), "301 /new/x");
+        assert_eq!(outcome(&server, "/away/x", ""), "302 https://example.org/x");
+        assert_eq!(outcome(&server, "/chain/x", ""), "/srv/0 /new/x?");
+        assert_eq!(outcome(&server, "/elsewhere", ""), "no route");
+    }
+
+    #[test]
+    fn replacement_query_replaces_or_joins_the_original() {
+        let server = server(
+            "    rewrite:\n      - \"^/keep$ /new?a=1\"\n      - \"^/drop$ /new?\"\n",
+            &[("/", &[])],
+        );
+        assert_eq!(outcome(&server, "/keep?b=2", ""), "/srv/0 /new?a=1&b=2");
+        assert_eq!(outcome(&server, "/keep", ""), "/srv/0 /new?a=1");
+        assert_eq!(outcome(&server, "/drop?b=2", ""), "/srv/0 /new?");
+    }
+
+    #[test]
+    fn conditions_gate_their_rule() {
+        let server = server(
+            "    rewrite:\n      - { pattern: \"^/(.*)$\", to: \"/mobile/$1\", flag: last, header: \"User-Agent ~ Mobile\" }\n      - { pattern: \"^/(.*)$\", to: \"/debug/$1\", flag: last, query: \"debug=1\" }\n      - { pattern: \"^/(.*)$\", to: \"/plain/$1\", flag: last, header: \"!X-Keep\" }\n",
+            &[("/", &[])],
+        );
+        assert_eq!(outcome(&server, "/p", "User-Agent: Mobile Safari\r\n"), "/srv/0 /mobile/p?");
+        assert_eq!(outcome(&server, "/p?debug=1", "X-Keep: 1\r\n"), "/srv/0 /debug/p?debug=1");
+        assert_eq!(outcome(&server, "/p", "X-Keep: 1\r\n"), "/srv/0 /p?");
+        assert_eq!(outcome(&server, "/p", ""), "/srv/0 /plain/p?");
+    }
+
+    #[test]
+    fn returns_run_before_rewrites() {
+        let server = server(
+            "    return:\n      - { code: 301, to: \"https://canonical.test$request_uri\", host: \"!canonical.test\" }\n      - { code: 410, to: \"gone\", path: \"^/gone\" }\n    rewrite:\n      - \"^/(.*)$ /new/$1\"\n",
+            &[("/", &[])],
+        );
+        assert_eq!(outcome(&server, "/a?b", ""), "HTTP/1.1 301 Moved Permanently");
+        assert_eq!(outcome(&server, "/gone", "Host: canonical.test\r\n"), "HTTP/1.1 410 Gone");
+        assert_eq!(outcome(&server, "/a", "Host: canonical.test\r\n"), "/srv/0 /new/a?");
+
+        let Err(RewriteStop::Return(response)) = rewrite_request(&server, &mut request("/a?b", "")) else {
+            panic!("expected a return");
+        };
+        assert!(String::from_utf8_lossy(&response).contains("Location: https://canonical.test/a?b\r\n"));
+    }
+
+    #[test]
+    fn rules_sending_requests_around_are_stopped() {
+        let server = server(
+            "",
+            &[
+                ("/a", &["\"^/a$ /b last\""]),
+                ("/b", &["\"^/b$ /a last\""]),
+                ("/self", &["\"^/self(.*)$ /self/$1\""]),
+            ],
+        );
+        assert_eq!(outcome(&server, "/a", ""), "too many cycles");
+        assert_eq!(outcome(&server, "/self", ""), "too many cycles");
+    }
+}
@@ -1,23 +1,216 @@
-use crate::request::HttpRequest;
+//! Route selection. The configured `path` of a route picks its matcher:
+//!
+//! - `= /login`: exact match only
+//! - `^~ /static`: prefix which, as the longest matching one, skips regexes
+//! - `~ ^/u/(\d+)$`: regular expression (`~*` ignores case), captures
+//!   usable as `$1` or `${name}` in `root` and `redirect`
+//! - `/blog`: prefix, on whole path segments
+//!
+//! Precedence follows nginx: an exact match wins; then the longest prefix
+//! if it is a `^~` one; then the first matching regex in config order; then
+//! the longest prefix.
 
-type Handler = fn(&HttpRequest) -> Vec<u8>; // renvoie directement les bytes
+use std::borrow::Cow;
 
-pub struct Router {
-    routes: std::collections::HashMap<String, Handler>,
+use regex::{Captures, Regex, RegexBuilder};
+
+use crate::config::{Route, ServerConfig};
+
+#[derive(Debug, Clone)]
+pub enum RouteMatcher {
+    Prefix,
+    PriorityPrefix,
+    Exact,
+    Regex(Regex),
 }
 
-impl Router {
-    pub fn new() -> Self {
-        Router {
-            routes: std::collections::HashMap::new(),
+impl RouteMatcher {
+    /// Split a configured path into its matcher and the literal path (or
+    /// pattern) that remains
+    pub fn parse(path: &str) -> Result<(RouteMatcher, String), String> {
+        let regex = |pattern: &str, ignore_case: bool| {
+            RegexBuilder::new(pattern)
+                .case_insensitive(ignore_case)
+                .build()
+                .map(RouteMatcher::Regex)
+                .map_err(|e| format!("invalid regex: {}", e))
+        };
+
+        if let Some(rest) = path.strip_prefix("^~") {
+            Ok((RouteMatcher::PriorityPrefix, rest.trim().to_string()))
+        } else if let Some(rest) = path.strip_prefix("~*") {
+            Ok((regex(rest.trim(), true)?, rest.trim().to_string()))
+        } else if let Some(rest) = path.strip_prefix('~') {
+            Ok((regex(rest.trim(), false)?, rest.trim().to_string()))
+        } else if let Some(rest) = path.strip_prefix('=') {
+            Ok((RouteMatcher::Exact, rest.trim().to_string()))
+        } else {
+            Ok((RouteMatcher::Prefix, path.to_string()))
         }
     }
+}
+
+/// Route serving `request_path`. A regex route comes back as a copy for this
+/// request, with `root` filled from the captures and the captures kept for
+/// `redirect` and CGI scripts. Its `path` becomes the request path up to
+/// the end of the match: `root` stands for that part, typically naming a
+/// file or directory built from captures, and what follows the match is
+/// looked up inside it.
+pub fn find_matching_route<'a>(server: &'a ServerConfig, request_path: &str) -> Option<Cow<'a, Route>> {
+    if let Some(route) = server
+        .routes
+        .iter()
+        .find(|route| matches!(route.matcher, RouteMatcher::Exact) && route.path == request_path)
+    {
+        return Some(Cow::Borrowed(route));
+    }
+
+    let prefix = server
+        .routes
+        .iter()
+        .filter(|route| matches!(route.matcher, RouteMatcher::Prefix | RouteMatcher::PriorityPrefix))
+        .filter(|route| {
+            if route.path == "/" {
+                true
+            } else {
+                request_path == route.path || request_path.starts_with(&(route.path.clone() + "/"))
+            }
+        })
+        .max_by_key(|route| route.path.len());
+    if let Some(route) = prefix
+        && matches!(route.matcher, RouteMatcher::PriorityPrefix)
+    {
+        return Some(Cow::Borrowed(route));
+    }
+
+    for route in &server.routes {
+        if let RouteMatcher::Regex(regex) = &route.matcher
+            && let Some(captures) = regex.captures(request_path)
+            && let Some(route) = with_captures(route, regex, &captures, request_path)
+        {
+            return Some(Cow::Owned(route));
+        }
+    }
+
+    prefix.map(Cow::Borrowed)
+}
+
+/// Copy of a regex route for one match; None when a capture would lead
+/// out of the route's root
+fn with_captures(route: &Route, regex: &Regex, captures: &Captures, request_path: &str) -> Option<Route> {
+    let mut values = Vec::new();
+    for (i, name) in regex.capture_names().enumerate().skip(1) {
+        let value = captures.get(i).map_or("", |m| m.as_str());
+        if value.split('/').any(|segment| segment == "..") || value.contains('\\') {
+            return None;
+        }
+        values.push((i.to_string(), value.to_string()));
+        if let Some(name) = name {
+            values.push((name.to_string(), value.to_string()));
+        }
+    }
+
+    let mut route = route.clone();
+    route.path = request_path[..captures.get(0)?.end()].to_string();
+    route.root = expand_captures(&route.root, &values);
+    route.captures = values;
+    Some(route)
+}
+
+/// Fill `$1`, `$2`... and `${name}` from `captures`; any other `$` is
+/// left as written
+pub fn expand_captures(template: &str, captures: &[(String, String)]) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(dollar) = rest.find('$') {
+        out.push_str(&rest[..dollar]);
+        let after = &rest[dollar + 1..];
+        let (key, len) = match after.strip_prefix('{').and_then(|inner| inner.find('}').map(|end| (inner, end))) {
+            Some((inner, end)) => (&inner[..end], end + 2),
+            None => {
+                let digits = after.bytes().take_while(u8::is_ascii_digit).count();
+                (&after[..digits], digits)
+            }
+        };
+        match captures.iter().find(|(name, _)| !key.is_empty() && name == key) {
+            Some((_, value)) => {
+                out.push_str(value);
+                rest = &after[len..];
+            }
+            None => {
+                out.push('$');
+                rest = after;
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::parse_config;
+
+    fn server(routes: &[&str]) -> ServerConfig {
+        let mut yaml = String::from("servers:\n  - server_name: \"test\"\n    host: 127.0.0.1\n    ports:\n      - 8080\n    routes:\n");
+        for (i, path) in routes.iter().enumerate() {
+            yaml += &format!("      - path: \"{}\"\n        methods: [GET]\n        root: \"/srv/{}\"\n", path, i);
+        }
+        parse_config(&yaml).expect("test config").servers.remove(0)
+    }
+
+    /// Root of the route chosen for `path`, which tells the routes apart
+    fn root_for(server: &ServerConfig, path: &str) -> Option<String> {
+        find_matching_route(server, path).map(|route| route.root.clone())
+    }
+
+    #[test]
+    fn nginx_precedence() {
+        let server = server(&["/", "/static", "^~ /static/img", "~ \\.png$", "= /static/logo.png"]);
+        assert_eq!(root_for(&server, "/static/logo.png").as_deref(), Some("/srv/4"));
+        assert_eq!(root_for(&server, "/static/img/a.png").as_deref(), Some("/srv/2"));
+        assert_eq!(root_for(&server, "/static/a.png").as_deref(), Some("/srv/3"));
+        assert_eq!(root_for(&server, "/static/a.css").as_deref(), Some("/srv/1"));
+        assert_eq!(root_for(&server, "/other").as_deref(), Some("/srv/0"));
+    }
+
+    #[test]
+    fn prefixes_match_whole_segments() {
+        let server = server(&["/blog"]);
+        assert!(root_for(&server, "/blog").is_some());
+        assert!(root_for(&server, "/blog/post").is_some());
+        assert!(root_for(&server, "/blogger").is_none());
+    }
+
+    #[test]
+    fn first_matching_regex_wins() {
+        let server = server(&["~* ^/users/", "~ ^/users/(\\d+)$"]);
+        assert_eq!(root_for(&server, "/USERS/7").as_deref(), Some("/srv/0"));
+        assert_eq!(root_for(&server, "/other"), None);
+    }
+
+    #[test]
+    fn captures_fill_root_and_path() {
+        let mut server = server(&["~ ^/u/(?<user>\\w+)/(\\d+)"]);
+        server.routes[0].root = "/home/${user}/$2/$9".to_string();
+        let route = find_matching_route(&server, "/u/ada/42/rest").unwrap();
+        assert_eq!(route.root, "/home/ada/42/$9");
+        assert_eq!(route.path, "/u/ada/42");
+        assert!(route.captures.contains(&("user".to_string(), "ada".to_string())));
+    }
 
-    pub fn handle(&mut self, path: &str, handler: Handler) {
-        self.routes.insert(path.to_string(), handler);
+    #[test]
+    fn captures_cannot_leave_the_root() {
+        let server = server(&["~ ^/f/(.*)$"]);
+        assert!(root_for(&server, "/f/a/b").is_some());
+        assert!(root_for(&server, "/f/../etc").is_none());
+        assert!(root_for(&server, "/f/a\\b").is_none());
     }
 
-    pub fn route(&self, path: &str) -> Option<Handler> {
-        self.routes.get(path).copied()
+    #[test]
+    fn expand_captures_leaves_unknown_references() {
+        let captures = [("1".to_string(), "x".to_string()), ("id".to_string(), "7".to_string())];
+        assert_eq!(expand_captures("$1-${id}-$2-${other}-$-$$1", &captures), "x-7-$2-${other}-$-$x");
     }
 }
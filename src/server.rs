@@ -1,18 +1,41 @@
-use crate::config::{Config, ServerConfig};
+use crate::auth::AuthUser;
+use crate::config::{Config, ListenAddr, Route, ServerConfig, Timeouts};
 use crate::models::HttpResponseCommon;
 use crate::read::handle_read_state;
-use crate::request::HttpRequestBuilder;
+use crate::request::{HttpRequestBuilder, RequestLimits};
+use crate::rewrite::RewriteStop;
+use crate::timer::TimerQueue;
+use crate::utils::HttpMethod;
+use crate::utils::cookie_keys::CookieKeys;
 use crate::utils::session::SessionStore;
-use crate::write::handle_write_state;
+use crate::webdav::DavStore;
+use crate::write::{handle_write_state, should_keep_alive};
 use mio::net::{TcpListener, TcpStream};
-use mio::{Events, Interest, Poll, Token};
-use std::collections::HashMap;
+use mio::{Events, Interest, Poll, Token, Waker};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::{HashMap, VecDeque};
 use std::io::{self};
-use std::net::Shutdown;
+use std::net::{Shutdown, SocketAddr};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver, Sender};
 use std::time::{Duration, Instant};
 
 const LISTENER_TOKEN_START: usize = 0;
 const CONNECTION_TOKEN_START: usize = 10000;
+/// Event raised by a `Wakeup` from another thread
+const WAKER_TOKEN: Token = Token(usize::MAX);
+
+/// Time given to a body upload before the minimum transfer rate applies.
+const BODY_RATE_GRACE: Duration = Duration::from_secs(5);
+/// Upper bound on a single poll so expired sessions still get cleaned up.
+const MAX_POLL_WAIT: Duration = Duration::from_secs(1);
+/// Expired sessions are never served, so purging them from storage (which
+/// may mean scanning a directory) only needs to happen occasionally.
+const SESSION_CLEANUP_INTERVAL: Duration = Duration::from_secs(30);
+/// Responses a pipelining client may have waiting on one connection before
+/// its further requests are left unread until the backlog is written.
+const MAX_PIPELINED_RESPONSES: usize = 16;
 
 #[derive(PartialEq, Debug)]
 pub enum Status {
@@ -21,14 +44,152 @@ pub enum Status {
     Finish,
 }
 
+/// A response waiting to be written, in request order
+pub struct Outgoing {
+    pub response: Box<dyn HttpResponseCommon>,
+    pub keep_alive: bool,
+}
+
 pub struct SocketStatus {
     pub ttl: Instant,
     pub status: Status,
     pub request: HttpRequestBuilder,
     pub response: Option<Box<dyn HttpResponseCommon>>,
+    pub outbox: VecDeque<Outgoing>,
+    pub peer_closed: bool,
     pub server_selected: bool,
+    /// Route chosen after the rewrite phase, once the headers are complete
+    pub target: Option<Result<Option<Route>, RewriteStop>>,
+    /// Credentials already checked before the body (`Expect: 100-continue`)
+    pub auth: Option<Result<AuthUser, String>>,
     pub body_too_large: bool,
+    pub body_skipped: bool, // answered before its body was read
     pub max_body_size: Option<usize>,
+    pub request_started: Option<Instant>,
+    pub body_started: Option<Instant>,
+    pub min_body_rate: usize,
+    pub timeouts: Timeouts,
+    pub requests_served: usize,
+    pub keepalive_requests: usize,
+}
+
+impl SocketStatus {
+    /// Move the response just produced into the outbox and, on a kept-alive
+    /// connection, start the next request with any bytes already received
+    /// for it. Returns `true` when such a pipelined request can be handled
+    /// before the outbox is written.
+    pub fn queue_response(&mut self) -> bool {
+        let Some(mut response) = self.response.take() else {
+            return false;
+        };
+        self.requests_served += 1;
+
+        // A body that was not read to its end (oversized or refused before
+        // it was sent) leaves the stream out of sync with the next request
+        let keep_alive = !self.body_too_large
+            && !self.body_skipped
+            && !self.peer_closed
+            && self.requests_served < self.keepalive_requests
+            && self.request.get().is_some_and(should_keep_alive);
+
+        if keep_alive {
+            response.add_header("Connection", "keep-alive");
+            response.add_header(
+                "Keep-Alive",
+                &format!(
+                    "timeout={}, max={}",
+                    self.timeouts.keepalive.as_secs(),
+                    self.keepalive_requests - self.requests_served
+                ),
+            );
+        } else {
+            response.add_header("Connection", "close");
+        }
+        if self.request.get().is_some_and(|r| r.method == HttpMethod::HEAD) {
+            response.omit_body();
+        }
+        self.outbox.push_back(Outgoing {
+            response,
+            keep_alive,
+        });
+
+        if !keep_alive {
+            return false;
+        }
+        self.reset_for_next_request();
+        self.request.has_carried_over() && self.outbox.len() < MAX_PIPELINED_RESPONSES
+    }
+
+    /// Prepare a kept-alive connection to read its next request.
+    fn reset_for_next_request(&mut self) {
+        self.request = self.request.next_request();
+        self.server_selected = false;
+        self.target = None;
+        self.auth = None;
+        self.body_too_large = false;
+        self.body_skipped = false;
+        self.max_body_size = None;
+        self.request_started = self.request.has_carried_over().then(Instant::now);
+        self.body_started = None;
+    }
+
+    /// The instant at which the connection times out in its current phase.
+    pub fn deadline(&self) -> Instant {
+        match self.status {
+            Status::Write => {
+                // A script still producing the response is bounded by
+                // cgi_timeout, which may allow longer than send_timeout
+                let waiting = self
+                    .outbox
+                    .front()
+                    .is_some_and(|outgoing| outgoing.response.waiting_for_source());
+                if waiting {
+                    self.ttl + self.timeouts.send.max(self.timeouts.cgi)
+                } else {
+                    self.ttl + self.timeouts.send
+                }
+            }
+            Status::Finish => self.ttl,
+            Status::Read => match self.request_started {
+                None => self.ttl + self.timeouts.keepalive,
+                Some(started) if !self.request.header_done() => {
+                    started + self.timeouts.client_header
+                }
+                Some(_) => {
+                    let idle = self.ttl + self.timeouts.client_body;
+                    match self.body_started {
+                        Some(body_started) if self.min_body_rate > 0 => {
+                            // Without more data, the average rate falls below the
+                            // minimum once the bytes received so far are spread
+                            // over longer than this.
+                            let allowed = Duration::from_secs_f64(
+                                self.request.body_len() as f64 / self.min_body_rate as f64,
+                            );
+                            idle.min(body_started + allowed.max(BODY_RATE_GRACE))
+                        }
+                        _ => idle,
+                    }
+                }
+            },
+        }
+    }
+}
+
+/// Resumes one connection from another thread, such as the reader of a
+/// CGI script's output, once it has something for the event loop
+#[derive(Clone)]
+pub struct Wakeup {
+    token: Token,
+    waker: Arc<Waker>,
+    woken: Sender<Token>,
+}
+
+impl Wakeup {
+    pub fn wake(&self) {
+        if self.woken.send(self.token).is_ok() {
+            let _ = self.waker.wake();
+        }
+    }
 }
 
 pub struct SocketData {
@@ -36,14 +197,21 @@ pub struct SocketData {
     pub status: SocketStatus,
     pub listener_token: Token,
     pub session_store: SessionStore,
+    pub cookie_keys: Rc<CookieKeys>,
+    pub dav: Rc<DavStore>,
+    pub wakeup: Wakeup,
+    /// Deadline of the live entry for this connection in the timer queue
+    pub armed_deadline: Option<Instant>,
 }
 
 pub struct ListenerInfo {
     pub listener: TcpListener,
-    pub host: String,
-    pub port: u16,
+    pub addr: SocketAddr,
     pub servers: Vec<ServerConfig>,
     pub default_server_index: usize,
+    pub limits: RequestLimits,
+    pub timeouts: Timeouts,
+    pub keepalive_requests: usize,
 }
 
 pub struct Server {
@@ -52,42 +220,87 @@ pub struct Server {
     listeners: HashMap<Token, ListenerInfo>,
     connections: HashMap<Token, SocketData>,
     session_store: SessionStore,
+    cookie_keys: Rc<CookieKeys>,
+    dav: Rc<DavStore>,
+    timers: TimerQueue,
+    waker: Arc<Waker>,
+    woken: (Sender<Token>, Receiver<Token>),
+    last_session_cleanup: Instant,
     next_token: usize,
 }
 
+/// Nonblocking listening socket on `addr`; for IPv6, `ipv6only` decides
+/// whether IPv4 connections are accepted too
+fn bind_listener(addr: SocketAddr, ipv6only: bool) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(ipv6only)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    socket.set_nonblocking(true)?;
+    Ok(TcpListener::from_std(socket.into()))
+}
+
 impl Server {
     pub fn new() -> io::Result<Self> {
+        let poll = Poll::new()?;
+        let waker = Arc::new(Waker::new(poll.registry(), WAKER_TOKEN)?);
         Ok(Server {
-            poll: Poll::new()?,
+            poll,
             events: Events::with_capacity(1024),
             listeners: HashMap::new(),
             connections: HashMap::new(),
             session_store: SessionStore::new(),
+            cookie_keys: Rc::new(CookieKeys::random()),
+            dav: Rc::new(DavStore::new()),
+            timers: TimerQueue::new(),
+            waker,
+            woken: mpsc::channel(),
+            last_session_cleanup: Instant::now(),
             next_token: CONNECTION_TOKEN_START,
         })
     }
 
     pub fn run(&mut self, config: Config) -> io::Result<()> {
-        let mut listener_map: HashMap<(String, u16), Vec<(usize, ServerConfig)>> = HashMap::new();
+        self.session_store = SessionStore::from_config(&config.session_store, &config.session)?;
+        self.cookie_keys = Rc::new(CookieKeys::new(&config.cookie_secrets));
+
+        // Servers by bound address, with the entry that first named it
+        let mut listener_map: HashMap<SocketAddr, (ListenAddr, Vec<(usize, ServerConfig)>)> = HashMap::new();
 
         for (idx, server) in config.servers.iter().enumerate() {
-            for &port in &server.ports {
-                let key = (server.host.clone(), port);
-                listener_map
-                    .entry(key)
-                    .or_insert_with(Vec::new)
-                    .push((idx, server.clone()));
+            for listen in &server.listen {
+                for addr in listen.resolve()? {
+                    let (first, server_list) = listener_map
+                        .entry(addr)
+                        .or_insert_with(|| (listen.clone(), Vec::new()));
+                    if addr.is_ipv6() && first.ipv6only != listen.ipv6only {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            format!("conflicting ipv6only settings for listen address {}", addr),
+                        ));
+                    }
+                    if server_list.last().is_none_or(|(last, _)| *last != idx) {
+                        server_list.push((idx, server.clone()));
+                    }
+                }
             }
         }
 
-        let mut token_counter = LISTENER_TOKEN_START;
-
-        for ((host, port), server_list) in listener_map {
-            println!("Setting up listener on {}:{}... ", host, port);
-            let addr = format!("{}:{}", host, port).parse().unwrap();
-            let mut listener = TcpListener::bind(addr)?;
+        for (token_counter, (addr, (listen, server_list))) in
+            (LISTENER_TOKEN_START..).zip(listener_map)
+        {
+            if listen.to_string() == addr.to_string() {
+                println!("Setting up listener on {}... ", addr);
+            } else {
+                println!("Setting up listener on {} ({})... ", listen, addr);
+            }
+            let mut listener = bind_listener(addr, listen.ipv6only).map_err(|e| {
+                io::Error::new(e.kind(), format!("cannot listen on {} ({}): {}", listen, addr, e))
+            })?;
             let token = Token(token_counter);
-            token_counter += 1;
 
             self.poll
                 .registry()
@@ -99,11 +312,15 @@ impl Server {
                 .unwrap_or(0);
 
             let servers: Vec<ServerConfig> = server_list.into_iter().map(|(_, srv)| srv).collect();
+            // Header limits and timeouts must apply before the Host header is
+            // known, so they come from the default server of the listener.
+            let limits = servers[default_idx].request_limits();
+            let timeouts = servers[default_idx].timeouts;
+            let keepalive_requests = servers[default_idx].keepalive_requests;
 
             println!(
-                "Listening on {}:{} with {} server(s)",
-                host,
-                port,
+                "Listening on {} with {} server(s)",
+                addr,
                 servers.len()
             );
             for (i, srv) in servers.iter().enumerate() {
@@ -118,26 +335,45 @@ impl Server {
                 token,
                 ListenerInfo {
                     listener,
-                    host,
-                    port,
+                    addr,
                     servers,
                     default_server_index: default_idx,
+                    limits,
+                    timeouts,
+                    keepalive_requests,
                 },
             );
         }
 
         loop {
-            self.session_store.cleanup();
+            if self.last_session_cleanup.elapsed() >= SESSION_CLEANUP_INTERVAL {
+                self.session_store.cleanup();
+                self.last_session_cleanup = Instant::now();
+            }
             self.check_timeouts();
-            let timeout = Some(Duration::from_millis(100)); // wait max 100ms
+            let timeout = self
+                .timers
+                .next_timeout(Instant::now())
+                .map_or(MAX_POLL_WAIT, |t| t.min(MAX_POLL_WAIT));
 
-            self.poll.poll(&mut self.events, timeout)?;
+            self.poll.poll(&mut self.events, Some(timeout))?;
 
             for event in self.events.iter() {
                 let token = event.token();
 
-                if token.0 < CONNECTION_TOKEN_START {
+                if token == WAKER_TOKEN {
+                    // Several wakeups for one connection need a single pass
+                    let mut woken: Vec<Token> = self.woken.1.try_iter().collect();
+                    woken.sort();
+                    woken.dedup();
+                    for token in woken {
+                        Server::drive(&mut self.connections, &self.listeners, &mut self.timers, token);
+                    }
+                } else if token.0 < CONNECTION_TOKEN_START {
                     if let Some(listener_info) = self.listeners.get_mut(&token) {
+                        let limits = listener_info.limits;
+                        let timeouts = listener_info.timeouts;
+                        let keepalive_requests = listener_info.keepalive_requests;
                         loop {
                             match listener_info.listener.accept() {
                                 Ok((mut stream, _)) => {
@@ -153,21 +389,44 @@ impl Server {
                                         )
                                         .unwrap();
 
+                                    let status = SocketStatus {
+                                        ttl: Instant::now(),
+                                        status: Status::Read,
+                                        request: HttpRequestBuilder::with_limits(limits),
+                                        response: None,
+                                        outbox: VecDeque::new(),
+                                        peer_closed: false,
+                                        server_selected: false,
+                                        target: None,
+                                        auth: None,
+                                        max_body_size: None,
+                                        body_too_large: false,
+                                        body_skipped: false,
+                                        request_started: None,
+                                        body_started: None,
+                                        min_body_rate: 0,
+                                        timeouts,
+                                        requests_served: 0,
+                                        keepalive_requests,
+                                    };
+                                    let deadline = status.deadline();
+                                    self.timers.schedule(conn_token, deadline);
+
                                     self.connections.insert(
                                         conn_token,
                                         SocketData {
                                             stream,
-                                            status: SocketStatus {
-                                                ttl: Instant::now(),
-                                                status: Status::Read,
-                                                request: HttpRequestBuilder::new(),
-                                                response: None,
-                                                server_selected: false,
-                                                max_body_size: None,
-                                                body_too_large: false,
-                                            },
+                                            status,
                                             listener_token: token,
                                             session_store: self.session_store.clone(),
+                                            cookie_keys: self.cookie_keys.clone(),
+                                            dav: self.dav.clone(),
+                                            wakeup: Wakeup {
+                                                token: conn_token,
+                                                waker: self.waker.clone(),
+                                                woken: self.woken.0.clone(),
+                                            },
+                                            armed_deadline: Some(deadline),
                                         },
                                     );
 
@@ -187,27 +446,41 @@ impl Server {
                         }
                     }
                 } else {
-                    if let Some(socket_data) = self.connections.get_mut(&token) {
-                        loop {
-                            let listener_info = self.listeners.get(&socket_data.listener_token);
-                            match Server::handle(socket_data, listener_info) {
-                                Some(true) => {
-                                    continue;
-                                }
-                                Some(false) => {
-                                    break;
-                                }
-                                None => {
-                                    let _ = socket_data.stream.shutdown(Shutdown::Both);
-                                    self.connections.remove(&token);
-                                    break;
-                                }
-                            }
-                        }
+                    Server::drive(&mut self.connections, &self.listeners, &mut self.timers, token);
+                }
+            }
+        }
+    }
+
+    /// Handle the connection until it has to wait for its socket (or for a
+    /// wakeup), closing it if it is done
+    fn drive(
+        connections: &mut HashMap<Token, SocketData>,
+        listeners: &HashMap<Token, ListenerInfo>,
+        timers: &mut TimerQueue,
+        token: Token,
+    ) {
+        if let Some(socket_data) = connections.get_mut(&token) {
+            loop {
+                let listener_info = listeners.get(&socket_data.listener_token);
+                match Server::handle(socket_data, listener_info) {
+                    Some(true) => {
+                        continue;
+                    }
+                    Some(false) => {
+                        break;
+                    }
+                    None => {
+                        let _ = socket_data.stream.shutdown(Shutdown::Both);
+                        connections.remove(&token);
+                        break;
                     }
                 }
             }
         }
+        if let Some(conn) = connections.get_mut(&token) {
+            Server::arm_timer(timers, token, conn);
+        }
     }
 
     pub fn handle(
@@ -215,24 +488,66 @@ impl Server {
         listener_info: Option<&ListenerInfo>,
     ) -> Option<bool> {
         match socket_data.status.status {
-            Status::Read => handle_read_state(socket_data, listener_info),
+            Status::Read => {
+                let result = handle_read_state(socket_data, listener_info);
+                let status = &mut socket_data.status;
+
+                if status.status == Status::Write {
+                    // Answer a request that was pipelined behind this one
+                    // before writing anything
+                    if status.queue_response() {
+                        status.status = Status::Read;
+                        return Some(true);
+                    }
+                } else if status.outbox.is_empty() || (result.is_none() && !status.peer_closed) {
+                    return result;
+                } else if let Some(last) = status.outbox.back_mut() {
+                    // Nothing complete left to read: flush what is queued,
+                    // and stop after it if the client has closed its side
+                    last.keep_alive &= !status.peer_closed;
+                    status.status = Status::Write;
+                }
+
+                // The send timeout runs from the moment the response is ready,
+                // not from the last byte of the request.
+                status.ttl = Instant::now();
+                Some(true)
+            }
             Status::Write => handle_write_state(socket_data),
             Status::Finish => None,
         }
     }
 
+    /// Make sure the timer queue holds an entry no later than the current
+    /// deadline of the connection.
+    fn arm_timer(timers: &mut TimerQueue, token: Token, conn: &mut SocketData) {
+        let deadline = conn.status.deadline();
+        if conn.armed_deadline.is_none_or(|armed| deadline < armed) {
+            conn.armed_deadline = Some(deadline);
+            timers.schedule(token, deadline);
+        }
+    }
+
     fn check_timeouts(&mut self) {
-        const TIMEOUT: Duration = Duration::from_secs(5);
         let now = Instant::now();
-        let mut expired = Vec::new();
 
-        for (token, conn) in &self.connections {
-            if now.duration_since(conn.status.ttl) > TIMEOUT {
-                expired.push(*token);
+        for (armed, token) in self.timers.pop_expired(now) {
+            let Some(conn) = self.connections.get_mut(&token) else {
+                continue;
+            };
+            // Superseded by an earlier entry that has already been handled
+            if conn.armed_deadline != Some(armed) {
+                continue;
+            }
+
+            // Progress since arming may have pushed the deadline back
+            let deadline = conn.status.deadline();
+            if deadline > now {
+                conn.armed_deadline = Some(deadline);
+                self.timers.schedule(token, deadline);
+                continue;
             }
-        }
 
-        for token in expired {
             if let Some(mut conn) = self.connections.remove(&token) {
                 let _ = self.poll.registry().deregister(&mut conn.stream);
                 let _ = conn.stream.shutdown(Shutdown::Both);
@@ -0,0 +1,52 @@
+use mio::Token;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::time::{Duration, Instant};
+
+/// Min-heap of connection deadlines.
+///
+/// Entries are never removed when a connection makes progress: the owner
+/// keeps track of the deadline it last armed and ignores entries that no
+/// longer match it, re-arming when a popped deadline has moved.
+pub struct TimerQueue {
+    heap: BinaryHeap<Reverse<(Instant, Token)>>,
+}
+
+impl Default for TimerQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TimerQueue {
+    pub fn new() -> Self {
+        Self {
+            heap: BinaryHeap::new(),
+        }
+    }
+
+    pub fn schedule(&mut self, token: Token, deadline: Instant) {
+        self.heap.push(Reverse((deadline, token)));
+    }
+
+    /// Time left until the earliest deadline, if any is scheduled.
+    pub fn next_timeout(&self, now: Instant) -> Option<Duration> {
+        self.heap
+            .peek()
+            .map(|Reverse((deadline, _))| deadline.saturating_duration_since(now))
+    }
+
+    /// Remove and return every entry whose deadline is not after `now`.
+    pub fn pop_expired(&mut self, now: Instant) -> Vec<(Instant, Token)> {
+        let mut expired = Vec::new();
+        while let Some(Reverse((deadline, _))) = self.heap.peek() {
+            if *deadline > now {
+                break;
+            }
+            if let Some(Reverse(entry)) = self.heap.pop() {
+                expired.push(entry);
+            }
+        }
+        expired
+    }
+}
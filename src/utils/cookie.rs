@@ -1,5 +1,7 @@
 use std::time::{Duration, SystemTime};
 
+use crate::utils::cookie_keys::CookieKeys;
+
 #[derive(Debug, Clone)]
 pub struct Cookie {
     name: String,
@@ -36,6 +38,26 @@ impl Cookie {
         }
     }
 
+    /// Create a cookie whose value is signed with the newest key of the ring
+    pub fn signed(name: &str, value: &str, keys: &CookieKeys) -> Self {
+        Cookie::new(name, &keys.sign(name, value))
+    }
+
+    /// Create a cookie whose value is encrypted with the newest key of the ring
+    pub fn encrypted(name: &str, value: &str, keys: &CookieKeys) -> Self {
+        Cookie::new(name, &keys.encrypt(name, value))
+    }
+
+    /// Value of a signed cookie, if the signature is valid for any key of the ring
+    pub fn verified_value(&self, keys: &CookieKeys) -> Option<String> {
+        keys.verify(&self.name, &self.value)
+    }
+
+    /// Value of an encrypted cookie, if any key of the ring decrypts it
+    pub fn decrypted_value(&self, keys: &CookieKeys) -> Option<String> {
+        keys.decrypt(&self.name, &self.value)
+    }
+
     /// Set the cookie path
     pub fn path(mut self, path: &str) -> Self {
         self.path = Some(path.to_string());
@@ -231,10 +253,15 @@ impl std::fmt::Display for Cookie {
 
 // Helper function to extract session ID from cookies
 pub fn extract_session_id(cookie_header: Option<&str>) -> Option<String> {
+    find_cookie(cookie_header, "session_id")
+}
+
+/// Value of the named cookie in a Cookie header
+pub fn find_cookie(cookie_header: Option<&str>, name: &str) -> Option<String> {
     cookie_header.and_then(|header| {
         Cookie::parse(header)
             .into_iter()
-            .find(|cookie| cookie.name() == "session_id")
+            .find(|cookie| cookie.name() == name)
             .map(|cookie| cookie.value().to_string())
     })
 }
@@ -0,0 +1,189 @@
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as BASE64URL;
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+const NONCE_LEN: usize = 12;
+
+/// Keys derived from one `cookie_secret` entry
+struct KeyPair {
+    signing: [u8; 32],
+    encryption: [u8; 32],
+}
+
+impl KeyPair {
+    fn derive(secret: &[u8]) -> Self {
+        Self {
+            signing: derive_key(secret, b"localserver cookie signing"),
+            encryption: derive_key(secret, b"localserver cookie encryption"),
+        }
+    }
+}
+
+fn derive_key(secret: &[u8], purpose: &[u8]) -> [u8; 32] {
+    let mut mac =
+        <HmacSha256 as Mac>::new_from_slice(secret).expect("HMAC accepts any key length");
+    mac.update(purpose);
+    mac.finalize().into_bytes().into()
+}
+
+/// Keyring protecting cookie values. New values are always signed or
+/// encrypted with the first (newest) key; older keys are only tried when
+/// reading, so secrets can be rotated without invalidating live cookies.
+///
+/// The cookie name is bound into every MAC/tag, so a value issued for one
+/// cookie cannot be replayed under another name.
+pub struct CookieKeys {
+    keys: Vec<KeyPair>,
+}
+
+impl CookieKeys {
+    /// Keyring from the configured secrets, newest first. Without any secret a
+    /// random key is used, so protected cookies do not survive a restart.
+    pub fn new(secrets: &[String]) -> Self {
+        if secrets.is_empty() {
+            eprintln!("No cookie_secret configured: using a random key for this run");
+            return Self::random();
+        }
+        Self {
+            keys: secrets.iter().map(|s| KeyPair::derive(s.as_bytes())).collect(),
+        }
+    }
+
+    /// Keyring with a single key that only lives as long as the process
+    pub fn random() -> Self {
+        let mut secret = [0u8; 32];
+        getrandom::fill(&mut secret).expect("OS random generator unavailable");
+        Self {
+            keys: vec![KeyPair::derive(&secret)],
+        }
+    }
+
+    fn mac(key: &KeyPair, name: &str, value: &str) -> HmacSha256 {
+        let mut mac =
+            <HmacSha256 as Mac>::new_from_slice(&key.signing).expect("HMAC accepts any key length");
+        mac.update(name.as_bytes());
+        mac.update(b"=");
+        mac.update(value.as_bytes());
+        mac
+    }
+
+    /// `value.signature`, readable by the client but tamper-proof
+    pub fn sign(&self, name: &str, value: &str) -> String {
+        let signature = Self::mac(&self.keys[0], name, value).finalize().into_bytes();
+        format!("{}.{}", value, BASE64URL.encode(signature))
+    }
+
+    /// Original value of a signed cookie if any key in the ring vouches for it
+    pub fn verify(&self, name: &str, signed: &str) -> Option<String> {
+        let (value, signature) = signed.rsplit_once('.')?;
+        let signature = BASE64URL.decode(signature).ok()?;
+
+        self.keys
+            .iter()
+            .any(|key| Self::mac(key, name, value).verify_slice(&signature).is_ok())
+            .then(|| value.to_string())
+    }
+
+    /// Value hidden from the client and tamper-proof (ChaCha20-Poly1305)
+    pub fn encrypt(&self, name: &str, value: &str) -> String {
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(&self.keys[0].encryption));
+        let mut nonce = [0u8; NONCE_LEN];
+        getrandom::fill(&mut nonce).expect("OS random generator unavailable");
+
+        let payload = Payload {
+            msg: value.as_bytes(),
+            aad: name.as_bytes(),
+        };
+        let ciphertext = cipher
+            .encrypt(Nonce::from_slice(&nonce), payload)
+            .expect("encryption of an in-memory buffer cannot fail");
+
+        let mut out = nonce.to_vec();
+        out.extend_from_slice(&ciphertext);
+        BASE64URL.encode(out)
+    }
+
+    /// Plain value of an encrypted cookie, if any key in the ring decrypts it
+    pub fn decrypt(&self, name: &str, encrypted: &str) -> Option<String> {
+        let data = BASE64URL.decode(encrypted).ok()?;
+        if data.len() < NONCE_LEN {
+            return None;
+        }
+        let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+
+        self.keys.iter().find_map(|key| {
+            let cipher = ChaCha20Poly1305::new(Key::from_slice(&key.encryption));
+            let payload = Payload {
+                msg: ciphertext,
+                aad: name.as_bytes(),
+            };
+            let plain = cipher.decrypt(Nonce::from_slice(nonce), payload).ok()?;
+            String::from_utf8(plain).ok()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(secrets: &[&str]) -> CookieKeys {
+        CookieKeys::new(&secrets.iter().map(|s| s.to_string()).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn signed_value_round_trips_and_stays_readable() {
+        let ring = keys(&["secret"]);
+        let signed = ring.sign("theme", "dark");
+        assert!(signed.starts_with("dark."));
+        assert_eq!(ring.verify("theme", &signed).as_deref(), Some("dark"));
+    }
+
+    #[test]
+    fn tampered_or_renamed_signed_value_is_refused() {
+        let ring = keys(&["secret"]);
+        let signed = ring.sign("theme", "dark");
+        assert_eq!(ring.verify("theme", &signed.replacen("dark", "light", 1)), None);
+        assert_eq!(ring.verify("other", &signed), None);
+        assert_eq!(ring.verify("theme", "dark"), None);
+        assert_eq!(keys(&["another"]).verify("theme", &signed), None);
+    }
+
+    #[test]
+    fn rotation_keeps_old_cookies_valid() {
+        let old = keys(&["old"]);
+        let rotated = keys(&["new", "old"]);
+        let signed = old.sign("theme", "dark");
+        let encrypted = old.encrypt("cart", "3 items");
+
+        assert_eq!(rotated.verify("theme", &signed).as_deref(), Some("dark"));
+        assert_eq!(rotated.decrypt("cart", &encrypted).as_deref(), Some("3 items"));
+
+        // New values use the newest key only
+        assert_eq!(old.verify("theme", &rotated.sign("theme", "dark")), None);
+        assert_eq!(old.decrypt("cart", &rotated.encrypt("cart", "3 items")), None);
+
+        // Once dropped from the ring, the old key no longer vouches for anything
+        assert_eq!(keys(&["new"]).verify("theme", &signed), None);
+    }
+
+    #[test]
+    fn encrypted_value_is_hidden_and_bound_to_its_name() {
+        let ring = keys(&["secret"]);
+        let encrypted = ring.encrypt("cart", "3 items");
+        assert!(!encrypted.contains("items"));
+        assert_ne!(encrypted, ring.encrypt("cart", "3 items"));
+        assert_eq!(ring.decrypt("cart", &encrypted).as_deref(), Some("3 items"));
+        assert_eq!(ring.decrypt("other", &encrypted), None);
+
+        let mut bytes = BASE64URL.decode(&encrypted).unwrap();
+        *bytes.last_mut().unwrap() ^= 1;
+        assert_eq!(ring.decrypt("cart", &BASE64URL.encode(bytes)), None);
+        assert_eq!(ring.decrypt("cart", "short"), None);
+    }
+}
@@ -1,38 +1,133 @@
-use std::collections::HashMap;
-
+/// Header fields in the order they were received or added. Names keep their
+/// original casing for output but are matched case-insensitively, and a
+/// field may appear several times (e.g. `Set-Cookie`).
 #[derive(Debug, Default, Clone)]
 pub struct HttpHeaders {
-    inner: HashMap<String, String>,
+    entries: Vec<(String, String)>,
 }
 
 impl HttpHeaders {
     pub fn new() -> Self {
         HttpHeaders {
-            inner: HashMap::new(),
+            entries: Vec::new(),
         }
     }
 
+    /// Set a field, replacing every existing occurrence of it
     pub fn insert(&mut self, key: &str, value: &str) {
+        self.remove(key);
+        self.append(key, value);
+    }
+
+    /// Add an occurrence of a field, keeping the existing ones
+    pub fn append(&mut self, key: &str, value: &str) {
+        // trim surrounding whitespace, keep the name's casing
+        self.entries
+            .push((key.trim().to_string(), value.trim().to_string()));
+    }
 
-        // trim whitespace and convert key to lowercase
-        let value = value.trim();
-        let key = key.trim();
-        self.inner
-            .insert(key.to_ascii_lowercase(), value.to_string());
+    /// Combined value of a field: occurrences are joined with `, ` (or `; `
+    /// for `Cookie`). Fields that cannot be combined, like `Set-Cookie`, must
+    /// be read with `get_all`.
+    pub fn get(&self, key: &str) -> Option<String> {
+        let values = self.get_all(key);
+        if values.is_empty() {
+            return None;
+        }
+        let separator = if key.eq_ignore_ascii_case("cookie") { "; " } else { ", " };
+        Some(values.join(separator))
     }
 
-    pub fn get(&self, key: &str) -> Option<&String> {
-        self.inner.get(&key.to_ascii_lowercase())
+    /// Every occurrence of a field, in order
+    pub fn get_all(&self, key: &str) -> Vec<&str> {
+        self.entries
+            .iter()
+            .filter(|(name, _)| name.eq_ignore_ascii_case(key))
+            .map(|(_, value)| value.as_str())
+            .collect()
     }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.entries
+            .iter()
+            .any(|(name, _)| name.eq_ignore_ascii_case(key))
+    }
+
+    /// Number of field lines, counting repeated fields once per occurrence
     pub fn len(&self) -> usize {
-        self.inner.len()
+        self.entries.len()
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Remove every occurrence of a field and return their combined value
     pub fn remove(&mut self, key: &str) -> Option<String> {
-        self.inner.remove(&key.to_ascii_lowercase())
+        let removed = self.get(key);
+        self.entries
+            .retain(|(name, _)| !name.eq_ignore_ascii_case(key));
+        removed
+    }
+
+    /// Field lines in order, with names in their original casing
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+
+    /// Distinct field names, in order of first appearance
+    pub fn names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = Vec::new();
+        for (name, _) in &self.entries {
+            if !names.iter().any(|n| n.eq_ignore_ascii_case(name)) {
+                names.push(name);
+            }
+        }
+        names
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repeated_fields_keep_order_and_casing() {
+        let mut headers = HttpHeaders::new();
+        headers.append("Set-Cookie", "a=1");
+        headers.append("X-Custom", " v ");
+        headers.append("set-cookie", "b=2");
+
+        assert_eq!(headers.get_all("SET-COOKIE"), vec!["a=1", "b=2"]);
+        assert_eq!(headers.get("x-custom").as_deref(), Some("v"));
+        assert_eq!(headers.names(), vec!["Set-Cookie", "X-Custom"]);
+        assert_eq!(
+            headers.iter().collect::<Vec<_>>(),
+            vec![("Set-Cookie", "a=1"), ("X-Custom", "v"), ("set-cookie", "b=2")]
+        );
+    }
+
+    #[test]
+    fn combined_values_use_the_field_separator() {
+        let mut headers = HttpHeaders::new();
+        headers.append("Accept", "text/html");
+        headers.append("accept", "*/*");
+        headers.append("Cookie", "a=1");
+        headers.append("Cookie", "b=2");
+        assert_eq!(headers.get("Accept").as_deref(), Some("text/html, */*"));
+        assert_eq!(headers.get("cookie").as_deref(), Some("a=1; b=2"));
     }
 
-    pub fn iter(&self) -> impl Iterator<Item = (&String, &String)> {
-        self.inner.iter()
+    #[test]
+    fn insert_replaces_every_occurrence() {
+        let mut headers = HttpHeaders::new();
+        headers.append("Via", "a");
+        headers.append("VIA", "b");
+        headers.insert("via", "c");
+        assert_eq!(headers.len(), 1);
+        assert_eq!(headers.remove("Via").as_deref(), Some("c"));
+        assert!(headers.is_empty() && !headers.contains("via"));
     }
 }
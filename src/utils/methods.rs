@@ -1,25 +1,62 @@
 #[derive(Debug, PartialEq, Eq, Hash, Clone)]
 pub enum HttpMethod {
     GET,
+    HEAD,
     POST,
+    PUT,
+    PATCH,
     DELETE,
+    OPTIONS,
+    // WebDAV (RFC 4918)
+    PROPFIND,
+    PROPPATCH,
+    MKCOL,
+    COPY,
+    MOVE,
+    LOCK,
+    UNLOCK,
     Other(String),
 }
 
-impl HttpMethod {
-    pub fn from_str(method: &str) -> HttpMethod {
+impl From<&str> for HttpMethod {
+    fn from(method: &str) -> HttpMethod {
         match method {
             "GET" => HttpMethod::GET,
+            "HEAD" => HttpMethod::HEAD,
             "POST" => HttpMethod::POST,
+            "PUT" => HttpMethod::PUT,
+            "PATCH" => HttpMethod::PATCH,
             "DELETE" => HttpMethod::DELETE,
+            "OPTIONS" => HttpMethod::OPTIONS,
+            "PROPFIND" => HttpMethod::PROPFIND,
+            "PROPPATCH" => HttpMethod::PROPPATCH,
+            "MKCOL" => HttpMethod::MKCOL,
+            "COPY" => HttpMethod::COPY,
+            "MOVE" => HttpMethod::MOVE,
+            "LOCK" => HttpMethod::LOCK,
+            "UNLOCK" => HttpMethod::UNLOCK,
             _ => Self::Other(method.to_string()),
         }
     }
+}
+
+impl HttpMethod {
     pub fn to_str(&self) -> &str {
         match self {
             HttpMethod::GET => "GET",
+            HttpMethod::HEAD => "HEAD",
             HttpMethod::POST => "POST",
+            HttpMethod::PUT => "PUT",
+            HttpMethod::PATCH => "PATCH",
             HttpMethod::DELETE => "DELETE",
+            HttpMethod::OPTIONS => "OPTIONS",
+            HttpMethod::PROPFIND => "PROPFIND",
+            HttpMethod::PROPPATCH => "PROPPATCH",
+            HttpMethod::MKCOL => "MKCOL",
+            HttpMethod::COPY => "COPY",
+            HttpMethod::MOVE => "MOVE",
+            HttpMethod::LOCK => "LOCK",
+            HttpMethod::UNLOCK => "UNLOCK",
             HttpMethod::Other(method) => method.as_str(),
         }
     }
@@ -1,7 +1,11 @@
 pub mod cookie;
+pub mod cookie_keys;
+pub mod json;
 mod methods;
 mod headers;
 pub mod session;
+pub mod session_backend;
+pub mod xml;
 
 pub use methods::HttpMethod;
 pub use headers::HttpHeaders;
@@ -1,24 +1,57 @@
-use std::cell::RefCell;
 use std::collections::HashMap;
+use std::io;
 use std::rc::Rc;
-use std::time::{Duration, Instant};
-use uuid::Uuid;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as BASE64URL;
+
+use crate::config::{SessionBackendKind, SessionConfig, SessionExpiry, SessionStoreConfig};
 use crate::request::HttpRequest;
-use crate::utils::cookie::Cookie;
+use crate::utils::cookie::{Cookie, find_cookie};
+use crate::utils::cookie_keys::CookieKeys;
+use crate::utils::json::json_string;
+use crate::utils::session_backend::{FileBackend, LogBackend, MemoryBackend, SessionBackend};
+
+/// Session data key holding the name of the logged-in user
+pub const SESSION_USER_KEY: &str = "user";
+/// Session data key remembering the page to return to after login
+pub const SESSION_RETURN_KEY: &str = "return_to";
+
+/// Session IDs are base64url characters, 6 random bits each
+pub const DEFAULT_SESSION_ID_LENGTH: usize = 43; // 256 bits
+pub const MIN_SESSION_ID_LENGTH: usize = 22; // 128 bits
+
+/// Random session ID of `length` base64url characters from the OS CSPRNG
+pub fn generate_session_id(length: usize) -> String {
+    let mut bytes = vec![0u8; (length * 6).div_ceil(8)];
+    getrandom::fill(&mut bytes).expect("OS random generator unavailable");
+    let mut id = BASE64URL.encode(bytes);
+    id.truncate(length);
+    id
+}
 
 #[derive(Debug, Clone)]
 pub struct Session {
     pub id: String,
-    pub created_at: Instant,
-    pub expires_at: Instant,
+    pub created_at: SystemTime,
+    pub expires_at: SystemTime,
     pub data: HashMap<String, String>,
 }
 
+impl Default for Session {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Session {
     pub fn new() -> Self {
-        let id = Uuid::new_v4().to_string();
-        let now = Instant::now();
+        Self::with_id(generate_session_id(DEFAULT_SESSION_ID_LENGTH))
+    }
+
+    pub fn with_id(id: String) -> Self {
+        let now = SystemTime::now();
 
         Session {
             id,
@@ -29,7 +62,7 @@ impl Session {
     }
 
     pub fn set_expiry(&mut self, duration: Duration) {
-        self.expires_at = Instant::now() + duration;
+        self.expires_at = SystemTime::now() + duration;
     }
 
     pub fn set_data(&mut self, key: &str, value: &str) {
@@ -45,109 +78,261 @@ impl Session {
     }
 
     pub fn is_expired(&self) -> bool {
-        Instant::now() >= self.expires_at
+        SystemTime::now() >= self.expires_at
+    }
+
+    /// Push the expiry back by `idle`, never past `max_lifetime` after creation
+    pub fn renew(&mut self, idle: Duration, max_lifetime: Option<Duration>) {
+        let mut expires_at = SystemTime::now() + idle;
+        if let Some(max) = max_lifetime {
+            expires_at = expires_at.min(self.created_at + max);
+        }
+        self.expires_at = expires_at;
+    }
+
+    /// Session data as a flat JSON object of strings, keys sorted
+    pub fn data_json(&self) -> String {
+        let mut entries: Vec<_> = self.data.iter().collect();
+        entries.sort();
+        let fields: Vec<String> = entries
+            .into_iter()
+            .map(|(k, v)| format!("{}:{}", json_string(k), json_string(v)))
+            .collect();
+        format!("{{{}}}", fields.join(","))
+    }
+
+    /// Seconds left before the session expires
+    pub fn remaining_secs(&self) -> u64 {
+        self.expires_at
+            .duration_since(SystemTime::now())
+            .map(|d| d.as_secs_f64().ceil() as u64)
+            .unwrap_or(0)
     }
 
-    pub fn renew(&mut self) {
-        self.expires_at = Instant::now() + Duration::from_secs(3600);
+    /// Serialize to a single line: `id<TAB>created<TAB>expires<TAB>k=v&k=v`,
+    /// with times as Unix seconds and data keys/values percent-encoded
+    pub fn to_record(&self) -> String {
+        let data: Vec<String> = self
+            .data
+            .iter()
+            .map(|(k, v)| format!("{}={}", urlencoding::encode(k), urlencoding::encode(v)))
+            .collect();
+
+        format!(
+            "{}\t{}\t{}\t{}",
+            self.id,
+            unix_secs(self.created_at),
+            unix_secs(self.expires_at),
+            data.join("&")
+        )
+    }
+
+    /// Parse a line produced by `to_record`
+    pub fn from_record(record: &str) -> Option<Session> {
+        let mut fields = record.trim_end_matches(['\r', '\n']).split('\t');
+        let id = fields.next()?.to_string();
+        let created_at = UNIX_EPOCH + Duration::from_secs(fields.next()?.parse().ok()?);
+        let expires_at = UNIX_EPOCH + Duration::from_secs(fields.next()?.parse().ok()?);
+
+        let mut data = HashMap::new();
+        for pair in fields.next().unwrap_or("").split('&').filter(|p| !p.is_empty()) {
+            let (k, v) = pair.split_once('=')?;
+            data.insert(
+                urlencoding::decode(k).ok()?.into_owned(),
+                urlencoding::decode(v).ok()?.into_owned(),
+            );
+        }
+
+        if id.is_empty() {
+            return None;
+        }
+
+        Some(Session {
+            id,
+            created_at,
+            expires_at,
+            data,
+        })
     }
 }
 
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Handle to the configured session backend and cookie policy, shared by
+/// all connections
 #[derive(Clone)]
 pub struct SessionStore {
-    inner: Rc<RefCell<HashMap<String, Session>>>,
+    backend: Rc<dyn SessionBackend>,
+    config: Rc<SessionConfig>,
+}
+
+impl Default for SessionStore {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl SessionStore {
+    /// In-memory store with the default policy, lost on restart
     pub fn new() -> Self {
         Self {
-            inner: Rc::new(RefCell::new(HashMap::new())),
+            backend: Rc::new(MemoryBackend::new()),
+            config: Rc::new(SessionConfig::default()),
         }
     }
 
+    /// Store using the backend and policy selected in the config
+    pub fn from_config(store: &SessionStoreConfig, session: &SessionConfig) -> io::Result<Self> {
+        let backend: Rc<dyn SessionBackend> = match store.backend {
+            SessionBackendKind::Memory => Rc::new(MemoryBackend::new()),
+            SessionBackendKind::File => Rc::new(FileBackend::open(&store.path)?),
+            SessionBackendKind::Log => Rc::new(LogBackend::open(&store.path)?),
+        };
+        Ok(Self {
+            backend,
+            config: Rc::new(session.clone()),
+        })
+    }
+
+    pub fn config(&self) -> &SessionConfig {
+        &self.config
+    }
+
     /// Create a new anonymous session
     pub fn create(&self) -> Session {
-        let session = Session::new();
-        self.inner
-            .borrow_mut()
-            .insert(session.id.clone(), session.clone());
+        let mut session = Session::with_id(generate_session_id(self.config.id_length));
+        session.renew(self.config.idle_timeout, self.config.max_lifetime);
+        self.backend.insert(&session);
         session
     }
 
+    /// Move a session's data under a fresh ID and drop the old one, so an ID
+    /// planted or observed before a login or privilege change is worthless
+    /// afterwards. Returns `None` if the session does not exist.
+    pub fn regenerate(&self, session_id: &str) -> Option<Session> {
+        let old = self.get(session_id)?;
+
+        let mut session = Session::with_id(generate_session_id(self.config.id_length));
+        session.created_at = old.created_at;
+        session.data = old.data;
+        session.renew(self.config.idle_timeout, self.config.max_lifetime);
+
+        self.backend.insert(&session);
+        self.backend.destroy(session_id);
+        Some(session)
+    }
+
+    /// Record activity on a session and store it; only sliding sessions are
+    /// extended
+    pub fn touch(&self, session: &mut Session) {
+        if self.config.expiry == SessionExpiry::Sliding {
+            session.renew(self.config.idle_timeout, self.config.max_lifetime);
+        }
+        self.backend.touch(session);
+    }
+
+    /// Signed cookie carrying a session ID, living as long as the session
+    pub fn cookie(&self, session: &Session, keys: &CookieKeys) -> Cookie {
+        self.base_cookie(&keys.sign(&self.config.cookie_name, &session.id))
+            .max_age(session.remaining_secs())
+    }
+
+    /// Cookie telling the browser to drop the session cookie
+    pub fn removal_cookie(&self) -> Cookie {
+        self.base_cookie("").max_age(0)
+    }
+
+    fn base_cookie(&self, value: &str) -> Cookie {
+        let config = &self.config;
+        let mut cookie = Cookie::new(&config.cookie_name, value)
+            .path(&config.path)
+            .http_only(true)
+            .secure(config.secure);
+        if let Some(domain) = &config.domain {
+            cookie = cookie.domain(domain);
+        }
+        if let Some(same_site) = &config.same_site {
+            cookie = cookie.same_site(same_site.clone());
+        }
+        cookie
+    }
+
     /// Get a session by ID
     pub fn get(&self, session_id: &str) -> Option<Session> {
-        let sessions = self.inner.borrow();
-        sessions.get(session_id).cloned()
+        self.backend.get(session_id)
     }
 
-    /// Update a session
     /// Update a session
     pub fn update(&self, session: &Session) -> bool {
-        let mut sessions = self.inner.borrow_mut();
+        self.backend.update(session)
+    }
 
-        if sessions.contains_key(&session.id) {
-            sessions.insert(session.id.clone(), session.clone());
-            true
-        } else {
-            false
-        }
+    /// Remove a session, e.g. on logout
+    pub fn destroy(&self, session_id: &str) -> bool {
+        self.backend.destroy(session_id)
+    }
+
+    /// Name of the user logged into a session, if any
+    pub fn logged_in_user(&self, session_id: &str) -> Option<String> {
+        self.get(session_id)
+            .and_then(|session| session.get_data(SESSION_USER_KEY).cloned())
     }
 
     /// Clean up expired sessions
     pub fn cleanup(&self) -> usize {
-        let mut sessions = self.inner.borrow_mut();
-        let before = sessions.len();
-        sessions.retain(|_, session| !session.is_expired());
-        before - sessions.len()
+        self.backend.cleanup()
     }
 
     pub fn with_session<F>(&self, session_id: &str, mut f: F) -> bool
     where
         F: FnMut(&mut Session),
     {
-        let mut sessions = self.inner.borrow_mut();
-        if let Some(session) = sessions.get_mut(session_id) {
-            f(session);
-            true
-        } else {
-            false
-        }
+        self.backend.with_session(session_id, &mut f)
     }
 }
 
-pub fn handle_session(request: &HttpRequest, session_store: &mut SessionStore) -> Cookie {
-    if let Some(session_id) = &request.session_id {
-        // Existing session: increment visits and renew expiry
-        session_store.with_session(session_id, |session| {
-            let visits = session
-                .data
-                .get("visits")
-                .and_then(|v| v.parse::<u32>().ok())
-                .unwrap_or(0);
-            session
-                .data
-                .insert("visits".to_string(), (visits + 1).to_string());
-            session.renew();
-        });
-
-        // Return cookie (refresh max_age)
-        Cookie::new("session_id", session_id)
-            .path("/")
-            .http_only(true)
-            .max_age(3600)
-    } else {
-        // No session: create new
+/// Attach the request to a session, creating one if needed. Returns the
+/// session ID and the signed cookie carrying it.
+pub fn handle_session(
+    request: &HttpRequest,
+    session_store: &SessionStore,
+    keys: &CookieKeys,
+) -> (String, Cookie) {
+    let cookie_name = &session_store.config().cookie_name;
+
+    // A cookie whose signature does not verify is treated as absent
+    let presented = find_cookie(request.headers.get("cookie").as_deref(), cookie_name)
+        .and_then(|value| keys.verify(cookie_name, &value));
+
+    // Existing session: increment visits and renew expiry
+    let mut current = None;
+    if let Some(mut session) = presented.and_then(|session_id| session_store.get(&session_id)) {
+        let visits = session
+            .data
+            .get("visits")
+            .and_then(|v| v.parse::<u32>().ok())
+            .unwrap_or(0);
+        session
+            .data
+            .insert("visits".to_string(), (visits + 1).to_string());
+        session_store.touch(&mut session);
+        current = Some(session);
+    }
+
+    // No session, or an unknown/expired ID: never adopt the presented ID,
+    // always mint a fresh one
+    let session = current.unwrap_or_else(|| {
         let mut session = session_store.create();
         session.data.insert("visits".to_string(), "1".to_string());
-        let new_session_id = session.id.clone();
-
-        // Save session in store
         session_store.update(&session);
+        session
+    });
 
-        // Create Set-Cookie header
-        Cookie::new("session_id", &new_session_id)
-            .path("/")
-            .http_only(true)
-            .max_age(3600)
-    }
+    // Create Set-Cookie header (refresh max_age)
+    let cookie = session_store.cookie(&session, keys);
+    (session.id, cookie)
 }
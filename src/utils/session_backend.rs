@@ -0,0 +1,375 @@
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::utils::session::Session;
+
+/// The log is rewritten once it holds this many times more records than
+/// there are live sessions (and at least `LOG_COMPACT_MIN` records).
+const LOG_COMPACT_FACTOR: usize = 4;
+const LOG_COMPACT_MIN: usize = 1000;
+
+/// A session file is rewritten for mere activity at most this often (or
+/// every quarter of the remaining lifetime, for short timeouts)
+const TOUCH_WRITE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Storage for sessions. `get` never returns an expired session, even if
+/// `cleanup` has not removed it yet.
+pub trait SessionBackend {
+    /// Store a newly created session
+    fn insert(&self, session: &Session) -> bool;
+
+    fn get(&self, session_id: &str) -> Option<Session>;
+
+    /// Replace a stored session; returns `false` if it does not exist
+    fn update(&self, session: &Session) -> bool;
+
+    /// Store a session after a request that only renewed it or bumped its
+    /// visit count; a backend may skip writes that would change little
+    fn touch(&self, session: &Session) -> bool {
+        self.update(session)
+    }
+
+    fn destroy(&self, session_id: &str) -> bool;
+
+    /// Remove expired sessions and return how many were removed
+    fn cleanup(&self) -> usize;
+
+    fn with_session(&self, session_id: &str, f: &mut dyn FnMut(&mut Session)) -> bool {
+        match self.get(session_id) {
+            Some(mut session) => {
+                f(&mut session);
+                self.update(&session)
+            }
+            None => false,
+        }
+    }
+}
+
+// === In-memory ===
+
+/// Sessions kept in process memory, lost on restart
+pub struct MemoryBackend {
+    sessions: RefCell<HashMap<String, Session>>,
+}
+
+impl Default for MemoryBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        Self {
+            sessions: RefCell::new(HashMap::new()),
+        }
+    }
+}
+
+impl SessionBackend for MemoryBackend {
+    fn insert(&self, session: &Session) -> bool {
+        self.sessions
+            .borrow_mut()
+            .insert(session.id.clone(), session.clone());
+        true
+    }
+
+    fn get(&self, session_id: &str) -> Option<Session> {
+        self.sessions
+            .borrow()
+            .get(session_id)
+            .filter(|session| !session.is_expired())
+            .cloned()
+    }
+
+    fn update(&self, session: &Session) -> bool {
+        let mut sessions = self.sessions.borrow_mut();
+        if sessions.contains_key(&session.id) {
+            sessions.insert(session.id.clone(), session.clone());
+            true
+        } else {
+            false
+        }
+    }
+
+    fn destroy(&self, session_id: &str) -> bool {
+        self.sessions.borrow_mut().remove(session_id).is_some()
+    }
+
+    fn cleanup(&self) -> usize {
+        let mut sessions = self.sessions.borrow_mut();
+        let before = sessions.len();
+        sessions.retain(|_, session| !session.is_expired());
+        before - sessions.len()
+    }
+
+    fn with_session(&self, session_id: &str, f: &mut dyn FnMut(&mut Session)) -> bool {
+        match self.sessions.borrow_mut().get_mut(session_id) {
+            Some(session) if !session.is_expired() => {
+                f(session);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+// === File per session ===
+
+/// One `<id>.session` file per session in a directory. Every operation goes
+/// to disk, so several server processes can share the directory; activity
+/// alone rewrites a file only once per `TOUCH_WRITE_INTERVAL`.
+pub struct FileBackend {
+    dir: PathBuf,
+}
+
+impl FileBackend {
+    pub fn open(dir: &str) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        Ok(Self {
+            dir: PathBuf::from(dir),
+        })
+    }
+
+    /// File of a session, or `None` if the ID could escape the directory
+    fn path_for(&self, session_id: &str) -> Option<PathBuf> {
+        let valid = !session_id.is_empty()
+            && session_id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        valid.then(|| self.dir.join(format!("{}.session", session_id)))
+    }
+
+    fn write(&self, session: &Session) -> bool {
+        let Some(path) = self.path_for(&session.id) else {
+            return false;
+        };
+        // Write then rename so readers never see a half-written file
+        let tmp = self.dir.join(format!(".{}.tmp", session.id));
+        match fs::write(&tmp, session.to_record()).and_then(|_| fs::rename(&tmp, &path)) {
+            Ok(_) => true,
+            Err(e) => {
+                eprintln!("Failed to write session file {:?}: {}", path, e);
+                false
+            }
+        }
+    }
+
+    fn read(path: &Path) -> Option<Session> {
+        Session::from_record(&fs::read_to_string(path).ok()?)
+    }
+}
+
+impl SessionBackend for FileBackend {
+    fn insert(&self, session: &Session) -> bool {
+        self.write(session)
+    }
+
+    fn get(&self, session_id: &str) -> Option<Session> {
+        Self::read(&self.path_for(session_id)?)
+            .filter(|session| session.id == session_id && !session.is_expired())
+    }
+
+    fn update(&self, session: &Session) -> bool {
+        match self.path_for(&session.id) {
+            Some(path) if path.exists() => self.write(session),
+            _ => false,
+        }
+    }
+
+    fn touch(&self, session: &Session) -> bool {
+        let Some(path) = self.path_for(&session.id) else {
+            return false;
+        };
+        let written = fs::metadata(&path)
+            .and_then(|meta| meta.modified())
+            .ok()
+            .and_then(|modified| modified.elapsed().ok());
+        let remaining = session
+            .expires_at
+            .duration_since(SystemTime::now())
+            .unwrap_or_default();
+        match written {
+            // Recent enough: the stored expiry still leaves most of the
+            // lifetime, so the renewal can wait for a later request
+            Some(elapsed) if elapsed < TOUCH_WRITE_INTERVAL.min(remaining / 4) => true,
+            Some(_) => self.write(session),
+            None => false,
+        }
+    }
+
+    fn destroy(&self, session_id: &str) -> bool {
+        self.path_for(session_id)
+            .is_some_and(|path| fs::remove_file(path).is_ok())
+    }
+
+    fn cleanup(&self) -> usize {
+        let Ok(entries) = fs::read_dir(&self.dir) else {
+            return 0;
+        };
+
+        let mut removed = 0;
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.extension().is_none_or(|ext| ext != "session") {
+                continue;
+            }
+            let expired = Self::read(&path).is_none_or(|session| session.is_expired());
+            if expired && fs::remove_file(&path).is_ok() {
+                removed += 1;
+            }
+        }
+        removed
+    }
+}
+
+// === Append-only log ===
+
+/// Every change is appended to a single log file (`S<TAB>record` to store a
+/// session, `D<TAB>id` to delete one) and replayed on startup. Reads are
+/// served from memory, so the log is owned by a single server process.
+pub struct LogBackend {
+    path: PathBuf,
+    sessions: RefCell<HashMap<String, Session>>,
+    log: RefCell<File>,
+    records: Cell<usize>,
+}
+
+impl LogBackend {
+    pub fn open(path: &str) -> io::Result<Self> {
+        let path = PathBuf::from(path);
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut sessions = HashMap::new();
+        let mut records = 0;
+        if let Ok(content) = fs::read_to_string(&path) {
+            for line in content.lines() {
+                records += 1;
+                match line.split_once('\t') {
+                    Some(("S", record)) => {
+                        if let Some(session) = Session::from_record(record) {
+                            sessions.insert(session.id.clone(), session);
+                        }
+                    }
+                    Some(("D", id)) => {
+                        sessions.remove(id);
+                    }
+                    // A torn last line after a crash is simply skipped
+                    _ => {}
+                }
+            }
+        }
+        sessions.retain(|_, session: &mut Session| !session.is_expired());
+
+        let log = OpenOptions::new().create(true).append(true).open(&path)?;
+        println!(
+            "Session log {:?}: restored {} session(s)",
+            path,
+            sessions.len()
+        );
+
+        Ok(Self {
+            path,
+            sessions: RefCell::new(sessions),
+            log: RefCell::new(log),
+            records: Cell::new(records),
+        })
+    }
+
+    fn append(&self, line: &str) -> bool {
+        match writeln!(self.log.borrow_mut(), "{}", line) {
+            Ok(_) => {
+                self.records.set(self.records.get() + 1);
+                true
+            }
+            Err(e) => {
+                eprintln!("Failed to append to session log {:?}: {}", self.path, e);
+                false
+            }
+        }
+    }
+
+    /// Rewrite the log with only the live sessions
+    fn compact(&self) -> io::Result<()> {
+        let sessions = self.sessions.borrow();
+        let tmp = self.path.with_extension("compact");
+
+        let mut content = String::new();
+        for session in sessions.values() {
+            content.push_str("S\t");
+            content.push_str(&session.to_record());
+            content.push('\n');
+        }
+        fs::write(&tmp, content)?;
+        fs::rename(&tmp, &self.path)?;
+
+        *self.log.borrow_mut() = OpenOptions::new().append(true).open(&self.path)?;
+        self.records.set(sessions.len());
+        Ok(())
+    }
+}
+
+impl SessionBackend for LogBackend {
+    fn insert(&self, session: &Session) -> bool {
+        self.sessions
+            .borrow_mut()
+            .insert(session.id.clone(), session.clone());
+        self.append(&format!("S\t{}", session.to_record()))
+    }
+
+    fn get(&self, session_id: &str) -> Option<Session> {
+        self.sessions
+            .borrow()
+            .get(session_id)
+            .filter(|session| !session.is_expired())
+            .cloned()
+    }
+
+    fn update(&self, session: &Session) -> bool {
+        {
+            let mut sessions = self.sessions.borrow_mut();
+            if !sessions.contains_key(&session.id) {
+                return false;
+            }
+            sessions.insert(session.id.clone(), session.clone());
+        }
+        self.append(&format!("S\t{}", session.to_record()))
+    }
+
+    fn destroy(&self, session_id: &str) -> bool {
+        let removed = self.sessions.borrow_mut().remove(session_id).is_some();
+        if removed {
+            self.append(&format!("D\t{}", session_id));
+        }
+        removed
+    }
+
+    fn cleanup(&self) -> usize {
+        let expired: Vec<String> = self
+            .sessions
+            .borrow()
+            .values()
+            .filter(|session| session.is_expired())
+            .map(|session| session.id.clone())
+            .collect();
+
+        for id in &expired {
+            self.destroy(id);
+        }
+
+        let live = self.sessions.borrow().len();
+        if self.records.get() > (live * LOG_COMPACT_FACTOR).max(LOG_COMPACT_MIN)
+            && let Err(e) = self.compact()
+        {
+            eprintln!("Failed to compact session log {:?}: {}", self.path, e);
+        }
+
+        expired.len()
+    }
+}
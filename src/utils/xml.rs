@@ -0,0 +1,359 @@
+/// Minimal XML reader for the small request bodies of WebDAV (`propfind`,
+/// `propertyupdate`, `lockinfo`). Namespaces are resolved, so elements are
+/// matched on `(namespace, local name)` whatever prefix the client chose.
+/// DTDs are refused outright: entity expansion is never needed here and is
+/// the usual way into an XML parser.
+#[derive(Debug, Clone)]
+pub struct XmlElement {
+    pub namespace: String,
+    pub name: String,
+    pub children: Vec<XmlNode>,
+}
+
+#[derive(Debug, Clone)]
+pub enum XmlNode {
+    Element(XmlElement),
+    Text(String),
+}
+
+/// Deepest nesting accepted, to keep the recursive parser off the stack limit
+const MAX_DEPTH: usize = 64;
+
+impl XmlElement {
+    pub fn parse(input: &str) -> Result<XmlElement, &'static str> {
+        let mut parser = Parser {
+            input,
+            pos: 0,
+            scopes: vec![vec![
+                ("xml".to_string(), "http://www.w3.org/XML/1998/namespace".to_string()),
+            ]],
+        };
+
+        parser.skip_misc()?;
+        let root = parser.element(0)?;
+        parser.skip_misc()?;
+        if parser.pos != input.len() {
+            return Err("Content after the root element");
+        }
+        Ok(root)
+    }
+
+    pub fn is(&self, namespace: &str, name: &str) -> bool {
+        self.namespace == namespace && self.name == name
+    }
+
+    /// Child elements, text and whitespace left out
+    pub fn elements(&self) -> impl Iterator<Item = &XmlElement> {
+        self.children.iter().filter_map(|node| match node {
+            XmlNode::Element(element) => Some(element),
+            XmlNode::Text(_) => None,
+        })
+    }
+
+    pub fn child(&self, namespace: &str, name: &str) -> Option<&XmlElement> {
+        self.elements().find(|e| e.is(namespace, name))
+    }
+
+    /// Concatenated text of the element and its descendants
+    pub fn text(&self) -> String {
+        self.children
+            .iter()
+            .map(|node| match node {
+                XmlNode::Element(element) => element.text(),
+                XmlNode::Text(text) => text.clone(),
+            })
+            .collect()
+    }
+
+    /// Content of the element serialised back to XML, each child element
+    /// declaring its own namespace so the fragment stands on its own
+    pub fn inner_xml(&self) -> String {
+        let mut out = String::new();
+        for node in &self.children {
+            match node {
+                XmlNode::Element(element) => element.write(&mut out),
+                XmlNode::Text(text) => out.push_str(&escape(text)),
+            }
+        }
+        out
+    }
+
+    fn write(&self, out: &mut String) {
+        out.push_str(&format!(
+            "<{} xmlns=\"{}\"",
+            self.name,
+            escape(&self.namespace)
+        ));
+        if self.children.is_empty() {
+            out.push_str("/>");
+        } else {
+            out.push('>');
+            out.push_str(&self.inner_xml());
+            out.push_str(&format!("</{}>", self.name));
+        }
+    }
+}
+
+/// Escape text for use in element content or a double-quoted attribute
+pub fn escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '&' => out.push_str("&amp;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+struct Parser<'a> {
+    input: &'a str,
+    pos: usize,
+    /// Namespace declarations of each open element, innermost last
+    scopes: Vec<Vec<(String, String)>>,
+}
+
+impl Parser<'_> {
+    fn rest(&self) -> &str {
+        &self.input[self.pos..]
+    }
+
+    fn skip_whitespace(&mut self) {
+        let trimmed = self.rest().trim_start_matches([' ', '\t', '\r', '\n']);
+        self.pos = self.input.len() - trimmed.len();
+    }
+
+    fn skip_past(&mut self, end: &str) -> Result<(), &'static str> {
+        let found = self.rest().find(end).ok_or("Unterminated markup")?;
+        self.pos += found + end.len();
+        Ok(())
+    }
+
+    /// Whitespace, comments and processing instructions around the root
+    fn skip_misc(&mut self) -> Result<(), &'static str> {
+        loop {
+            self.skip_whitespace();
+            if self.rest().starts_with("<?") {
+                self.skip_past("?>")?;
+            } else if self.rest().starts_with("<!--") {
+                self.skip_past("-->")?;
+            } else if self.rest().starts_with("<!") {
+                return Err("Document type declarations are not allowed");
+            } else {
+                return Ok(());
+            }
+        }
+    }
+
+    fn name(&mut self) -> Result<&str, &'static str> {
+        let rest = self.rest();
+        let len = rest
+            .find(|c: char| c.is_whitespace() || matches!(c, '/' | '>' | '=' | '<'))
+            .unwrap_or(rest.len());
+        if len == 0 {
+            return Err("Expected a name");
+        }
+        let start = self.pos;
+        self.pos += len;
+        Ok(&self.input[start..start + len])
+    }
+
+    fn resolve(&self, prefix: &str) -> Result<String, &'static str> {
+        self.scopes
+            .iter()
+            .rev()
+            .flat_map(|scope| scope.iter())
+            .find(|(p, _)| p == prefix)
+            .map(|(_, uri)| uri.clone())
+            .or_else(|| prefix.is_empty().then(String::new))
+            .ok_or("Undeclared namespace prefix")
+    }
+
+    fn element(&mut self, depth: usize) -> Result<XmlElement, &'static str> {
+        if depth > MAX_DEPTH {
+            return Err("Elements nested too deeply");
+        }
+        if !self.rest().starts_with('<') {
+            return Err("Expected an element");
+        }
+        self.pos += 1;
+        let qname = self.name()?.to_string();
+
+        // Attributes: only namespace declarations matter here
+        let mut scope = Vec::new();
+        let self_closing = loop {
+            self.skip_whitespace();
+            if self.rest().starts_with("/>") {
+                self.pos += 2;
+                break true;
+            }
+            if self.rest().starts_with('>') {
+                self.pos += 1;
+                break false;
+            }
+            let attribute = self.name()?.to_string();
+            self.skip_whitespace();
+            if !self.rest().starts_with('=') {
+                return Err("Expected '=' after attribute name");
+            }
+            self.pos += 1;
+            self.skip_whitespace();
+            let value = self.attribute_value()?;
+            if attribute == "xmlns" {
+                scope.push((String::new(), value));
+            } else if let Some(prefix) = attribute.strip_prefix("xmlns:") {
+                scope.push((prefix.to_string(), value));
+            }
+        };
+
+        self.scopes.push(scope);
+        let (prefix, name) = qname.split_once(':').unwrap_or(("", &qname));
+        let namespace = self.resolve(prefix)?;
+        let name = name.to_string();
+
+        let mut children = Vec::new();
+        if !self_closing {
+            loop {
+                let rest = self.rest();
+                if rest.is_empty() {
+                    return Err("Unterminated element");
+                } else if rest.starts_with("</") {
+                    self.pos += 2;
+                    if self.name()? != qname {
+                        return Err("Mismatched closing tag");
+                    }
+                    self.skip_whitespace();
+                    if !self.rest().starts_with('>') {
+                        return Err("Expected '>'");
+                    }
+                    self.pos += 1;
+                    break;
+                } else if rest.starts_with("<!--") {
+                    self.skip_past("-->")?;
+                } else if rest.starts_with("<?") {
+                    self.skip_past("?>")?;
+                } else if let Some(cdata) = rest.strip_prefix("<![CDATA[") {
+                    let end = cdata.find("]]>").ok_or("Unterminated CDATA section")?;
+                    children.push(XmlNode::Text(cdata[..end].to_string()));
+                    self.pos += "<![CDATA[".len() + end + 3;
+                } else if rest.starts_with("<!") {
+                    return Err("Unexpected declaration");
+                } else if rest.starts_with('<') {
+                    children.push(XmlNode::Element(self.element(depth + 1)?));
+                } else {
+                    let end = rest.find('<').unwrap_or(rest.len());
+                    let text = decode_entities(&rest[..end])?;
+                    self.pos += end;
+                    children.push(XmlNode::Text(text));
+                }
+            }
+        }
+        self.scopes.pop();
+
+        Ok(XmlElement {
+            namespace,
+            name,
+            children,
+        })
+    }
+
+    fn attribute_value(&mut self) -> Result<String, &'static str> {
+        let quote = self.rest().chars().next().ok_or("Expected attribute value")?;
+        if quote != '"' && quote != '\'' {
+            return Err("Attribute value must be quoted");
+        }
+        self.pos += 1;
+        let end = self.rest().find(quote).ok_or("Unterminated attribute value")?;
+        let value = decode_entities(&self.rest()[..end])?;
+        self.pos += end + 1;
+        Ok(value)
+    }
+}
+
+/// Replace the predefined entities and character references
+fn decode_entities(text: &str) -> Result<String, &'static str> {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(amp) = rest.find('&') {
+        out.push_str(&rest[..amp]);
+        let semi = rest[amp..].find(';').ok_or("Unterminated entity")? + amp;
+        let entity = &rest[amp + 1..semi];
+        let decoded = match entity {
+            "lt" => '<',
+            "gt" => '>',
+            "amp" => '&',
+            "quot" => '"',
+            "apos" => '\'',
+            _ => {
+                let code = if let Some(hex) = entity.strip_prefix("#x") {
+                    u32::from_str_radix(hex, 16).ok()
+                } else if let Some(dec) = entity.strip_prefix('#') {
+                    dec.parse().ok()
+                } else {
+                    None
+                };
+                code.and_then(char::from_u32).ok_or("Unknown entity")?
+            }
+        };
+        out.push(decoded);
+        rest = &rest[semi + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefixes_resolve_to_namespaces() {
+        let root = XmlElement::parse(
+            "<?xml version=\"1.0\"?>\n<a:propfind xmlns:a=\"DAV:\"><a:prop xmlns=\"urn:x\">\
+             <a:getetag/><color/></a:prop></a:propfind>",
+        )
+        .unwrap();
+        assert!(root.is("DAV:", "propfind"));
+        let prop = root.child("DAV:", "prop").unwrap();
+        let names: Vec<_> = prop.elements().map(|e| (e.namespace.as_str(), e.name.as_str())).collect();
+        assert_eq!(names, [("DAV:", "getetag"), ("urn:x", "color")]);
+    }
+
+    #[test]
+    fn text_decodes_entities_and_cdata() {
+        let root = XmlElement::parse("<o>a &lt;b&gt; &#x26;&#65;<!-- c --><![CDATA[<raw>]]></o>").unwrap();
+        assert_eq!(root.text(), "a <b> &A<raw>");
+    }
+
+    #[test]
+    fn inner_xml_declares_each_namespace() {
+        let root = XmlElement::parse("<D:owner xmlns:D=\"DAV:\"><D:href>mailto:a&amp;b</D:href></D:owner>").unwrap();
+        assert_eq!(root.inner_xml(), "<href xmlns=\"DAV:\">mailto:a&amp;b</href>");
+    }
+
+    #[test]
+    fn malformed_documents_are_refused() {
+        for input in [
+            "<!DOCTYPE x [<!ENTITY e \"boom\">]><x>&e;</x>",
+            "<a><b></a></b>",
+            "<p:a/>",
+            "<a>&e;</a>",
+            "<a/><b/>",
+            "<a x=1/>",
+            "<a>",
+        ] {
+            assert!(XmlElement::parse(input).is_err(), "{input}");
+        }
+    }
+
+    #[test]
+    fn nesting_is_limited() {
+        let nested = |depth: usize| "<a>".repeat(depth) + &"</a>".repeat(depth);
+        assert!(XmlElement::parse(&nested(MAX_DEPTH + 1)).is_ok());
+        assert!(XmlElement::parse(&nested(MAX_DEPTH + 2)).is_err());
+    }
+}
@@ -0,0 +1,206 @@
+//! Virtual host names. A server answers to every name in `server_names`:
+//!
+//! - `example.com`: that exact name
+//! - `*.example.com`: any subdomain, however deep (not `example.com` itself)
+//! - `.example.com`: `example.com` and all of its subdomains
+//! - `www.*`: any name starting with `www.`
+//! - `~^pr-\d+\.preview\.example\.com$`: regular expression
+//!
+//! Names compare without case and without a trailing dot. As in nginx, an
+//! exact name wins, then the longest leading wildcard, then the longest
+//! trailing wildcard, then the first matching regex in config order.
+
+use regex::{Regex, RegexBuilder};
+
+use crate::config::ServerConfig;
+
+#[derive(Debug, Clone)]
+pub enum ServerName {
+    Exact(String),
+    /// Suffix that must follow at least one label, `.example.com`
+    LeadingWildcard(String),
+    /// `.example.com`: the bare domain or any subdomain
+    Domain(String),
+    /// Prefix that must be followed by at least one label, `www.`
+    TrailingWildcard(String),
+    Regex(Regex),
+}
+
+/// Kind of a match, the lowest being preferred, then the longest name
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum MatchKind {
+    Exact,
+    Leading,
+    Trailing,
+    Regex,
+}
+
+impl ServerName {
+    pub fn parse(name: &str) -> Result<ServerName, String> {
+        let name = name.trim();
+        if let Some(pattern) = name.strip_prefix('~') {
+            return RegexBuilder::new(pattern.trim())
+                .case_insensitive(true)
+                .build()
+                .map(ServerName::Regex)
+                .map_err(|e| format!("Invalid regex in server name '{}': {}", name, e));
+        }
+
+        let name = normalize_hostname(name);
+        if name.is_empty() {
+            return Err("Empty server name".to_string());
+        }
+        if let Some(suffix) = name.strip_prefix("*.") {
+            if !suffix.contains('*') {
+                return Ok(ServerName::LeadingWildcard(format!(".{}", suffix)));
+            }
+        } else if let Some(prefix) = name.strip_suffix(".*") {
+            if !prefix.contains('*') {
+                return Ok(ServerName::TrailingWildcard(format!("{}.", prefix)));
+            }
+        } else if let Some(domain) = name.strip_prefix('.') {
+            if !domain.contains('*') {
+                return Ok(ServerName::Domain(domain.to_string()));
+            }
+        } else if !name.contains('*') {
+            return Ok(ServerName::Exact(name));
+        }
+        Err(format!(
+            "Invalid server name '{}': '*' only as a whole first or last label",
+            name
+        ))
+    }
+
+    /// Kind and length of the match for a normalized host name
+    fn matches(&self, hostname: &str) -> Option<(MatchKind, usize)> {
+        match self {
+            ServerName::Exact(name) => (name == hostname).then_some((MatchKind::Exact, name.len())),
+            ServerName::LeadingWildcard(suffix) => (hostname.len() > suffix.len()
+                && hostname.ends_with(suffix.as_str()))
+            .then_some((MatchKind::Leading, suffix.len())),
+            ServerName::Domain(domain) => {
+                if hostname == domain {
+                    Some((MatchKind::Exact, domain.len()))
+                } else {
+                    (hostname.len() > domain.len() + 1
+                        && hostname.ends_with(domain.as_str())
+                        && hostname.as_bytes()[hostname.len() - domain.len() - 1] == b'.')
+                        .then_some((MatchKind::Leading, domain.len() + 1))
+                }
+            }
+            ServerName::TrailingWildcard(prefix) => (hostname.len() > prefix.len()
+                && hostname.starts_with(prefix.as_str()))
+            .then_some((MatchKind::Trailing, prefix.len())),
+            ServerName::Regex(regex) => regex.is_match(hostname).then_some((MatchKind::Regex, 0)),
+        }
+    }
+}
+
+/// Lower case, without the trailing dot of a fully qualified name
+pub fn normalize_hostname(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// Server of `servers` whose names best match `hostname` (normalized), if any
+pub fn find_server<'a>(servers: &'a [ServerConfig], hostname: &str) -> Option<&'a ServerConfig> {
+    let mut best: Option<(MatchKind, usize, &ServerConfig)> = None;
+    for server in servers {
+        for name in &server.server_names {
+            if let Some((kind, len)) = name.matches(hostname) {
+                // A strictly better kind, or a longer name of the same kind;
+                // ties keep the first server (and regexes keep config order)
+                let better = match best {
+                    None => true,
+                    Some((best_kind, best_len, _)) => {
+                        kind < best_kind || (kind == best_kind && kind != MatchKind::Regex && len > best_len)
+                    }
+                };
+                if better {
+                    best = Some((kind, len, server));
+                }
+            }
+        }
+    }
+    best.map(|(_, _, server)| server)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::parse_config;
+
+    fn kind(name: &str, hostname: &str) -> Option<MatchKind> {
+        ServerName::parse(name).unwrap().matches(hostname).map(|(kind, _)| kind)
+    }
+
+    /// One server per entry, each answering to the given names
+    fn servers(names: &[&str]) -> Vec<ServerConfig> {
+        let mut yaml = String::from("servers:\n");
+        for (i, names) in names.iter().enumerate() {
+            yaml += &format!(
+                "  - server_name: \"s{}\"\n    server_names: [{}]\n    host: 127.0.0.1\n    ports:\n      - 8080\n    routes:\n      - path: \"/\"\n        methods: [GET]\n        root: \"/srv\"\n",
+                i, names
+            );
+        }
+        parse_config(&yaml).expect("test config").servers
+    }
+
+    fn found(servers: &[ServerConfig], hostname: &str) -> Option<String> {
+        find_server(servers, &normalize_hostname(hostname)).map(|s| s.server_name.clone())
+    }
+
+    #[test]
+    fn name_forms() {
+        assert_eq!(kind("Example.COM.", "example.com"), Some(MatchKind::Exact));
+        assert_eq!(kind("*.example.com", "a.b.example.com"), Some(MatchKind::Leading));
+        assert_eq!(kind("*.example.com", "example.com"), None);
+        assert_eq!(kind("*.example.com", "badexample.com"), None);
+        assert_eq!(kind(".example.com", "example.com"), Some(MatchKind::Exact));
+        assert_eq!(kind(".example.com", "www.example.com"), Some(MatchKind::Leading));
+        assert_eq!(kind(".example.com", "notexample.com"), None);
+        assert_eq!(kind("www.*", "www.example.org"), Some(MatchKind::Trailing));
+        assert_eq!(kind("www.*", "www."), None);
+        assert_eq!(kind("~^pr-\\d+\\.preview\\.", "PR-12.preview.test"), Some(MatchKind::Regex));
+    }
+
+    #[test]
+    fn misplaced_wildcards_are_refused() {
+        for name in ["a.*.com", "*example.com", "www*", "*.*.com", "", ".", "~("] {
+            assert!(ServerName::parse(name).is_err(), "{name}");
+        }
+    }
+
+    #[test]
+    fn hostnames_are_normalized() {
+        assert_eq!(normalize_hostname("WWW.Example.com."), "www.example.com");
+    }
+
+    #[test]
+    fn nginx_precedence() {
+        let servers = servers(&[
+            "\"~^www\\.\"",
+            "\"www.*\"",
+            "\"*.example.com\"",
+            "\"*.shop.example.com\"",
+            "\"www.shop.example.com\"",
+        ]);
+        assert_eq!(found(&servers, "WWW.shop.example.com.").as_deref(), Some("s4"));
+        assert_eq!(found(&servers, "www.a.shop.example.com").as_deref(), Some("s3"));
+        assert_eq!(found(&servers, "www.example.com").as_deref(), Some("s2"));
+        assert_eq!(found(&servers, "www.example.org").as_deref(), Some("s1"));
+        assert_eq!(found(&servers, "other.test").as_deref(), None);
+    }
+
+    #[test]
+    fn regexes_keep_config_order() {
+        let servers = servers(&["\"~^a\"", "\"~^ab\""]);
+        assert_eq!(found(&servers, "abc").as_deref(), Some("s0"));
+    }
+
+    #[test]
+    fn first_server_wins_a_tie() {
+        let servers = servers(&["\"*.example.com\"", "\".example.com\""]);
+        assert_eq!(found(&servers, "a.example.com").as_deref(), Some("s0"));
+        assert_eq!(found(&servers, "example.com").as_deref(), Some("s1"));
+    }
+}
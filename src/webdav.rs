@@ -0,0 +1,1081 @@
+//! WebDAV (RFC 4918) class 1 and 2 for routes with `webdav: true`: the
+//! route's directory can be browsed and edited by file managers. Paths go
+//! through `resolve_file_path` like every other file access, after percent
+//! decoding. Locks and dead properties are kept in memory.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use uuid::Uuid;
+
+use crate::config::{Route, ServerConfig};
+use crate::handler::{handle_get, handle_put};
+use crate::models::{FileResponse, HttpResponseCommon, SimpleResponse};
+use crate::read::resolve_file_path;
+use crate::request::HttpRequest;
+use crate::response::{HttpResponseBuilder, detect_content_type};
+use crate::utils::HttpMethod;
+use crate::utils::cookie::Cookie;
+use crate::utils::xml::{XmlElement, escape};
+
+const DAV: &str = "DAV:";
+
+/// Lock lifetime when the client asks for none, and the longest granted
+const DEFAULT_LOCK_TIMEOUT: Duration = Duration::from_secs(600);
+const MAX_LOCK_TIMEOUT: Duration = Duration::from_secs(3600);
+
+/// Methods only a WebDAV route answers
+pub const WEBDAV_METHODS: &[&str] = &["PROPFIND", "PROPPATCH", "MKCOL", "COPY", "MOVE", "LOCK", "UNLOCK"];
+
+/// Properties computed from the file system; clients cannot change them
+const LIVE_PROPERTIES: &[&str] = &[
+    "displayname",
+    "getcontentlength",
+    "getcontenttype",
+    "getetag",
+    "getlastmodified",
+    "resourcetype",
+    "lockdiscovery",
+    "supportedlock",
+];
+
+/// Write lock held on a resource, or on a whole tree with depth infinity
+struct Lock {
+    token: String,
+    path: PathBuf,
+    root_href: String,
+    infinite: bool,
+    exclusive: bool,
+    owner: String, // XML content of the client's `owner` element
+    timeout: Duration,
+    expires: Instant,
+}
+
+impl Lock {
+    fn covers(&self, path: &Path) -> bool {
+        self.path == path || (self.infinite && path.starts_with(&self.path))
+    }
+
+    fn to_xml(&self) -> String {
+        let scope = if self.exclusive { "exclusive" } else { "shared" };
+        let depth = if self.infinite { "infinity" } else { "0" };
+        let left = self.expires.saturating_duration_since(Instant::now());
+        let remaining = left.as_secs() + u64::from(left.subsec_nanos() > 0);
+        format!(
+            "<D:activelock><D:locktype><D:write/></D:locktype>\
+             <D:lockscope><D:{}/></D:lockscope><D:depth>{}</D:depth>\
+             <D:owner>{}</D:owner><D:timeout>Second-{}</D:timeout>\
+             <D:locktoken><D:href>{}</D:href></D:locktoken>\
+             <D:lockroot><D:href>{}</D:href></D:lockroot></D:activelock>",
+            scope,
+            depth,
+            self.owner,
+            remaining,
+            self.token,
+            escape(&self.root_href)
+        )
+    }
+}
+
+/// Property set by a client with PROPPATCH, stored as given
+#[derive(Clone)]
+struct DeadProperty {
+    namespace: String,
+    name: String,
+    value: String, // XML content
+}
+
+/// Locks and dead properties of every WebDAV route, shared by all
+/// connections like the session store
+#[derive(Default)]
+pub struct DavStore {
+    locks: RefCell<Vec<Lock>>,
+    properties: RefCell<HashMap<PathBuf, Vec<DeadProperty>>>,
+}
+
+impl DavStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn expire_locks(&self) {
+        let now = Instant::now();
+        self.locks.borrow_mut().retain(|lock| lock.expires > now);
+    }
+
+    /// Whether the submitted lock tokens allow changing `path` (and, for
+    /// `subtree`, everything below it). A resource under several shared locks
+    /// only needs one of their tokens.
+    fn may_write(&self, path: &Path, subtree: bool, tokens: &[String]) -> bool {
+        let locks = self.locks.borrow();
+        let relevant: Vec<&Lock> = locks
+            .iter()
+            .filter(|l| l.covers(path) || (subtree && l.path.starts_with(path)))
+            .collect();
+        let held = |l: &Lock| tokens.contains(&l.token);
+
+        relevant.iter().all(|lock| {
+            held(lock)
+                || (!lock.exclusive
+                    && relevant
+                        .iter()
+                        .any(|o| !o.exclusive && o.path == lock.path && held(o)))
+        })
+    }
+
+    /// Adding or removing a member changes its parent collection, which
+    /// a lock on the parent (even of depth 0) protects
+    fn may_change_member(&self, path: &Path, subtree: bool, tokens: &[String]) -> bool {
+        self.may_write(path, subtree, tokens)
+            && path
+                .parent()
+                .is_none_or(|parent| self.may_write(parent, false, tokens))
+    }
+
+    fn lock_conflicts(&self, path: &Path, infinite: bool, exclusive: bool) -> bool {
+        self.locks.borrow().iter().any(|l| {
+            (l.covers(path) || (infinite && l.path.starts_with(path))) && (exclusive || l.exclusive)
+        })
+    }
+
+    fn locks_on(&self, path: &Path) -> String {
+        self.locks
+            .borrow()
+            .iter()
+            .filter(|l| l.covers(path))
+            .map(Lock::to_xml)
+            .collect()
+    }
+
+    fn properties_of(&self, path: &Path) -> Vec<DeadProperty> {
+        self.properties.borrow().get(path).cloned().unwrap_or_default()
+    }
+
+    /// Forget locks and properties of a removed tree
+    fn forget(&self, path: &Path) {
+        self.locks.borrow_mut().retain(|l| !l.path.starts_with(path));
+        self.properties.borrow_mut().retain(|p, _| !p.starts_with(path));
+    }
+
+    fn copy_properties(&self, from: &Path, to: &Path) {
+        let mut properties = self.properties.borrow_mut();
+        let copied: Vec<(PathBuf, Vec<DeadProperty>)> = properties
+            .iter()
+            .filter_map(|(p, props)| {
+                let relative = p.strip_prefix(from).ok()?;
+                Some((to.join(relative), props.clone()))
+            })
+            .collect();
+        properties.extend(copied);
+    }
+}
+
+/// Answer a request to a WebDAV route. POST keeps its usual upload handling
+/// and is not sent here.
+pub fn handle_webdav(
+    server: &ServerConfig,
+    route: &Route,
+    request: &HttpRequest,
+    dav: &DavStore,
+    cookie: Option<&Cookie>,
+) -> Box<dyn HttpResponseCommon> {
+    let simple = |bytes: Vec<u8>| -> Box<dyn HttpResponseCommon> { Box::new(SimpleResponse::new(bytes)) };
+
+    dav.expire_locks();
+
+    let href = match urlencoding::decode(&request.path) {
+        Ok(href) if !href.contains('\0') => href.into_owned(),
+        _ => return simple(status(400, "Bad Request", "Invalid request path", cookie)),
+    };
+    // A missing parent directory leaves nothing to resolve
+    let Some(path) = resolve_file_path(server, route, &href).map(PathBuf::from) else {
+        let (code, text) = match request.method {
+            HttpMethod::PUT | HttpMethod::MKCOL | HttpMethod::LOCK => (409, "Conflict"),
+            _ => (404, "Not Found"),
+        };
+        return simple(status(code, text, "No such resource", cookie));
+    };
+
+    if let Some(header) = request.headers.get("if")
+        && !if_header_matches(&header, &path, dav)
+    {
+        return simple(status(412, "Precondition Failed", "If header does not match", cookie));
+    }
+    let tokens = submitted_tokens(request);
+
+    match request.method {
+        HttpMethod::GET | HttpMethod::HEAD if path.is_file() => {
+            let path_str = path.to_string_lossy();
+            match FileResponse::new(&path_str, cookie) {
+                Ok(mut response) => {
+                    if let Ok(metadata) = fs::metadata(&path) {
+                        response.add_header("ETag", &etag(&metadata));
+                        if let Ok(modified) = metadata.modified() {
+                            response.add_header("Last-Modified", &httpdate::fmt_http_date(modified));
+                        }
+                    }
+                    Box::new(response)
+                }
+                Err(_) => simple(status(404, "Not Found", "No such resource", cookie)),
+            }
+        }
+        HttpMethod::GET | HttpMethod::HEAD => {
+            handle_get(&path.to_string_lossy(), server, route, request, cookie)
+        }
+        HttpMethod::OPTIONS => simple(
+            HttpResponseBuilder::ok()
+                .header("Allow", &route.allow_header())
+                .header("DAV", "1, 2")
+                .header("MS-Author-Via", "DAV")
+                .cookie(cookie)
+                .build(),
+        ),
+        HttpMethod::PUT => {
+            // Creating a member also changes its parent collection
+            let allowed = if path.exists() {
+                dav.may_write(&path, false, &tokens)
+            } else {
+                dav.may_change_member(&path, false, &tokens)
+            };
+            if !allowed {
+                return simple(locked(cookie));
+            }
+            simple(handle_put(&path.to_string_lossy(), request, cookie))
+        }
+        HttpMethod::DELETE => simple(delete(&path, dav, &tokens, cookie)),
+        HttpMethod::MKCOL => simple(mkcol(&path, request, dav, &tokens, cookie)),
+        HttpMethod::PROPFIND => simple(propfind(&path, &href, request, dav, cookie)),
+        HttpMethod::PROPPATCH => simple(proppatch(&path, &href, request, dav, &tokens, cookie)),
+        HttpMethod::COPY | HttpMethod::MOVE => {
+            simple(copy_or_move(server, route, &path, request, dav, &tokens, cookie))
+        }
+        HttpMethod::LOCK => simple(lock(&path, &href, request, dav, cookie)),
+        HttpMethod::UNLOCK => simple(unlock(&path, request, dav, cookie)),
+        _ => simple(status(405, "Method Not Allowed", "Method not supported here", cookie)),
+    }
+}
+
+fn status(code: u16, text: &str, message: &str, cookie: Option<&Cookie>) -> Vec<u8> {
+    HttpResponseBuilder::new(code, text)
+        .header("Content-Type", "text/plain")
+        .body(message.as_bytes().to_vec())
+        .cookie(cookie)
+        .build()
+}
+
+fn locked(cookie: Option<&Cookie>) -> Vec<u8> {
+    status(423, "Locked", "Resource is locked", cookie)
+}
+
+/// Error with the DAV precondition `condition` that the request did not meet
+fn precondition_failed(code: u16, text: &str, condition: &str, cookie: Option<&Cookie>) -> Vec<u8> {
+    HttpResponseBuilder::new(code, text)
+        .header("Content-Type", "application/xml; charset=utf-8")
+        .body(
+            format!(
+                "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:error xmlns:D=\"DAV:\"><D:{}/></D:error>",
+                condition
+            )
+            .into_bytes(),
+        )
+        .cookie(cookie)
+        .build()
+}
+
+fn multistatus(body: String, cookie: Option<&Cookie>) -> Vec<u8> {
+    HttpResponseBuilder::new(207, "Multi-Status")
+        .header("Content-Type", "application/xml; charset=utf-8")
+        .body(
+            format!(
+                "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:multistatus xmlns:D=\"DAV:\">{}</D:multistatus>",
+                body
+            )
+            .into_bytes(),
+        )
+        .cookie(cookie)
+        .build()
+}
+
+/// Request body as XML; `None` for an empty body
+fn xml_body(request: &HttpRequest) -> Result<Option<XmlElement>, &'static str> {
+    let body = request.body.as_deref().unwrap_or_default();
+    if body.iter().all(u8::is_ascii_whitespace) {
+        return Ok(None);
+    }
+    let text = std::str::from_utf8(body).map_err(|_| "Body is not UTF-8")?;
+    XmlElement::parse(text).map(Some)
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Depth {
+    Zero,
+    One,
+    Infinity,
+}
+
+fn depth(request: &HttpRequest, default: Depth) -> Option<Depth> {
+    match request.headers.get("depth") {
+        None => Some(default),
+        Some(value) => match value.trim().to_ascii_lowercase().as_str() {
+            "0" => Some(Depth::Zero),
+            "1" => Some(Depth::One),
+            "infinity" => Some(Depth::Infinity),
+            _ => None,
+        },
+    }
+}
+
+/// Lock tokens named anywhere in the `If` header
+fn submitted_tokens(request: &HttpRequest) -> Vec<String> {
+    let header = request.headers.get("if").unwrap_or_default();
+    header
+        .split('<')
+        .skip(1)
+        .filter_map(|part| part.split_once('>').map(|(token, _)| token.trim()))
+        .filter(|token| token.starts_with("opaquelocktoken:"))
+        .map(str::to_string)
+        .collect()
+}
+
+/// Evaluate the `If` header (RFC 4918 §10.4) against the request resource:
+/// it holds when one of its parenthesised lists has all its conditions true.
+/// A condition is a lock token (active on the resource), an entity tag, or
+/// either of those negated with `Not`. Resource tags are not distinguished.
+fn if_header_matches(header: &str, path: &Path, dav: &DavStore) -> bool {
+    let current_etag = fs::metadata(path).ok().map(|m| etag(&m));
+    let lock_active = |token: &str| {
+        dav.locks
+            .borrow()
+            .iter()
+            .any(|l| l.token == token && l.covers(path))
+    };
+
+    let mut rest = header;
+    loop {
+        // Skip a resource tag before the next list
+        let Some(open) = rest.find('(') else {
+            return false;
+        };
+        let Some(close) = rest[open..].find(')') else {
+            return false;
+        };
+        let list = &rest[open + 1..open + close];
+        rest = &rest[open + close + 1..];
+
+        let mut all_true = true;
+        let mut negate = false;
+        let mut items = list.trim();
+        while !items.is_empty() {
+            let condition = if let Some(after) = items.strip_prefix("Not") {
+                negate = true;
+                items = after.trim_start();
+                continue;
+            } else if let Some(after) = items.strip_prefix('<') {
+                let Some(end) = after.find('>') else { return false };
+                items = after[end + 1..].trim_start();
+                lock_active(&after[..end])
+            } else if let Some(after) = items.strip_prefix('[') {
+                let Some(end) = after.find(']') else { return false };
+                items = after[end + 1..].trim_start();
+                let tag = after[..end].trim_start_matches("W/");
+                current_etag.as_deref() == Some(tag)
+            } else {
+                return false;
+            };
+            all_true &= condition != negate;
+            negate = false;
+        }
+        if all_true {
+            return true;
+        }
+    }
+}
+
+fn etag(metadata: &fs::Metadata) -> String {
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+    format!("\"{:x}-{:x}\"", metadata.len(), modified)
+}
+
+/// Percent-encode each segment of a decoded path
+fn encode_href(href: &str) -> String {
+    href.split('/')
+        .map(|segment| urlencoding::encode(segment).into_owned())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn delete(path: &Path, dav: &DavStore, tokens: &[String], cookie: Option<&Cookie>) -> Vec<u8> {
+    let Ok(metadata) = fs::symlink_metadata(path) else {
+        return status(404, "Not Found", "No such resource", cookie);
+    };
+    if !dav.may_change_member(path, true, tokens) {
+        return locked(cookie);
+    }
+
+    let result = if metadata.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    };
+    match result {
+        Ok(()) => {
+            dav.forget(path);
+            HttpResponseBuilder::no_content().cookie(cookie).build()
+        }
+        Err(e) => status(500, "Internal Server Error", &e.to_string(), cookie),
+    }
+}
+
+fn mkcol(
+    path: &Path,
+    request: &HttpRequest,
+    dav: &DavStore,
+    tokens: &[String],
+    cookie: Option<&Cookie>,
+) -> Vec<u8> {
+    // Bodies would describe the collection, which is not supported
+    if request.body.as_ref().is_some_and(|b| !b.is_empty()) {
+        return status(415, "Unsupported Media Type", "MKCOL body not supported", cookie);
+    }
+    if fs::symlink_metadata(path).is_ok() {
+        return status(405, "Method Not Allowed", "Resource already exists", cookie);
+    }
+    if !dav.may_change_member(path, false, tokens) {
+        return locked(cookie);
+    }
+    match fs::create_dir(path) {
+        Ok(()) => HttpResponseBuilder::created().cookie(cookie).build(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            status(409, "Conflict", "Parent collection does not exist", cookie)
+        }
+        Err(e) => status(500, "Internal Server Error", &e.to_string(), cookie),
+    }
+}
+
+enum PropfindQuery {
+    AllProp,
+    PropName,
+    Prop(Vec<(String, String)>),
+}
+
+fn propfind(
+    path: &Path,
+    href: &str,
+    request: &HttpRequest,
+    dav: &DavStore,
+    cookie: Option<&Cookie>,
+) -> Vec<u8> {
+    if fs::symlink_metadata(path).is_err() {
+        return status(404, "Not Found", "No such resource", cookie);
+    }
+    // A walk of the whole tree would hold up every other connection, so
+    // only finite depths are served (RFC 4918 §9.1)
+    let depth = match depth(request, Depth::Infinity) {
+        Some(Depth::Infinity) => return precondition_failed(403, "Forbidden", "propfind-finite-depth", cookie),
+        Some(depth) => depth,
+        None => return status(400, "Bad Request", "Invalid Depth header", cookie),
+    };
+
+    let query = match xml_body(request) {
+        Ok(None) => PropfindQuery::AllProp,
+        Ok(Some(root)) if root.is(DAV, "propfind") => {
+            if root.child(DAV, "propname").is_some() {
+                PropfindQuery::PropName
+            } else if let Some(prop) = root.child(DAV, "prop") {
+                PropfindQuery::Prop(
+                    prop.elements()
+                        .map(|e| (e.namespace.clone(), e.name.clone()))
+                        .collect(),
+                )
+            } else if root.child(DAV, "allprop").is_some() {
+                PropfindQuery::AllProp
+            } else {
+                return status(400, "Bad Request", "Empty propfind", cookie);
+            }
+        }
+        Ok(Some(_)) => return status(400, "Bad Request", "Expected a propfind element", cookie),
+        Err(e) => return status(400, "Bad Request", e, cookie),
+    };
+
+    let mut resources = Vec::new();
+    collect_resources(path, href.to_string(), depth, &mut resources);
+
+    let mut body = String::new();
+    for (resource_path, resource_href) in &resources {
+        body.push_str(&propfind_response(resource_path, resource_href, &query, dav));
+    }
+    multistatus(body, cookie)
+}
+
+/// The resource and, following `depth`, its members. Symbolic links are
+/// skipped so a listing never leaves the route's directory.
+fn collect_resources(path: &Path, mut href: String, depth: Depth, out: &mut Vec<(PathBuf, String)>) {
+    let is_dir = path.is_dir();
+    if is_dir && !href.ends_with('/') {
+        href.push('/');
+    }
+    out.push((path.to_path_buf(), href.clone()));
+
+    if !is_dir || depth == Depth::Zero {
+        return;
+    }
+    let Ok(entries) = fs::read_dir(path) else {
+        return;
+    };
+    let mut entries: Vec<_> = entries.flatten().collect();
+    entries.sort_by_key(|e| e.file_name());
+    for entry in entries {
+        if entry.file_type().map(|t| t.is_symlink()).unwrap_or(true) {
+            continue;
+        }
+        let child_href = format!("{}{}", href, entry.file_name().to_string_lossy());
+        let next = if depth == Depth::One { Depth::Zero } else { Depth::Infinity };
+        collect_resources(&entry.path(), child_href, next, out);
+    }
+}
+
+fn propfind_response(path: &Path, href: &str, query: &PropfindQuery, dav: &DavStore) -> String {
+    let dead = dav.properties_of(path);
+    let mut found = String::new();
+    let mut missing = String::new();
+
+    match query {
+        PropfindQuery::PropName => {
+            for name in LIVE_PROPERTIES {
+                if live_property(path, name, dav).is_some() {
+                    found.push_str(&format!("<D:{}/>", name));
+                }
+            }
+            for property in &dead {
+                found.push_str(&property_element(&property.namespace, &property.name, ""));
+            }
+        }
+        PropfindQuery::AllProp => {
+            for name in LIVE_PROPERTIES {
+                if let Some(value) = live_property(path, name, dav) {
+                    found.push_str(&format!("<D:{0}>{1}</D:{0}>", name, value));
+                }
+            }
+            for property in &dead {
+                found.push_str(&property_element(&property.namespace, &property.name, &property.value));
+            }
+        }
+        PropfindQuery::Prop(names) => {
+            for (namespace, name) in names {
+                let value = if namespace == DAV {
+                    live_property(path, name, dav)
+                } else {
+                    None
+                }
+                .or_else(|| {
+                    dead.iter()
+                        .find(|p| &p.namespace == namespace && &p.name == name)
+                        .map(|p| p.value.clone())
+                });
+                match value {
+                    Some(value) => found.push_str(&property_element(namespace, name, &value)),
+                    None => missing.push_str(&property_element(namespace, name, "")),
+                }
+            }
+        }
+    }
+
+    let mut response = format!("<D:response><D:href>{}</D:href>", escape(&encode_href(href)));
+    if !found.is_empty() || missing.is_empty() {
+        response.push_str(&format!(
+            "<D:propstat><D:prop>{}</D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat>",
+            found
+        ));
+    }
+    if !missing.is_empty() {
+        response.push_str(&format!(
+            "<D:propstat><D:prop>{}</D:prop><D:status>HTTP/1.1 404 Not Found</D:status></D:propstat>",
+            missing
+        ));
+    }
+    response.push_str("</D:response>");
+    response
+}
+
+/// `<name xmlns="namespace">value</name>`, with the `D:` prefix for DAV:
+fn property_element(namespace: &str, name: &str, value: &str) -> String {
+    let tag = if namespace == DAV {
+        format!("D:{}", name)
+    } else {
+        name.to_string()
+    };
+    let declaration = if namespace == DAV {
+        String::new()
+    } else {
+        format!(" xmlns=\"{}\"", escape(namespace))
+    };
+    if value.is_empty() {
+        format!("<{}{}/>", tag, declaration)
+    } else {
+        format!("<{0}{1}>{2}</{0}>", tag, declaration, value)
+    }
+}
+
+/// XML value of a live property, `None` when it does not apply
+fn live_property(path: &Path, name: &str, dav: &DavStore) -> Option<String> {
+    let metadata = fs::metadata(path).ok()?;
+    let is_dir = metadata.is_dir();
+    match name {
+        "displayname" => path.file_name().map(|n| escape(&n.to_string_lossy())),
+        "getcontentlength" if !is_dir => Some(metadata.len().to_string()),
+        "getcontenttype" if !is_dir => Some(detect_content_type(&path.to_string_lossy()).to_string()),
+        "getetag" if !is_dir => Some(escape(&etag(&metadata))),
+        "getlastmodified" => metadata
+            .modified()
+            .ok()
+            .map(httpdate::fmt_http_date),
+        "resourcetype" => Some(if is_dir { "<D:collection/>".to_string() } else { String::new() }),
+        "lockdiscovery" => Some(dav.locks_on(path)),
+        "supportedlock" => Some(
+            "<D:lockentry><D:lockscope><D:exclusive/></D:lockscope><D:locktype><D:write/></D:locktype></D:lockentry>\
+             <D:lockentry><D:lockscope><D:shared/></D:lockscope><D:locktype><D:write/></D:locktype></D:lockentry>"
+                .to_string(),
+        ),
+        _ => None,
+    }
+}
+
+/// Property changes are all-or-nothing: a single refused change (on a live
+/// property) fails the others with 424
+fn proppatch(
+    path: &Path,
+    href: &str,
+    request: &HttpRequest,
+    dav: &DavStore,
+    tokens: &[String],
+    cookie: Option<&Cookie>,
+) -> Vec<u8> {
+    if fs::symlink_metadata(path).is_err() {
+        return status(404, "Not Found", "No such resource", cookie);
+    }
+    if !dav.may_write(path, false, tokens) {
+        return locked(cookie);
+    }
+    let root = match xml_body(request) {
+        Ok(Some(root)) if root.is(DAV, "propertyupdate") => root,
+        Ok(_) => return status(400, "Bad Request", "Expected a propertyupdate element", cookie),
+        Err(e) => return status(400, "Bad Request", e, cookie),
+    };
+
+    // (property, new value or None for removal), in document order
+    let mut changes: Vec<(&XmlElement, Option<String>)> = Vec::new();
+    for instruction in root.elements() {
+        let set = instruction.is(DAV, "set");
+        if !set && !instruction.is(DAV, "remove") {
+            continue;
+        }
+        for prop in instruction.elements().filter(|e| e.is(DAV, "prop")) {
+            for property in prop.elements() {
+                changes.push((property, set.then(|| property.inner_xml())));
+            }
+        }
+    }
+
+    let protected = |p: &XmlElement| p.namespace == DAV && LIVE_PROPERTIES.contains(&p.name.as_str());
+    let failed = changes.iter().any(|(p, _)| protected(p));
+
+    if !failed {
+        let mut properties = dav.properties.borrow_mut();
+        let stored = properties.entry(path.to_path_buf()).or_default();
+        for (property, value) in &changes {
+            stored.retain(|p| !(p.namespace == property.namespace && p.name == property.name));
+            if let Some(value) = value {
+                stored.push(DeadProperty {
+                    namespace: property.namespace.clone(),
+                    name: property.name.clone(),
+                    value: value.clone(),
+                });
+            }
+        }
+    }
+
+    let mut by_status: Vec<(&str, String)> = Vec::new();
+    for (property, _) in &changes {
+        let code = match (failed, protected(property)) {
+            (false, _) => "200 OK",
+            (true, true) => "403 Forbidden",
+            (true, false) => "424 Failed Dependency",
+        };
+        let element = property_element(&property.namespace, &property.name, "");
+        match by_status.iter_mut().find(|(c, _)| *c == code) {
+            Some((_, props)) => props.push_str(&element),
+            None => by_status.push((code, element)),
+        }
+    }
+
+    let mut body = format!("<D:response><D:href>{}</D:href>", escape(&encode_href(href)));
+    for (code, props) in by_status {
+        body.push_str(&format!(
+            "<D:propstat><D:prop>{}</D:prop><D:status>HTTP/1.1 {}</D:status></D:propstat>",
+            props, code
+        ));
+    }
+    body.push_str("</D:response>");
+    multistatus(body, cookie)
+}
+
+/// Resolve the `Destination` header to a path under the same route. It may
+/// be an absolute URI, whose authority must be this server.
+fn destination(
+    server: &ServerConfig,
+    route: &Route,
+    request: &HttpRequest,
+) -> Result<PathBuf, (u16, &'static str, &'static str)> {
+    let header = request
+        .headers
+        .get("destination")
+        .ok_or((400, "Bad Request", "Missing Destination header"))?;
+
+    let target = match header
+        .strip_prefix("http://")
+        .or_else(|| header.strip_prefix("https://"))
+    {
+        Some(rest) => {
+            let split = rest.find('/').unwrap_or(rest.len());
+            let host = request.headers.get("host").unwrap_or_default();
+            if !rest[..split].eq_ignore_ascii_case(&host) {
+                return Err((502, "Bad Gateway", "Destination is on another server"));
+            }
+            &rest[split..]
+        }
+        None => header.as_str(),
+    };
+    let target = target.split(['?', '#']).next().unwrap_or_default();
+
+    let decoded = urlencoding::decode(target)
+        .ok()
+        .filter(|d| d.starts_with('/') && !d.contains('\0'))
+        .ok_or((400, "Bad Request", "Invalid Destination header"))?;
+    let trimmed = route.path.trim_end_matches('/');
+    if !(decoded == route.path || decoded.starts_with(&format!("{}/", trimmed))) {
+        return Err((502, "Bad Gateway", "Destination is outside this collection"));
+    }
+
+    resolve_file_path(server, route, &decoded)
+        .map(PathBuf::from)
+        .ok_or((409, "Conflict", "Destination parent does not exist"))
+}
+
+fn copy_or_move(
+    server: &ServerConfig,
+    route: &Route,
+    source: &Path,
+    request: &HttpRequest,
+    dav: &DavStore,
+    tokens: &[String],
+    cookie: Option<&Cookie>,
+) -> Vec<u8> {
+    let moving = request.method == HttpMethod::MOVE;
+    let Ok(metadata) = fs::symlink_metadata(source) else {
+        return status(404, "Not Found", "No such resource", cookie);
+    };
+    let target = match destination(server, route, request) {
+        Ok(target) => target,
+        Err((code, text, message)) => return status(code, text, message, cookie),
+    };
+
+    // MOVE always takes the whole tree; COPY may stop at the collection
+    let depth = match depth(request, Depth::Infinity) {
+        Some(Depth::Infinity) => Depth::Infinity,
+        Some(Depth::Zero) if !moving => Depth::Zero,
+        _ => return status(400, "Bad Request", "Invalid Depth header", cookie),
+    };
+    if target == source || (metadata.is_dir() && target.starts_with(source)) {
+        return status(403, "Forbidden", "Destination is inside the source", cookie);
+    }
+
+    let overwrite = !request
+        .headers
+        .get("overwrite")
+        .is_some_and(|v| v.trim().eq_ignore_ascii_case("F"));
+    let existed = fs::symlink_metadata(&target).is_ok();
+    if existed && !overwrite {
+        return status(412, "Precondition Failed", "Destination exists", cookie);
+    }
+
+    if (moving && !dav.may_change_member(source, true, tokens))
+        || !dav.may_change_member(&target, true, tokens)
+    {
+        return locked(cookie);
+    }
+
+    if existed {
+        let removed = if target.is_dir() {
+            fs::remove_dir_all(&target)
+        } else {
+            fs::remove_file(&target)
+        };
+        if let Err(e) = removed {
+            return status(500, "Internal Server Error", &e.to_string(), cookie);
+        }
+        dav.forget(&target);
+    }
+
+    let result = if moving {
+        // Across file systems a rename fails; copy then remove instead
+        fs::rename(source, &target).or_else(|_| {
+            copy_tree(source, &target, Depth::Infinity)?;
+            if metadata.is_dir() {
+                fs::remove_dir_all(source)
+            } else {
+                fs::remove_file(source)
+            }
+        })
+    } else {
+        copy_tree(source, &target, depth)
+    };
+
+    match result {
+        Ok(()) => {
+            dav.copy_properties(source, &target);
+            if moving {
+                // Locks stay with the URL they were taken on
+                dav.forget(source);
+            }
+            if existed {
+                HttpResponseBuilder::no_content().cookie(cookie).build()
+            } else {
+                HttpResponseBuilder::created().cookie(cookie).build()
+            }
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            status(409, "Conflict", "Destination parent does not exist", cookie)
+        }
+        Err(e) => status(500, "Internal Server Error", &e.to_string(), cookie),
+    }
+}
+
+fn copy_tree(source: &Path, target: &Path, depth: Depth) -> io::Result<()> {
+    if !source.is_dir() {
+        return fs::copy(source, target).map(|_| ());
+    }
+    fs::create_dir(target)?;
+    if depth == Depth::Zero {
+        return Ok(());
+    }
+    for entry in fs::read_dir(source)? {
+        let entry = entry?;
+        if entry.file_type()?.is_symlink() {
+            continue;
+        }
+        copy_tree(&entry.path(), &target.join(entry.file_name()), depth)?;
+    }
+    Ok(())
+}
+
+/// `Timeout: Second-600, Infinite` — the first form understood, capped
+fn lock_timeout(request: &HttpRequest) -> Duration {
+    let header = request.headers.get("timeout").unwrap_or_default();
+    header
+        .split(',')
+        .find_map(|t| {
+            let t = t.trim();
+            if t.eq_ignore_ascii_case("infinite") {
+                Some(MAX_LOCK_TIMEOUT)
+            } else {
+                t.strip_prefix("Second-")?.parse().ok().map(Duration::from_secs)
+            }
+        })
+        .unwrap_or(DEFAULT_LOCK_TIMEOUT)
+        .min(MAX_LOCK_TIMEOUT)
+}
+
+fn lock_response(code: u16, text: &str, lock: &Lock, cookie: Option<&Cookie>) -> Vec<u8> {
+    let body = format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:prop xmlns:D=\"DAV:\"><D:lockdiscovery>{}</D:lockdiscovery></D:prop>",
+        lock.to_xml()
+    );
+    HttpResponseBuilder::new(code, text)
+        .header("Content-Type", "application/xml; charset=utf-8")
+        .header("Lock-Token", &format!("<{}>", lock.token))
+        .body(body.into_bytes())
+        .cookie(cookie)
+        .build()
+}
+
+fn lock(path: &Path, href: &str, request: &HttpRequest, dav: &DavStore, cookie: Option<&Cookie>) -> Vec<u8> {
+    let timeout = lock_timeout(request);
+
+    let lockinfo = match xml_body(request) {
+        Ok(Some(root)) if root.is(DAV, "lockinfo") => root,
+        Ok(Some(_)) => return status(400, "Bad Request", "Expected a lockinfo element", cookie),
+        Err(e) => return status(400, "Bad Request", e, cookie),
+        Ok(None) => {
+            // Refresh: no body, the lock is named in the If header
+            let tokens = submitted_tokens(request);
+            let mut locks = dav.locks.borrow_mut();
+            let Some(lock) = locks
+                .iter_mut()
+                .find(|l| tokens.contains(&l.token) && l.covers(path))
+            else {
+                return status(412, "Precondition Failed", "No lock to refresh", cookie);
+            };
+            lock.timeout = timeout;
+            lock.expires = Instant::now() + timeout;
+            return lock_response(200, "OK", lock, cookie);
+        }
+    };
+
+    let exclusive = match lockinfo.child(DAV, "lockscope") {
+        Some(scope) if scope.child(DAV, "exclusive").is_some() => true,
+        Some(scope) if scope.child(DAV, "shared").is_some() => false,
+        _ => return status(400, "Bad Request", "Invalid lockscope", cookie),
+    };
+    if lockinfo
+        .child(DAV, "locktype")
+        .and_then(|t| t.child(DAV, "write"))
+        .is_none()
+    {
+        return status(400, "Bad Request", "Only write locks are supported", cookie);
+    }
+    let infinite = match depth(request, Depth::Infinity) {
+        Some(Depth::Infinity) => true,
+        Some(Depth::Zero) => false,
+        _ => return status(400, "Bad Request", "Invalid Depth header", cookie),
+    };
+
+    if dav.lock_conflicts(path, infinite, exclusive) {
+        return locked(cookie);
+    }
+
+    // Locking an unmapped URL creates an empty resource (RFC 4918 §7.3)
+    let created = fs::symlink_metadata(path).is_err();
+    if created && let Err(e) = fs::write(path, b"") {
+        return match e.kind() {
+            io::ErrorKind::NotFound => status(409, "Conflict", "Parent collection does not exist", cookie),
+            _ => status(500, "Internal Server Error", &e.to_string(), cookie),
+        };
+    }
+
+    let lock = Lock {
+        token: format!("opaquelocktoken:{}", Uuid::new_v4()),
+        path: path.to_path_buf(),
+        root_href: encode_href(href),
+        infinite,
+        exclusive,
+        owner: lockinfo
+            .child(DAV, "owner")
+            .map(|o| o.inner_xml())
+            .unwrap_or_default(),
+        timeout,
+        expires: Instant::now() + timeout,
+    };
+    println!("WEBDAV: {} locked until {:?}", path.display(), lock.timeout);
+    let response = if created {
+        lock_response(201, "Created", &lock, cookie)
+    } else {
+        lock_response(200, "OK", &lock, cookie)
+    };
+    dav.locks.borrow_mut().push(lock);
+    response
+}
+
+fn unlock(path: &Path, request: &HttpRequest, dav: &DavStore, cookie: Option<&Cookie>) -> Vec<u8> {
+    let Some(token) = request
+        .headers
+        .get("lock-token")
+        .and_then(|t| Some(t.trim().strip_prefix('<')?.strip_suffix('>')?.to_string()))
+    else {
+        return status(400, "Bad Request", "Missing Lock-Token header", cookie);
+    };
+
+    let mut locks = dav.locks.borrow_mut();
+    let before = locks.len();
+    locks.retain(|l| !(l.token == token && l.covers(path)));
+    if locks.len() == before {
+        return status(409, "Conflict", "Lock token does not apply to this resource", cookie);
+    }
+    HttpResponseBuilder::no_content().cookie(cookie).build()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// File removed again when the test ends
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new() -> Self {
+            let path = std::env::temp_dir().join(format!("webdav-test-{}", Uuid::new_v4().simple()));
+            fs::write(&path, "content").expect("write temp file");
+            TempFile(path)
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn lock(dav: &DavStore, path: &Path, token: &str, exclusive: bool) {
+        dav.locks.borrow_mut().push(Lock {
+            token: token.to_string(),
+            path: path.to_path_buf(),
+            root_href: "/".to_string(),
+            infinite: true,
+            exclusive,
+            owner: String::new(),
+            timeout: DEFAULT_LOCK_TIMEOUT,
+            expires: Instant::now() + DEFAULT_LOCK_TIMEOUT,
+        });
+    }
+
+    #[test]
+    fn if_header_checks_lock_tokens_and_etags() {
+        let file = TempFile::new();
+        let dav = DavStore::new();
+        lock(&dav, &file.0, "urn:uuid:held", true);
+        let tag = etag(&fs::metadata(&file.0).unwrap());
+
+        assert!(if_header_matches("(<urn:uuid:held>)", &file.0, &dav));
+        assert!(!if_header_matches("(<urn:uuid:other>)", &file.0, &dav));
+        assert!(if_header_matches(&format!("(<urn:uuid:held> [{tag}])"), &file.0, &dav));
+        assert!(if_header_matches(&format!("([W/{tag}])"), &file.0, &dav));
+        assert!(!if_header_matches("(<urn:uuid:held> [\"stale\"])", &file.0, &dav));
+        assert!(if_header_matches("(Not <urn:uuid:other>)", &file.0, &dav));
+        assert!(!if_header_matches("(Not <urn:uuid:held>)", &file.0, &dav));
+    }
+
+    #[test]
+    fn if_header_needs_one_true_list() {
+        let file = TempFile::new();
+        let dav = DavStore::new();
+        lock(&dav, &file.0, "urn:uuid:held", false);
+
+        assert!(if_header_matches("(<urn:uuid:other>) (<urn:uuid:held>)", &file.0, &dav));
+        assert!(if_header_matches("</res> (<urn:uuid:held>)", &file.0, &dav));
+        assert!(!if_header_matches("(<urn:uuid:other>) ([\"x\"])", &file.0, &dav));
+        assert!(!if_header_matches("(<urn:uuid:held>", &file.0, &dav));
+        assert!(!if_header_matches("", &file.0, &dav));
+    }
+
+    #[test]
+    fn shared_locks_need_one_of_their_tokens() {
+        let dav = DavStore::new();
+        let dir = Path::new("/srv/dav");
+        let file = dir.join("file");
+        lock(&dav, dir, "urn:uuid:a", false);
+        lock(&dav, dir, "urn:uuid:b", false);
+
+        assert!(dav.may_write(&file, false, &["urn:uuid:b".to_string()]));
+        assert!(!dav.may_write(&file, false, &[]));
+        assert!(!dav.lock_conflicts(&file, false, false));
+        assert!(dav.lock_conflicts(&file, false, true));
+    }
+}
@@ -1,24 +1,37 @@
 use std::{io, net::Shutdown, time::Instant};
 use std::io::{Write};
-use crate::{models::HttpResponseCommon, request::HttpRequestBuilder, server::{SocketData, Status}};
-
-fn should_keep_alive(request: &crate::request::HttpRequest) -> bool {
-    request
-        .headers
-        .get("connection")
-        .map(|v| v.to_lowercase() == "keep-alive")
-        .unwrap_or(false)
+use crate::server::{SocketData, Status};
+
+/// Whether the client wants the connection to persist: HTTP/1.1 does unless
+/// it sends `Connection: close`, HTTP/1.0 only with `Connection: keep-alive`.
+pub(crate) fn should_keep_alive(request: &crate::request::HttpRequest) -> bool {
+    let connection = request.headers.get("connection").unwrap_or_default();
+    let has_token = |token: &str| {
+        connection
+            .split(',')
+            .any(|t| t.trim().eq_ignore_ascii_case(token))
+    };
+
+    if has_token("close") {
+        false
+    } else if request.version == "HTTP/1.0" {
+        has_token("keep-alive")
+    } else {
+        true
+    }
 }
 
 fn write_response(socket: &mut SocketData) -> Option<bool> {
-    let response: &mut Box<dyn HttpResponseCommon + 'static> = socket.status.response.as_mut()?;
+    let response = &mut socket.status.outbox.front_mut()?.response;
 
     response.fill_if_needed().ok()?;
 
     let data = response.peek();
 
     if data.is_empty() {
-        return Some(true);
+        // Not finished yet means the source has nothing for now (CGI output
+        // still to come): its wakeup resumes the connection
+        return Some(response.is_finished());
     }
     match socket.stream.write(data) {
         Ok(n) => {
@@ -26,11 +39,9 @@ fn write_response(socket: &mut SocketData) -> Option<bool> {
             if n > 0 {
                 socket.status.ttl = Instant::now();
             }
-            if response.is_finished() {
-                Some(false)
-            } else {
-                Some(true)
-            }
+            // Finished or not, the caller moves on: a finished response must
+            // not wait for another (edge-triggered) event to be completed
+            Some(true)
         }
         Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Some(false),
         Err(_) => None,
@@ -46,25 +57,52 @@ pub fn handle_write_state(socket_data: &mut SocketData) -> Option<bool> {
             return other;
         }
     }
-    let response = socket_data.status.response.as_ref()?;
+    let outgoing = socket_data.status.outbox.front()?;
 
-    if !response.is_finished() {
+    if !outgoing.response.is_finished() {
         println!("Response not finished yet.");
         return Some(true);
     }
 
-    let request = socket_data.status.request.get()?;
-    let keep_alive = should_keep_alive(request);
+    let keep_alive = outgoing.keep_alive;
+    socket_data.status.outbox.pop_front();
 
-    if keep_alive {
-        socket_data.status.status = Status::Read;
-        socket_data.status.request = HttpRequestBuilder::new();
-        socket_data.status.response = None;
-        println!("Keeping connection alive for next request.");
-        Some(true)
-    } else {
+    if !keep_alive {
         println!("Closing connection.");
         let _ = socket_data.stream.shutdown(Shutdown::Both);
-        None
+        return None;
+    }
+
+    // Responses to pipelined requests go out in the order they were queued
+    if socket_data.status.outbox.is_empty() {
+        socket_data.status.status = Status::Read;
+        println!("Keeping connection alive for next request.");
+    }
+    Some(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::HttpRequestBuilder;
+
+    fn keep_alive(raw: &str) -> bool {
+        let mut builder = HttpRequestBuilder::new();
+        builder.append(raw.as_bytes().to_vec()).expect("valid request");
+        should_keep_alive(builder.get().expect("complete request"))
+    }
+
+    #[test]
+    fn http11_persists_unless_closed() {
+        assert!(keep_alive("GET / HTTP/1.1\r\nHost: x\r\n\r\n"));
+        assert!(!keep_alive("GET / HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n"));
+        assert!(!keep_alive("GET / HTTP/1.1\r\nHost: x\r\nConnection: Upgrade, CLOSE\r\n\r\n"));
+    }
+
+    #[test]
+    fn http10_persists_only_on_request() {
+        assert!(!keep_alive("GET / HTTP/1.0\r\n\r\n"));
+        assert!(keep_alive("GET / HTTP/1.0\r\nConnection: Keep-Alive\r\n\r\n"));
+        assert!(!keep_alive("GET / HTTP/1.0\r\nConnection: keep-alive, close\r\n\r\n"));
     }
 }